@@ -0,0 +1,156 @@
+//! Scaling retries down while the upstream keeps failing.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::breaker::MAX_HOSTS;
+
+pub(crate) struct AdaptiveLimit {
+    window: Duration,
+    /// Fewer attempts than this in the window leave the limit alone
+    min_samples: u32,
+    /// Up to [`MAX_HOSTS`], beyond which the host with the fewest failures is forgotten
+    hosts: RefCell<HashMap<String, Health>>,
+}
+
+/// Attempts and failures towards one host, in the current and the previous window
+struct Health {
+    start: Instant,
+    current: (u32, u32),
+    previous: (u32, u32),
+}
+
+impl Health {
+    fn roll(&mut self, now: Instant, window: Duration) {
+        let elapsed = now.saturating_duration_since(self.start);
+        if elapsed >= window * 2 {
+            self.previous = (0, 0);
+        } else if elapsed >= window {
+            self.previous = self.current;
+        } else {
+            return;
+        }
+        self.current = (0, 0);
+        self.start = now;
+    }
+}
+
+impl AdaptiveLimit {
+    pub(crate) fn new(window: Duration, min_samples: u32) -> Self {
+        AdaptiveLimit {
+            window,
+            min_samples,
+            hosts: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Records an attempt towards `host` made at `now`, a failure is an attempt the policies
+    /// asked to retry
+    pub(crate) fn record(&self, host: &str, failed: bool, now: Instant) {
+        let mut hosts = self.hosts.borrow_mut();
+        if hosts.len() >= MAX_HOSTS && !hosts.contains_key(host) {
+            let healthiest = hosts.iter().min_by_key(|(_, health)| health.current.1 + health.previous.1).map(|(host, _)| host.clone());
+            if let Some(healthiest) = healthiest {
+                hosts.remove(&healthiest);
+            }
+        }
+        let health = hosts.entry(host.to_owned()).or_insert_with(|| Health {
+            start: now,
+            current: (0, 0),
+            previous: (0, 0),
+        });
+
+        health.roll(now, self.window);
+        health.current.0 += 1;
+        if failed {
+            health.current.1 += 1;
+        }
+    }
+
+    /// `max_retries` scaled by the success rate of `host` until `now`
+    pub(crate) fn limit(&self, host: &str, max_retries: u8, now: Instant) -> u8 {
+        let mut hosts = self.hosts.borrow_mut();
+        let health = match hosts.get_mut(host) {
+            Some(health) => health,
+            None => return max_retries,
+        };
+
+        health.roll(now, self.window);
+        let attempts = health.current.0 + health.previous.0;
+        let failures = health.current.1 + health.previous.1;
+        if attempts < self.min_samples.max(1) {
+            return max_retries;
+        }
+
+        let success_rate = 1.0 - f64::from(failures) / f64::from(attempts);
+        (f64::from(max_retries) * success_rate).round() as u8
+    }
+}
+
+/// Drops retries with a probability growing with the recent failure rate of all requests, the
+/// client side throttling of the Google SRE book
+pub(crate) struct AdaptiveThrottle {
+    window: Duration,
+    /// How many times more attempts than successes may be made before retries are dropped
+    overload: f64,
+    health: RefCell<Option<Health>>,
+}
+
+impl AdaptiveThrottle {
+    pub(crate) fn new(window: Duration, overload: f64) -> Self {
+        AdaptiveThrottle {
+            window,
+            overload: overload.max(1.0),
+            health: RefCell::new(None),
+        }
+    }
+
+    /// Records an attempt made at `now`, a failure is an attempt the policies asked to retry
+    pub(crate) fn record(&self, failed: bool, now: Instant) {
+        let mut health = self.health.borrow_mut();
+        let health = health.get_or_insert_with(|| Health {
+            start: now,
+            current: (0, 0),
+            previous: (0, 0),
+        });
+
+        health.roll(now, self.window);
+        health.current.0 += 1;
+        if failed {
+            health.current.1 += 1;
+        }
+    }
+
+    /// Whether a retry at `now` may be made, false at random once attempts outnumber successes
+    /// by more than `overload` times
+    pub(crate) fn admits(&self, now: Instant) -> bool {
+        let mut health = self.health.borrow_mut();
+        let health = match health.as_mut() {
+            Some(health) => health,
+            None => return true,
+        };
+
+        health.roll(now, self.window);
+        let attempts = f64::from(health.current.0 + health.previous.0);
+        let successes = attempts - f64::from(health.current.1 + health.previous.1);
+        let reject = ((attempts - self.overload * successes) / (attempts + 1.0)).max(0.0);
+        rand::random::<f64>() >= reject
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_are_kept_for_the_least_healthy_hosts() {
+        let (adaptive, now) = (AdaptiveLimit::new(Duration::from_secs(60), 1), Instant::now());
+        adaptive.record("failing.example.com", true, now);
+        for i in 0..MAX_HOSTS + 10 {
+            adaptive.record(&format!("{}.example.com", i), false, now);
+        }
+        assert_eq!(adaptive.hosts.borrow().len(), MAX_HOSTS);
+        assert_eq!(adaptive.limit("failing.example.com", 3, now), 0);
+    }
+}
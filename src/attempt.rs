@@ -0,0 +1,620 @@
+use actix_http::http::StatusCode;
+use actix_http::RequestHeadType;
+use awc::error::SendRequestError;
+use awc::ClientResponse;
+use futures::future::{self, Either};
+use std::convert::TryFrom;
+use std::fmt;
+use std::net::SocketAddr;
+use std::future::Future;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::policy::{self, AttemptContext, AttemptOutcome, RetryContext, RetryDecision, StatefulPolicy};
+use crate::audit::Entry;
+use crate::backoff::Delays;
+use crate::breaker::CircuitOpen;
+use crate::events::{AttemptEvent, EventSender, Timings};
+use crate::state::{Counter, HistoryEntry};
+use crate::{Inner, ReplayBody};
+
+/// How often a queued first attempt asks the circuit breaker again
+const QUEUE_POLL: Duration = Duration::from_millis(50);
+
+/// What an attempt ended with, the owned counterpart of [`AttemptOutcome`]
+pub(crate) enum Outcome {
+    Response(ClientResponse),
+    Error(SendRequestError),
+}
+
+impl Outcome {
+    pub(crate) fn context(&self, attempt: u32) -> RetryContext<'_> {
+        RetryContext {
+            attempt,
+            outcome: match self {
+                Outcome::Response(res) => AttemptOutcome::Response(res),
+                Outcome::Error(e) => AttemptOutcome::Error(e),
+            },
+        }
+    }
+
+    pub(crate) fn into_result(self) -> Result<ClientResponse, SendRequestError> {
+        match self {
+            Outcome::Response(res) => Ok(res),
+            Outcome::Error(e) => Err(e),
+        }
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Response(res) => write!(f, "response status {}", res.status()),
+            Outcome::Error(e) => write!(f, "error: {}", e),
+        }
+    }
+}
+
+/// What to do after an attempt
+pub(crate) enum Step {
+    /// Send the request again after the delay, once [`Attempts::wait`] agrees
+    Retry(Outcome, Duration),
+    /// Hand the outcome to the caller
+    Done(Outcome),
+}
+
+/// The state of one logical request across its attempts
+pub(crate) struct Attempts<'a> {
+    inner: &'a Inner,
+    tries: u8,
+    stateful: Vec<Box<dyn StatefulPolicy>>,
+    /// Delays before each retry, from the configured backoff
+    delays: Option<Box<dyn Delays>>,
+    /// Where the judged attempts are reported to
+    events: Option<EventSender>,
+    /// Whether the attempts of this request are logged, giving up always is
+    sampled: bool,
+    /// When the attempt in flight was sent
+    started: Option<Instant>,
+    /// Identifies the request in the audit log
+    request_id: u64,
+    /// Authority the request is sent to, when known
+    host: Option<String>,
+    /// Retries this request may make, `max_retries` unless the adaptive limit lowered it
+    limit: u8,
+    /// When the request has to be done by, from [`Retry::total_deadline`](crate::Retry::total_deadline)
+    deadline: Option<Instant>,
+    /// Head of the request, for the request policies
+    request: Option<&'a RequestHeadType>,
+    /// When the request was started, for the [`AttemptContext`]
+    began: Instant,
+    /// Status of the last attempt, if it got a response
+    last_status: Option<StatusCode>,
+    /// Whether a response was already retried for failing the integrity check
+    reread: bool,
+    /// Retries made for each of [`Inner::retry_limits`]
+    limited: Vec<u8>,
+    /// Delays of each of [`Inner::class_backoffs`], from the first retry it applied to
+    class_delays: Vec<Option<Box<dyn Delays>>>,
+    /// Address the attempt in flight connects to, when known
+    addr: Option<SocketAddr>,
+    /// Body bytes each retry re-sends, for the bandwidth guard
+    resend: u64,
+}
+
+impl<'a> Attempts<'a> {
+    pub(crate) fn new(inner: &'a Inner) -> Self {
+        let attempts = Attempts {
+            inner,
+            tries: 0,
+            stateful: inner.stateful.iter().map(|f| f()).collect(),
+            delays: inner.backoff.as_ref().map(|f| inner.delays(f)),
+            events: None,
+            sampled: inner.telemetry_ratio >= 1.0 || rand::random::<f64>() < inner.telemetry_ratio,
+            started: None,
+            request_id: rand::random(),
+            host: None,
+            limit: inner.max_retries,
+            deadline: inner.total_deadline.map(|deadline| inner.timer.now() + deadline),
+            request: None,
+            began: inner.timer.now(),
+            last_status: None,
+            reread: false,
+            limited: vec![0; inner.retry_limits.len()],
+            class_delays: inner.class_backoffs.iter().map(|_| None).collect(),
+            addr: None,
+            resend: 0,
+        };
+        attempts.count(Counter::Request);
+        inner.drain.enter();
+        attempts
+    }
+
+    /// Adds one to `counter` of the shared state, if there is one
+    pub(crate) fn count(&self, counter: Counter) {
+        if let Some(state) = &self.inner.state {
+            state.count(counter);
+        }
+    }
+
+    /// Reports every judged attempt to `events`
+    pub(crate) fn observe(&mut self, events: EventSender) {
+        self.events = Some(events);
+    }
+
+    /// Sets the request the attempts send, for the request policies and the host it goes to
+    pub(crate) fn sends(&mut self, head: &'a RequestHeadType) {
+        self.request = Some(head);
+        if let Some(authority) = head.as_ref().uri.authority() {
+            self.towards(authority.as_str());
+        }
+    }
+
+    /// Sets the body each retry re-sends, which the bandwidth guard is charged for
+    pub(crate) fn resends(&mut self, body: &ReplayBody) {
+        self.resend = match body {
+            ReplayBody::Bytes(b) => b.len() as u64,
+            ReplayBody::Empty | ReplayBody::None => 0,
+        };
+    }
+
+    /// Sets the address the next attempt connects to, when it isn't left to the connector
+    pub(crate) fn connects(&mut self, addr: Option<SocketAddr>) {
+        self.addr = addr;
+    }
+
+    /// Sets the host of the request, which the adaptive limit is applied to
+    fn towards(&mut self, host: &str) {
+        if let Some(adaptive) = &self.inner.adaptive {
+            self.limit = adaptive.limit(host, self.inner.max_retries, self.inner.timer.now());
+        }
+        self.host = Some(host.to_owned());
+    }
+
+    /// Number of retries made so far
+    pub(crate) fn tries(&self) -> u8 {
+        self.tries
+    }
+
+    /// Number of retries still allowed, by the adaptive limit and the retry budget
+    pub(crate) fn retries_left(&self) -> u8 {
+        let left = self.limit.saturating_sub(self.tries);
+        match &self.inner.budget {
+            Some(budget) => left.min(u8::try_from(budget.remaining()).unwrap_or(u8::MAX)),
+            None => left,
+        }
+    }
+
+    /// Marks the next attempt as sent, for the timings of its event
+    pub(crate) fn start(&mut self) {
+        self.started = Some(self.inner.timer.now());
+    }
+
+    /// Runs an attempt, failing it with a timeout once its own timeout or the deadline of the
+    /// request passed
+    pub(crate) async fn bounded<T, F>(&self, attempt: F) -> Result<T, SendRequestError>
+        where F: Future<Output=Result<T, SendRequestError>>
+    {
+        let own = self.inner.attempt_timeout.as_ref().and_then(|f| f(u32::from(self.tries) + 1));
+        let left = self.deadline.map(|deadline| deadline.saturating_duration_since(self.inner.timer.now()));
+        let timeout = match (own, left) {
+            (Some(own), Some(left)) => own.min(left),
+            (Some(timeout), None) | (None, Some(timeout)) => timeout,
+            (None, None) => return attempt.await,
+        };
+
+        futures::pin_mut!(attempt);
+        match future::select(attempt, self.inner.timer.sleep(timeout)).await {
+            Either::Left((res, _)) => res,
+            Either::Right(_) => Err(SendRequestError::Timeout),
+        }
+    }
+
+    /// Fails with [`CircuitOpen`] when the circuit breaker refuses the next attempt.
+    ///
+    /// A refused first attempt is queued for up to [`Retry::queue_first_attempt`](crate::Retry::queue_first_attempt).
+    /// An attempt towards a host which is nearly out of quota waits for the quota to reset first,
+    /// or fails with a timeout if the deadline of the request passes before that.
+    pub(crate) async fn permit(&self) -> Result<(), SendRequestError> {
+        if self.quota_wait().await.is_some() {
+            return Err(SendRequestError::Timeout);
+        }
+
+        if self.permitted() {
+            return Ok(());
+        }
+
+        if let (0, Some(max)) = (self.tries, self.inner.queue_first) {
+            let timer = &self.inner.timer;
+            let deadline = timer.now() + max;
+            while timer.now() < deadline {
+                timer.sleep(QUEUE_POLL.min(deadline.saturating_duration_since(timer.now()))).await;
+                if self.permitted() {
+                    return Ok(());
+                }
+            }
+        }
+
+        Err(CircuitOpen::error())
+    }
+
+    /// Holds the next attempt back until the rate limit quota of its host resets, naming what
+    /// cut the wait short
+    async fn quota_wait(&self) -> Option<&'static str> {
+        let (throttle, host) = match (&self.inner.throttle, &self.host) {
+            (Some(throttle), Some(host)) => (throttle, host),
+            _ => return None,
+        };
+        let now = self.inner.timer.now();
+        let delay = throttle.delay(host, now)?;
+        if self.deadline.is_some_and(|deadline| now.checked_add(delay).is_none_or(|at| at >= deadline)) {
+            log::debug!("not waiting {:?} for the quota of {} to reset, the deadline passes first", delay, host);
+            return Some("deadline");
+        }
+        // Draining cuts the wait short rather than holding up shutdown
+        match future::select(self.inner.timer.sleep(delay), self.inner.drain.stopped()).await {
+            Either::Left(_) => None,
+            Either::Right(_) => {
+                log::debug!("not waiting for the quota of {} to reset, retries are being drained", host);
+                Some("draining")
+            }
+        }
+    }
+
+    /// Whether the circuit breakers let the next attempt through
+    fn permitted(&self) -> bool {
+        let inner = self.inner;
+        inner.breaker.as_ref().is_none_or(|breaker| breaker.permit())
+            && match (&inner.host_breakers, &self.host) {
+                (Some(breakers), Some(host)) => breakers.permit(host, inner.timer.now()),
+                _ => true,
+            }
+    }
+
+    /// Judges the attempt which just finished, `target` names the request in log lines
+    pub(crate) async fn judge(&mut self, res: Result<ClientResponse, SendRequestError>, target: &dyn fmt::Display) -> Step {
+        let inner = self.inner;
+        self.count(Counter::Attempt);
+
+        let timer = &inner.timer;
+        let to_head = self.started.take().map(|start| timer.now().saturating_duration_since(start)).unwrap_or_default();
+        let checked = timer.now();
+        let mut head_delay = None;
+        // `permitted` is whether the request policies and terminal errors allow retrying at all,
+        // which the stateful policies can't override
+        let (outcome, mut retry, permitted) = match res {
+            Ok(res) => {
+                if let (Some(throttle), Some(host)) = (&inner.throttle, &self.host) {
+                    throttle.record(host, &res, inner.timer.now());
+                }
+                let ctx = AttemptContext {
+                    attempt: u32::from(self.tries) + 1,
+                    elapsed: timer.now().saturating_duration_since(self.began),
+                    last_status: self.last_status,
+                };
+                let (res, valid) = inner.check_response(res, self.request, &ctx).await;
+                let (res, intact) = match &inner.integrity {
+                    Some(integrity) if !self.reread && !inner.streaming_safe => integrity.verify(res, self.request).await,
+                    _ => (res, true),
+                };
+                if !intact {
+                    log::debug!("body of {} doesn't match its headers", target);
+                    self.reread = true;
+                }
+                let (res, delay) = inner.head_delay(res);
+                head_delay = delay;
+                let permitted = inner.policies.allow(self.request);
+                (Outcome::Response(res), (!valid || !intact) && permitted, permitted)
+            }
+            Err(e) => {
+                // Streams refused unprocessed are retried whatever the policies and method
+                let refused = inner.retry_refused_streams && policy::is_refused_stream(&e);
+                let permitted = refused || (!inner.is_terminal_error(&e) && inner.policies.allow(self.request));
+                let retry = refused || (permitted && inner.policies.retries_error(&e, self.request));
+                (Outcome::Error(e), retry, permitted)
+            }
+        };
+
+
+        let timings = Timings { to_head, checks: timer.now().saturating_duration_since(checked) };
+        if let Some(state) = &inner.state {
+            state.add_head_time(to_head);
+        }
+
+        self.last_status = match &outcome {
+            Outcome::Response(res) => Some(res.status()),
+            Outcome::Error(_) => None,
+        };
+        let ctx = outcome.context(u32::from(self.tries) + 1);
+        match policy::decide_all(&mut self.stateful, &ctx) {
+            RetryDecision::Retry => retry = permitted,
+            RetryDecision::Stop => retry = false,
+            RetryDecision::Defer => {}
+        }
+
+        if let Some(budget) = inner.budget.as_ref().filter(|_| !retry && matches!(outcome, Outcome::Response(_))) {
+            budget.deposit();
+        }
+        self.learn(!retry && matches!(outcome, Outcome::Response(_)), retry);
+
+        let hint = inner.wait_hint(&ctx, head_delay);
+        let delay = self.schedule(retry, hint, Some(&ctx.outcome), target, &outcome);
+
+        if let Some(events) = &self.events {
+            // The receiver may have been dropped, the request carries on regardless
+            let _ = events.unbounded_send(AttemptEvent::new(ctx.attempt, &outcome, timings, delay));
+        }
+
+        match delay {
+            Some(delay) => Step::Retry(outcome, delay),
+            None => Step::Done(outcome),
+        }
+    }
+
+    /// Judges a response refused for its credentials while there's another one to send it with,
+    /// retrying it at once within the same limits as other retries.
+    ///
+    /// The refusal says nothing about the health of the host, so the breakers, outlier ejection
+    /// and adaptive limits don't learn of it.
+    pub(crate) fn judge_refused(&mut self, res: ClientResponse, target: &dyn fmt::Display) -> Step {
+        self.count(Counter::Attempt);
+        self.started = None;
+        self.last_status = Some(res.status());
+
+        let permitted = self.inner.policies.allow(self.request);
+        let outcome = Outcome::Response(res);
+        let ctx = outcome.context(u32::from(self.tries) + 1);
+        match self.schedule(permitted, Some(Duration::from_secs(0)), Some(&ctx.outcome), target, &outcome) {
+            Some(delay) => Step::Retry(outcome, delay),
+            None => Step::Done(outcome),
+        }
+    }
+
+    /// Records how the attempt went with the circuit breakers, outlier ejection and adaptive
+    /// limits, `success` being a response which isn't retried
+    fn learn(&self, success: bool, retried: bool) {
+        let inner = self.inner;
+        let now = inner.timer.now();
+        if let Some(breaker) = &inner.breaker {
+            breaker.record(success);
+        }
+        if let (Some(breakers), Some(host)) = (&inner.host_breakers, &self.host) {
+            breakers.record(host, success, now);
+        }
+        if let (Some(outliers), Some(addr)) = (&inner.outliers, self.addr) {
+            outliers.record(addr, success, now);
+        }
+        if let (Some(adaptive), Some(host)) = (&inner.adaptive, &self.host) {
+            adaptive.record(host, retried, now);
+        }
+        if let Some(throttle) = &inner.adaptive_throttle {
+            throttle.record(retried, now);
+        }
+    }
+
+    /// Decides whether a retry the policies asked for may happen, returning the delay before it.
+    ///
+    /// `hint` is the wait the server asked for, which replaces the delay of the backoff. `last`
+    /// is handed to the backoff, when the attempt has an HTTP outcome.
+    pub(crate) fn schedule(
+        &mut self,
+        retry: bool,
+        hint: Option<Duration>,
+        last: Option<&AttemptOutcome<'_>>,
+        target: &dyn fmt::Display,
+        outcome: &dyn fmt::Display,
+    ) -> Option<Duration> {
+        let inner = self.inner;
+        // Outcomes with a limit of their own only count the retries they caused
+        let trigger = last.and_then(|last| inner.retry_limits.iter().position(|(applies, _)| applies(last)));
+        let (used, limit) = match trigger {
+            Some(i) => (self.limited[i], inner.retry_limits[i].1),
+            None => (self.tries, self.limit),
+        };
+
+        let (delay, reason) = if !retry {
+            (None, "not_retryable")
+        } else if used >= limit {
+            if limit > 0 {
+                self.count(Counter::GiveUp);
+                log::warn!("giving up on {} after {} attempts, the last ended with {}", target, u32::from(self.tries) + 1, outcome);
+            }
+            (None, "max_retries")
+        } else if inner.drain.is_draining() {
+            log::debug!("not retrying {} after {}, retries are being drained", target, outcome);
+            (None, "draining")
+        } else if inner.dry_run {
+            log::info!("dry run: would retry {} after {}", target, outcome);
+            (None, "dry_run")
+        } else {
+            let class = last.and_then(|last| inner.class_backoffs.iter().position(|(applies, _)| applies(last)));
+            let delays = match class {
+                Some(i) => Some(self.class_delays[i].get_or_insert_with(|| inner.delays(&inner.class_backoffs[i].1))),
+                None => self.delays.as_mut(),
+            };
+            let delay = match delays {
+                Some(delays) => delays.next(u32::from(self.tries) + 1, last),
+                None => Some(Duration::from_secs(0)),
+            };
+            match delay.map(|delay| hint.unwrap_or(delay)) {
+                Some(delay) if self.deadline.is_some_and(|deadline| inner.timer.now().checked_add(delay).is_none_or(|at| at >= deadline)) => {
+                    self.count(Counter::GiveUp);
+                    log::warn!("giving up on {} as its deadline passes before a retry, the last attempt ended with {}", target, outcome);
+                    (None, "deadline")
+                }
+                Some(delay) => match self.affords() {
+                    Some(reason) => {
+                        log::debug!("not retrying {} after {}, ruled out by the retry {}", target, outcome, reason);
+                        (None, reason)
+                    }
+                    None => (Some(delay), "policy"),
+                },
+                None => {
+                    self.count(Counter::GiveUp);
+                    log::warn!("giving up on {} as its backoff ran out, the last attempt ended with {}", target, outcome);
+                    (None, "backoff_exhausted")
+                }
+            }
+        };
+
+        if let (Some(i), Some(_)) = (trigger, delay) {
+            self.limited[i] += 1;
+        }
+
+        let decision = if delay.is_some() { "retry" } else { "return" };
+        self.audit(decision, reason, delay, target, outcome);
+        delay
+    }
+
+    /// Whether a retry may be scheduled, naming what rules it out
+    fn affords(&self) -> Option<&'static str> {
+        let inner = self.inner;
+        if inner.adaptive_throttle.as_ref().is_some_and(|throttle| !throttle.admits(inner.timer.now())) {
+            return Some("throttling");
+        }
+        if inner.budget.as_ref().is_some_and(|budget| budget.remaining() == 0) {
+            return Some("budget");
+        }
+        None
+    }
+
+    /// Pays for a retry about to be sent from the budget and the retry rate, naming the one which
+    /// can't afford it
+    fn spend(&self) -> Option<&'static str> {
+        let inner = self.inner;
+        if inner.budget.as_ref().is_some_and(|budget| !budget.withdraw()) {
+            return Some("budget");
+        }
+        if let Some(rate) = &inner.retry_rate {
+            if !rate.try_record(inner.timer.now()) {
+                if let Some(budget) = &inner.budget {
+                    budget.refund();
+                }
+                return Some("rate");
+            }
+        }
+        None
+    }
+
+    /// Whether the bandwidth guard lets a retry re-send the body
+    fn admits_resend(&self, target: &dyn fmt::Display, outcome: &dyn fmt::Display) -> bool {
+        match &self.inner.bandwidth {
+            Some(guard) if self.resend > 0 && !guard.fits(self.resend, self.inner.timer.now()) => {
+                log::debug!("not retrying {}, re-sending its {} byte body would exceed the bandwidth cap", target, self.resend);
+                self.audit("return", "bandwidth", None, target, outcome);
+                false
+            }
+            _ => true,
+        }
+    }
+
+    /// Records a retry decision in the audit log and the history of the shared state
+    pub(crate) fn audit(
+        &self,
+        decision: &'static str,
+        reason: &'static str,
+        delay: Option<Duration>,
+        target: &dyn fmt::Display,
+        outcome: &dyn fmt::Display,
+    ) {
+        if let Some(state) = &self.inner.state {
+            state.record(|| HistoryEntry {
+                time: SystemTime::now(),
+                host: self.host.clone(),
+                attempt: u32::from(self.tries) + 1,
+                decision,
+                reason,
+                delay,
+                outcome: outcome.to_string(),
+            });
+        }
+        if let Some(log) = &self.inner.audit {
+            log.record(&Entry {
+                request_id: self.request_id,
+                target,
+                attempt: u32::from(self.tries) + 1,
+                decision,
+                reason,
+                delay,
+                outcome,
+            });
+        }
+    }
+
+    /// Sleeps for the delay of a scheduled retry and the quota wait of its host, then checks it
+    /// isn't vetoed, pays for it and counts it.
+    ///
+    /// `ctx` is only missing for attempts without an HTTP outcome, which skip the vetoes.
+    pub(crate) async fn wait(
+        &mut self,
+        delay: Duration,
+        ctx: Option<&RetryContext<'_>>,
+        target: &dyn fmt::Display,
+        outcome: &dyn fmt::Display,
+    ) -> bool {
+        // Vetoes which already hold don't need the backoff waited out
+        if delay > Duration::from_secs(0) && self.vetoed(ctx, target, outcome) {
+            return false;
+        }
+
+        if delay > Duration::from_secs(0) {
+            if self.sampled {
+                log::debug!("waiting {:?} before retrying {}", delay, target);
+            }
+            let sleep = self.inner.timer.sleep(delay);
+            if let Either::Right(_) = future::select(sleep, self.inner.drain.stopped()).await {
+                log::debug!("not retrying {} after {}, retries are being drained", target, outcome);
+                self.audit("return", "draining", None, target, outcome);
+                return false;
+            }
+        }
+
+        if let Some(reason) = self.quota_wait().await {
+            log::debug!("not retrying {} after {}, the rate limit quota wait was cut short", target, outcome);
+            self.audit("return", reason, None, target, outcome);
+            return false;
+        }
+
+        if self.vetoed(ctx, target, outcome) || !self.admits_resend(target, outcome) {
+            return false;
+        }
+
+        if let Some(reason) = self.spend() {
+            log::debug!("not retrying {} after {}, ruled out by the retry {}", target, outcome, reason);
+            self.audit("return", reason, None, target, outcome);
+            return false;
+        }
+        if let Some(guard) = self.inner.bandwidth.as_ref().filter(|_| self.resend > 0) {
+            guard.charge(self.resend, self.inner.timer.now());
+        }
+
+        if self.sampled {
+            log::debug!("retrying {} after {}", target, outcome);
+        }
+        self.tries = self.tries.saturating_add(1);
+        self.count(Counter::Retry);
+        true
+    }
+
+    fn vetoed(&self, ctx: Option<&RetryContext<'_>>, target: &dyn fmt::Display, outcome: &dyn fmt::Display) -> bool {
+        let vetoed = ctx.is_some_and(|ctx| self.inner.vetoes.iter().any(|veto| veto(ctx)));
+        if vetoed {
+            if self.sampled {
+                log::debug!("retry of {} after {} was vetoed", target, outcome);
+            }
+            self.audit("return", "vetoed", None, target, outcome);
+        }
+        vetoed
+    }
+
+    /// [`wait`](Attempts::wait) for the retry of a judged attempt
+    pub(crate) async fn retry(&mut self, outcome: &Outcome, delay: Duration, target: &dyn fmt::Display) -> bool {
+        let attempt = u32::from(self.tries) + 1;
+        self.wait(delay, Some(&outcome.context(attempt)), target, outcome).await
+    }
+}
+
+impl Drop for Attempts<'_> {
+    fn drop(&mut self) {
+        self.inner.drain.leave();
+    }
+}
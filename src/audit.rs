@@ -0,0 +1,92 @@
+//! A JSON lines record of every retry decision.
+
+use std::cell::RefCell;
+use std::collections::hash_map::DefaultHasher;
+use std::fmt::{self, Write as _};
+use std::hash::{Hash, Hasher};
+use std::io::Write;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub(crate) struct AuditLog {
+    writer: RefCell<Box<dyn Write>>,
+}
+
+/// One retry decision, written as one line
+pub(crate) struct Entry<'a> {
+    /// Random per request, ties the lines of one request together
+    pub(crate) request_id: u64,
+    pub(crate) target: &'a dyn fmt::Display,
+    pub(crate) attempt: u32,
+    /// `retry` or `return`
+    pub(crate) decision: &'static str,
+    pub(crate) reason: &'static str,
+    pub(crate) delay: Option<Duration>,
+    pub(crate) outcome: &'a dyn fmt::Display,
+}
+
+impl AuditLog {
+    pub(crate) fn new<W: Write + 'static>(writer: W) -> Self {
+        AuditLog {
+            writer: RefCell::new(Box::new(writer)),
+        }
+    }
+
+    pub(crate) fn record(&self, entry: &Entry<'_>) {
+        let line = entry.to_json();
+        let mut writer = self.writer.borrow_mut();
+        if let Err(e) = writer.write_all(line.as_bytes()).and_then(|_| writer.flush()) {
+            log::warn!("failed to write retry audit log: {}", e);
+        }
+    }
+}
+
+impl Entry<'_> {
+    fn to_json(&self) -> String {
+        let target = self.target.to_string();
+        let mut hasher = DefaultHasher::new();
+        target.hash(&mut hasher);
+
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let delay = match self.delay {
+            Some(delay) => delay.as_millis().to_string(),
+            None => "null".to_owned(),
+        };
+
+        let mut line = String::new();
+        let _ = writeln!(
+            line,
+            "{{\"time_ms\":{},\"request_id\":\"{:016x}\",\"fingerprint\":\"{:016x}\",\"target\":{},\
+             \"attempt\":{},\"decision\":\"{}\",\"reason\":\"{}\",\"delay_ms\":{},\"outcome\":{}}}",
+            time.as_millis(),
+            self.request_id,
+            hasher.finish(),
+            json_string(&target),
+            self.attempt,
+            self.decision,
+            self.reason,
+            delay,
+            json_string(&self.outcome.to_string()),
+        );
+        line
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
@@ -0,0 +1,470 @@
+//! Delays between the attempts of a request.
+
+use std::iter;
+use std::time::Duration;
+
+use crate::{AttemptOutcome, BackoffFn, Inner, Retry};
+
+/// A delay schedule which can look at how the last attempt went, for [`Retry::backoff_with`].
+///
+/// Plain lists of delays don't need to implement this, any iterator of delays can be handed to
+/// [`Retry::backoff`].
+///
+/// ```
+/// use std::time::Duration;
+/// use awc_retry::{AttemptOutcome, Backoff, Retry};
+///
+/// /// Waits longer after errors than after bad responses
+/// #[derive(Clone)]
+/// struct Patient;
+///
+/// impl Backoff for Patient {
+///     fn next_delay(&mut self, attempt: u32, last: &AttemptOutcome<'_>) -> Option<Duration> {
+///         let base = match last {
+///             AttemptOutcome::Error(_) => Duration::from_secs(2),
+///             AttemptOutcome::Response(_) => Duration::from_millis(500),
+///         };
+///         Some(base * attempt)
+///     }
+/// }
+///
+/// let retry = Retry::new(3).backoff_with(Patient);
+/// ```
+pub trait Backoff {
+    /// The delay before retrying after `attempt` (the first attempt is `1`) ended with `last`,
+    /// `None` ends the retries.
+    fn next_delay(&mut self, attempt: u32, last: &AttemptOutcome<'_>) -> Option<Duration>;
+}
+
+/// A backoff as the retry engine drives it, `last` is missing for calls retried by
+/// [`retry_fn`](crate::retry_fn) which have no HTTP outcome
+pub(crate) trait Delays {
+    fn next(&mut self, attempt: u32, last: Option<&AttemptOutcome<'_>>) -> Option<Duration>;
+}
+
+impl<I: Iterator<Item=Duration>> Delays for I {
+    fn next(&mut self, _: u32, _: Option<&AttemptOutcome<'_>>) -> Option<Duration> {
+        Iterator::next(self)
+    }
+}
+
+/// Drives a [`Backoff`] implementation
+struct Custom<B>(B);
+
+impl<B: Backoff> Delays for Custom<B> {
+    fn next(&mut self, attempt: u32, last: Option<&AttemptOutcome<'_>>) -> Option<Duration> {
+        match last {
+            Some(last) => self.0.next_delay(attempt, last),
+            None => Some(Duration::from_secs(0)),
+        }
+    }
+}
+
+/// Delays growing by a multiplier after every retry, for [`Retry::backoff`].
+///
+/// ```
+/// use std::time::Duration;
+/// use awc_retry::{ExponentialBackoff, Retry};
+///
+/// let retry = Retry::new(5).backoff(ExponentialBackoff::new(Duration::from_millis(100)).max(Duration::from_secs(10)));
+///
+/// let delays: Vec<_> = ExponentialBackoff::new(Duration::from_secs(1)).multiplier(3.0).take(3).collect();
+/// assert_eq!(delays, [Duration::from_secs(1), Duration::from_secs(3), Duration::from_secs(9)]);
+/// ```
+#[derive(Debug, Clone)]
+pub struct ExponentialBackoff {
+    next: Duration,
+    multiplier: f64,
+    max: Option<Duration>,
+}
+
+impl ExponentialBackoff {
+    /// Starts at `initial` and doubles, without a maximum
+    pub fn new(initial: Duration) -> Self {
+        ExponentialBackoff {
+            next: initial,
+            multiplier: 2.0,
+            max: None,
+        }
+    }
+
+    /// What each delay is multiplied by to get the next one
+    pub fn multiplier(mut self, multiplier: f64) -> Self {
+        self.multiplier = multiplier;
+        self
+    }
+
+    /// Stops the delays from growing past `max`
+    pub fn max(mut self, max: Duration) -> Self {
+        self.max = Some(max);
+        self.next = self.next.min(max);
+        self
+    }
+}
+
+impl Iterator for ExponentialBackoff {
+    type Item = Duration;
+
+    fn next(&mut self) -> Option<Duration> {
+        let delay = self.next;
+        let grown = delay.as_secs_f64() * self.multiplier;
+        let max = self.max.map_or(f64::from(u32::MAX), |max| max.as_secs_f64());
+        // Also keeps the delay in range of `Duration` when there's no maximum
+        self.next = if grown < max {
+            Duration::from_secs_f64(grown.max(0.0))
+        } else {
+            self.max.unwrap_or(delay)
+        };
+        Some(delay)
+    }
+}
+
+/// Delays growing by `base` after every retry, `base * attempt`, for [`Retry::backoff`].
+///
+/// ```
+/// use std::time::Duration;
+/// use awc_retry::LinearBackoff;
+///
+/// let delays: Vec<_> = LinearBackoff::new(Duration::from_secs(1)).max(Duration::from_secs(2)).take(3).collect();
+/// assert_eq!(delays, [Duration::from_secs(1), Duration::from_secs(2), Duration::from_secs(2)]);
+/// ```
+#[derive(Debug, Clone)]
+pub struct LinearBackoff {
+    base: Duration,
+    next: Duration,
+    max: Duration,
+}
+
+impl LinearBackoff {
+    /// Starts at `base`, without a maximum
+    pub fn new(base: Duration) -> Self {
+        LinearBackoff {
+            base,
+            next: base,
+            max: Duration::MAX,
+        }
+    }
+
+    /// Stops the delays from growing past `max`
+    pub fn max(mut self, max: Duration) -> Self {
+        self.max = max;
+        self.next = self.next.min(max);
+        self
+    }
+}
+
+impl Iterator for LinearBackoff {
+    type Item = Duration;
+
+    fn next(&mut self) -> Option<Duration> {
+        let delay = self.next;
+        self.next = delay.checked_add(self.base).map_or(self.max, |next| next.min(self.max));
+        Some(delay)
+    }
+}
+
+/// Delays following the Fibonacci sequence, `base`, `base`, `2 * base`, `3 * base`, `5 * base`,
+/// ..., for [`Retry::backoff`].
+///
+/// ```
+/// use std::time::Duration;
+/// use awc_retry::FibonacciBackoff;
+///
+/// let delays: Vec<_> = FibonacciBackoff::new(Duration::from_secs(1)).take(5).map(|d| d.as_secs()).collect();
+/// assert_eq!(delays, [1, 1, 2, 3, 5]);
+/// ```
+#[derive(Debug, Clone)]
+pub struct FibonacciBackoff {
+    next: Duration,
+    after: Duration,
+    max: Duration,
+}
+
+impl FibonacciBackoff {
+    /// Starts at `base`, without a maximum
+    pub fn new(base: Duration) -> Self {
+        FibonacciBackoff {
+            next: base,
+            after: base,
+            max: Duration::MAX,
+        }
+    }
+
+    /// Stops the delays from growing past `max`
+    pub fn max(mut self, max: Duration) -> Self {
+        self.max = max;
+        self.next = self.next.min(max);
+        self.after = self.after.min(max);
+        self
+    }
+}
+
+impl Iterator for FibonacciBackoff {
+    type Item = Duration;
+
+    fn next(&mut self) -> Option<Duration> {
+        let delay = self.next;
+        let after = delay.checked_add(self.after).map_or(self.max, |after| after.min(self.max));
+        self.next = self.after;
+        self.after = after;
+        Some(delay)
+    }
+}
+
+/// Randomization of backoff delays, so clients which failed together don't retry together,
+/// see [`Retry::jitter`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Jitter {
+    /// Anywhere between no delay and the full delay
+    Full,
+    /// At least half of the delay, up to the full delay
+    Equal,
+    /// Between the first delay of the backoff and three times the previous jittered delay,
+    /// capped by the delay of the backoff
+    Decorrelated,
+}
+
+impl Jitter {
+    /// Applies the jitter to every delay of `delays`
+    pub(crate) fn apply(self, delays: Box<dyn Delays>) -> Box<dyn Delays> {
+        Box::new(Jittered {
+            jitter: self,
+            delays,
+            first: None,
+            previous: None,
+        })
+    }
+}
+
+struct Jittered {
+    jitter: Jitter,
+    delays: Box<dyn Delays>,
+    /// First delay of `delays`, for decorrelated jitter
+    first: Option<Duration>,
+    /// Last jittered delay, for decorrelated jitter
+    previous: Option<Duration>,
+}
+
+impl Delays for Jittered {
+    fn next(&mut self, attempt: u32, last: Option<&AttemptOutcome<'_>>) -> Option<Duration> {
+        let delay = self.delays.next(attempt, last)?;
+        let jittered = match self.jitter {
+            Jitter::Full => delay.mul_f64(rand::random::<f64>()),
+            Jitter::Equal => delay / 2 + (delay / 2).mul_f64(rand::random::<f64>()),
+            Jitter::Decorrelated => {
+                let first = *self.first.get_or_insert(delay);
+                let high = self.previous.map_or(delay, |previous| (previous * 3).min(delay));
+                let low = first.min(high);
+                low + (high - low).mul_f64(rand::random::<f64>())
+            }
+        };
+        self.previous = Some(jittered);
+        Some(jittered)
+    }
+}
+
+impl Retry {
+    /// Randomizes the delays of the [`backoff`](Retry::backoff), whichever backoff it is.
+    /// [`validate`](Retry::validate) rejects a jitter without one.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use awc_retry::{ExponentialBackoff, Jitter, Retry};
+    ///
+    /// let retry = Retry::new(5)
+    ///     .backoff(ExponentialBackoff::new(Duration::from_millis(100)).max(Duration::from_secs(10)))
+    ///     .jitter(Jitter::Full);
+    /// ```
+    pub fn jitter(mut self, jitter: Jitter) -> Self {
+        self.0.jitter = Some(jitter);
+        self
+    }
+
+    /// Waits the same `delay` before every retry, a shorthand for a [`backoff`](Retry::backoff)
+    /// repeating it.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use awc_retry::Retry;
+    ///
+    /// let retry = Retry::new(3).delay(Duration::from_secs(1));
+    /// ```
+    pub fn delay(self, delay: Duration) -> Self {
+        self.backoff(iter::repeat(delay))
+    }
+
+    /// Waits between attempts according to `schedule`, the delays before the first, second,
+    /// ... retry.
+    ///
+    /// Every request starts from a fresh clone of `schedule`, a schedule which runs out of
+    /// delays ends the retries even if `max_retries` allows more.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use awc_retry::Retry;
+    ///
+    /// let retry = Retry::new(4).backoff(vec![
+    ///     Duration::from_millis(0),
+    ///     Duration::from_millis(250),
+    ///     Duration::from_secs(1),
+    ///     Duration::from_secs(5),
+    /// ]);
+    /// ```
+    pub fn backoff<I>(mut self, schedule: I) -> Self
+        where
+            I: IntoIterator<Item=Duration> + Clone + 'static,
+            I::IntoIter: 'static,
+    {
+        self.0.backoff = Some(Box::new(move || Box::new(schedule.clone().into_iter())));
+        self
+    }
+
+    /// Waits between attempts according to a [`Backoff`] implementation, every request starts
+    /// from a fresh clone of `backoff`.
+    ///
+    /// Calls retried by [`retry_fn`](crate::retry_fn) have no outcome to hand to the backoff,
+    /// so they are retried without a delay.
+    pub fn backoff_with<B>(mut self, backoff: B) -> Self
+        where B: Backoff + Clone + 'static
+    {
+        self.0.backoff = Some(Box::new(move || Box::new(Custom(backoff.clone()))));
+        self
+    }
+
+    /// Waits according to `schedule` before retrying the outcomes `f` returns true for, in place
+    /// of the [`backoff`](Retry::backoff), such as retrying refused connections at once while
+    /// giving rate limits time.
+    ///
+    /// The schedule only moves on for the retries it applies to, and the first schedule `f`
+    /// applies to wins. [`jitter`](Retry::jitter) applies to these schedules as well.
+    ///
+    /// ```
+    /// use std::iter;
+    /// use std::time::Duration;
+    /// use actix_http::http::StatusCode;
+    /// use awc_retry::{AttemptOutcome, ExponentialBackoff, Retry};
+    ///
+    /// let retry = Retry::new(5)
+    ///     .backoff(ExponentialBackoff::new(Duration::from_millis(100)))
+    ///     .backoff_for(
+    ///         |outcome| matches!(outcome, AttemptOutcome::Response(res) if res.status() == StatusCode::TOO_MANY_REQUESTS),
+    ///         ExponentialBackoff::new(Duration::from_secs(5)),
+    ///     )
+    ///     .backoff_for(|outcome| matches!(outcome, AttemptOutcome::Error(_)), iter::repeat(Duration::from_millis(10)));
+    /// ```
+    pub fn backoff_for<F, I>(mut self, f: F, schedule: I) -> Self
+        where
+            F: Fn(&AttemptOutcome<'_>) -> bool + 'static,
+            I: IntoIterator<Item=Duration> + Clone + 'static,
+            I::IntoIter: 'static,
+    {
+        let backoff: Box<BackoffFn> = Box::new(move || Box::new(schedule.clone().into_iter()));
+        self.0.class_backoffs.push((Box::new(f), backoff));
+        self
+    }
+}
+
+impl Inner {
+    /// A fresh run of the delays of `backoff`, jittered if configured
+    pub(crate) fn delays(&self, backoff: &BackoffFn) -> Box<dyn Delays> {
+        match self.jitter {
+            Some(jitter) => jitter.apply(backoff()),
+            None => backoff(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::timer::MockTimer;
+    use crate::{retry_fn, Replayable};
+    use actix_service::{fn_service, Service};
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn exponential_grows_up_to_its_max() {
+        let delays: Vec<_> = ExponentialBackoff::new(ms(100)).max(ms(300)).take(4).collect();
+        assert_eq!(delays, [ms(100), ms(200), ms(300), ms(300)]);
+
+        let delays: Vec<_> = ExponentialBackoff::new(ms(100)).multiplier(3.0).take(3).collect();
+        assert_eq!(delays, [ms(100), ms(300), ms(900)]);
+    }
+
+    #[test]
+    fn exponential_without_max_stays_in_range() {
+        let last = ExponentialBackoff::new(Duration::from_secs(1)).nth(100).unwrap();
+        assert!(last <= Duration::from_secs(u64::from(u32::MAX)));
+    }
+
+    #[test]
+    fn linear_and_fibonacci_grow_up_to_their_max() {
+        let delays: Vec<_> = LinearBackoff::new(ms(100)).max(ms(250)).take(4).collect();
+        assert_eq!(delays, [ms(100), ms(200), ms(250), ms(250)]);
+
+        let delays: Vec<_> = FibonacciBackoff::new(ms(100)).max(ms(400)).take(6).collect();
+        assert_eq!(delays, [ms(100), ms(100), ms(200), ms(300), ms(400), ms(400)]);
+    }
+
+    fn jittered(jitter: Jitter, delays: Vec<Duration>) -> Vec<Duration> {
+        let mut jittered = jitter.apply(Box::new(delays.into_iter()));
+        iter::from_fn(|| jittered.next(1, None)).collect()
+    }
+
+    #[test]
+    fn full_and_equal_jitter_stay_within_the_delay() {
+        for _ in 0..100 {
+            for delay in jittered(Jitter::Full, vec![ms(1000); 10]) {
+                assert!(delay <= ms(1000));
+            }
+            for delay in jittered(Jitter::Equal, vec![ms(1000); 10]) {
+                assert!(delay >= ms(500) && delay <= ms(1000));
+            }
+        }
+    }
+
+    #[test]
+    fn decorrelated_jitter_stays_between_the_first_delay_and_three_times_the_previous() {
+        for _ in 0..100 {
+            let backoff: Vec<_> = ExponentialBackoff::new(ms(100)).take(8).collect();
+            let delays = jittered(Jitter::Decorrelated, backoff.clone());
+            assert_eq!(delays[0], ms(100));
+            for i in 1..delays.len() {
+                assert!(delays[i] >= ms(100) && delays[i] <= (delays[i - 1] * 3).min(backoff[i]));
+            }
+        }
+    }
+
+    #[derive(Clone)]
+    struct Ping;
+
+    impl Replayable for Ping {
+        fn replay(&self) -> Self {
+            Ping
+        }
+    }
+
+    #[test]
+    fn retries_sleep_the_backoff_on_the_timer() {
+        let timer = MockTimer::new();
+        let retry = Retry::new(3).backoff(ExponentialBackoff::new(ms(100))).timer(timer.clone());
+        let service = retry_fn(fn_service(|_: Ping| async { Err::<(), _>("down") }), retry);
+
+        let res = actix_rt::System::new().block_on(service.call(Ping));
+        assert_eq!(res, Err("down"));
+        assert_eq!(timer.elapsed(), ms(100 + 200 + 400));
+    }
+
+    #[test]
+    fn jittered_retries_sleep_at_most_the_backoff() {
+        let timer = MockTimer::new();
+        let retry = Retry::new(3).delay(ms(100)).jitter(Jitter::Equal).timer(timer.clone());
+        let service = retry_fn(fn_service(|_: Ping| async { Err::<(), _>("down") }), retry);
+
+        let _ = actix_rt::System::new().block_on(service.call(Ping));
+        assert!(timer.elapsed() >= ms(150) && timer.elapsed() <= ms(300));
+    }
+}
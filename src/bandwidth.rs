@@ -0,0 +1,41 @@
+//! Limits on the request body bytes sent again by retries.
+
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+
+pub(crate) struct BandwidthGuard {
+    /// Bytes which may be re-sent per window
+    limit: u64,
+    window: Duration,
+    /// Start of the current window and the bytes re-sent in it, once anything was
+    used: Cell<Option<(Instant, u64)>>,
+}
+
+impl BandwidthGuard {
+    pub(crate) fn new(limit: u64, window: Duration) -> Self {
+        BandwidthGuard {
+            limit,
+            window,
+            used: Cell::new(None),
+        }
+    }
+
+    /// Whether `bytes` fit in the allowance of the window at `now`
+    pub(crate) fn fits(&self, bytes: u64, now: Instant) -> bool {
+        self.window_at(now).1.saturating_add(bytes) <= self.limit
+    }
+
+    /// Takes `bytes` from the allowance of the window at `now`
+    pub(crate) fn charge(&self, bytes: u64, now: Instant) {
+        let (start, used) = self.window_at(now);
+        self.used.set(Some((start, used.saturating_add(bytes))));
+    }
+
+    /// Start of the window at `now` and the bytes re-sent in it
+    fn window_at(&self, now: Instant) -> (Instant, u64) {
+        match self.used.get() {
+            Some((start, used)) if now.saturating_duration_since(start) < self.window => (start, used),
+            _ => (now, 0),
+        }
+    }
+}
@@ -0,0 +1,183 @@
+//! Buffering of response bodies for the policies which inspect them.
+
+use actix_http::encoding::Decoder;
+use actix_http::http::{header, ContentEncoding};
+use actix_http::{Payload, PayloadStream};
+use awc::ClientResponse;
+use bytes::{Bytes, BytesMut};
+use futures::{stream, StreamExt};
+
+/// What [`buffer`] found out about a body
+pub(crate) enum Buffered {
+    /// The whole body
+    Complete(Bytes),
+    /// The body is longer than this many bytes
+    TooLong(usize),
+    /// Reading the body failed, or it's in an encoding this can't decode
+    Failed,
+}
+
+impl Buffered {
+    /// Whether buffering up to `limit` bytes could turn this into a complete body
+    pub(crate) fn can_grow_to(&self, limit: usize) -> bool {
+        matches!(self, Buffered::TooLong(len) if limit > *len)
+    }
+}
+
+/// Reads up to `limit` bytes of the body of `res`.
+///
+/// The returned response still yields the whole body, including a read error if there was one,
+/// so callers see the same body with or without buffering.
+pub(crate) async fn buffer(res: ClientResponse, limit: usize) -> (ClientResponse, Buffered) {
+    let mut payload = None;
+    let res = res.map_body(|_, pl| {
+        payload = Some(pl);
+        Payload::None
+    });
+    let mut payload = match payload {
+        Some(payload) => payload,
+        None => return (res, Buffered::Failed),
+    };
+
+    let mut buf = BytesMut::new();
+    let (rest, buffered): (PayloadStream, _) = loop {
+        match payload.next().await {
+            Some(Ok(chunk)) => {
+                buf.extend_from_slice(&chunk);
+                if buf.len() > limit {
+                    let rest = stream::iter(vec![Ok(buf.freeze())]).chain(payload);
+                    break (Box::pin(rest), Buffered::TooLong(limit));
+                }
+            }
+            Some(Err(e)) => break (Box::pin(stream::iter(vec![Ok(buf.freeze()), Err(e)])), Buffered::Failed),
+            None => {
+                let body = buf.freeze();
+                break (Box::pin(stream::iter(vec![Ok(body.clone())])), Buffered::Complete(body));
+            }
+        }
+    };
+
+    (res.map_body(|_, _| Payload::Stream(rest)), buffered)
+}
+
+/// Buffers up to `limit` bytes of the body of `res` into `body`, unless the policies checked
+/// before already buffered as much.
+///
+/// The middleware sees bodies before the client decompresses them, so `body` is decoded by the
+/// `Content-Encoding` of `res` for the policies to look at, while `res` keeps the body as sent.
+pub(crate) async fn buffer_shared(res: ClientResponse, body: &mut Option<Buffered>, limit: usize) -> ClientResponse {
+    if body.as_ref().is_some_and(|b| !b.can_grow_to(limit)) {
+        return res;
+    }
+
+    let (res, buffered) = buffer(res, limit).await;
+    *body = Some(decode(&res, buffered, limit).await);
+    res
+}
+
+/// Decodes a complete body the way the client would, up to `limit` decoded bytes
+async fn decode(res: &ClientResponse, buffered: Buffered, limit: usize) -> Buffered {
+    let raw = match buffered {
+        Buffered::Complete(raw) => raw,
+        buffered => return buffered,
+    };
+    let encoding = match res.headers().get(header::CONTENT_ENCODING) {
+        Some(value) => value.to_str().unwrap_or_default().trim(),
+        None => return Buffered::Complete(raw),
+    };
+    let encoding = match ContentEncoding::from(encoding) {
+        ContentEncoding::Identity if encoding.eq_ignore_ascii_case("identity") => return Buffered::Complete(raw),
+        // Unknown and stacked encodings can't be looked into
+        ContentEncoding::Identity | ContentEncoding::Auto => return Buffered::Failed,
+        encoding => encoding,
+    };
+
+    let mut decoder = Decoder::new(stream::iter(vec![Ok(raw)]), encoding);
+    let mut buf = BytesMut::new();
+    while let Some(chunk) = decoder.next().await {
+        match chunk {
+            Ok(chunk) => buf.extend_from_slice(&chunk),
+            Err(_) => return Buffered::Failed,
+        }
+        if buf.len() > limit {
+            return Buffered::TooLong(limit);
+        }
+    }
+    Buffered::Complete(buf.freeze())
+}
+
+/// The `Content-Length` of `res`, unless its body is content-encoded, so the length of what is
+/// read from it depends on whether the client decompresses it
+pub(crate) fn declared_length(res: &ClientResponse) -> Option<usize> {
+    let encoded = res.headers()
+        .get(header::CONTENT_ENCODING)
+        .is_some_and(|v| !v.as_bytes().eq_ignore_ascii_case(b"identity"));
+    if encoded {
+        return None;
+    }
+
+    res.headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok()?.trim().parse::<usize>().ok())
+}
+
+/// The `<Code>` of an XML error document like `<Error><Code>SlowDown</Code>...</Error>`
+pub(crate) fn xml_error_code(body: &[u8]) -> Option<&str> {
+    let doc = std::str::from_utf8(body).ok()?;
+    let root = doc.find("<Error>")?;
+    let doc = &doc[root..];
+
+    let start = doc.find("<Code>")? + "<Code>".len();
+    let len = doc[start..].find("</Code>")?;
+    Some(doc[start..start + len].trim())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use awc::test::TestResponse;
+
+    /// An S3 `SlowDown` error document of 319 bytes, gzipped
+    const GZIPPED_ERROR: &[u8] = &[
+        0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0xb3, 0x71, 0x2d, 0x2a, 0xca, 0x2f,
+        0xb2, 0xb3, 0x71, 0xce, 0x4f, 0x49, 0xb5, 0x0b, 0xce, 0xc9, 0x2f, 0x77, 0xc9, 0x2f, 0xcf, 0xb3,
+        0xd1, 0x07, 0x73, 0x6d, 0x7c, 0x53, 0x8b, 0x8b, 0x13, 0xd3, 0x53, 0xed, 0x02, 0x72, 0x52, 0x13,
+        0x8b, 0x53, 0x15, 0x8a, 0x52, 0x53, 0x4a, 0x93, 0x53, 0x15, 0x2a, 0xf3, 0x4b, 0x8b, 0x80, 0xec,
+        0xc2, 0xd2, 0xd4, 0xe2, 0x12, 0x85, 0xa2, 0xc4, 0x92, 0x54, 0x3d, 0x85, 0x11, 0xa2, 0xc0, 0x46,
+        0x1f, 0x16, 0x22, 0x36, 0xfa, 0x90, 0x70, 0x03, 0x00, 0x33, 0xf2, 0x5b, 0xd8, 0x3f, 0x01, 0x00,
+        0x00,
+    ];
+
+    fn buffered(encoding: &str, payload: &'static [u8], limit: usize) -> (Bytes, Option<Buffered>) {
+        let res = TestResponse::default().header("content-encoding", encoding).set_payload(payload).finish();
+        actix_rt::System::new().block_on(async {
+            let mut body = None;
+            let mut res = buffer_shared(res, &mut body, limit).await;
+            (res.body().await.unwrap(), body)
+        })
+    }
+
+    #[test]
+    fn encoded_bodies_are_decoded_for_the_policies() {
+        let (sent, body) = buffered("gzip", GZIPPED_ERROR, 1024);
+        // The client decompresses the body itself, so it's handed on as it came
+        assert_eq!(sent, GZIPPED_ERROR);
+        match body {
+            Some(Buffered::Complete(body)) => assert_eq!(xml_error_code(&body), Some("SlowDown")),
+            _ => panic!("the gzipped body wasn't decoded"),
+        }
+    }
+
+    #[test]
+    fn decoded_bodies_longer_than_the_limit_are_too_long() {
+        let (_, body) = buffered("gzip", GZIPPED_ERROR, 200);
+        assert!(matches!(body, Some(Buffered::TooLong(_))));
+    }
+
+    #[test]
+    fn unknown_encodings_and_broken_bodies_cant_be_inspected() {
+        assert!(matches!(buffered("zstd", GZIPPED_ERROR, 1024).1, Some(Buffered::Failed)));
+        assert!(matches!(buffered("gzip", b"not gzip", 1024).1, Some(Buffered::Failed)));
+        assert!(matches!(buffered("identity", b"plain", 1024).1, Some(Buffered::Complete(_))));
+    }
+}
@@ -0,0 +1,527 @@
+//! Circuit breakers consulted before every attempt.
+
+use awc::error::{ConnectError, SendRequestError};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use std::{fmt, io};
+
+use crate::Retry;
+
+/// Decides whether attempts may be made and learns from how they went
+pub(crate) trait Breaker {
+    /// Whether the next attempt may be sent
+    fn permit(&self) -> bool;
+    /// Records how an attempt went, `success` is false for errors and retried responses
+    fn record(&self, success: bool);
+}
+
+/// The error an attempt fails with when a circuit breaker refuses it.
+///
+/// It is carried as the source of a `SendRequestError::Connect(ConnectError::Io(_))`, use
+/// [`CircuitOpen::matches`] to tell it apart from real connection errors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CircuitOpen;
+
+impl CircuitOpen {
+    /// Whether `err` is a request refused by a circuit breaker
+    pub fn matches(err: &SendRequestError) -> bool {
+        match err {
+            SendRequestError::Connect(ConnectError::Io(e)) => {
+                e.get_ref().is_some_and(|e| e.is::<CircuitOpen>())
+            }
+            _ => false,
+        }
+    }
+
+    pub(crate) fn error() -> SendRequestError {
+        SendRequestError::Connect(ConnectError::Io(io::Error::other(CircuitOpen)))
+    }
+}
+
+impl fmt::Display for CircuitOpen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "circuit breaker is open")
+    }
+}
+
+impl std::error::Error for CircuitOpen {}
+
+/// Circuit breakers of their own for each host, see [`Retry::host_breaker_with`].
+///
+/// A host's circuit opens once `failures` attempts in a row towards it failed. After
+/// `cooldown` it turns half-open and lets `probes` attempts through at a time: `successes` of
+/// them succeeding in a row close it, any of them failing opens it for another `cooldown`.
+/// Probes which never finish, such as of cancelled requests, are given up on after `cooldown`.
+///
+/// Built with [`failure_rate`](HostBreaker::failure_rate) the circuit opens by the share of
+/// failures among the latest attempts instead, as resilience4j does.
+///
+/// Clones share the circuits, so configurations given clones of one breaker open and close
+/// the circuit of a host together.
+///
+/// ```
+/// use std::time::Duration;
+/// use awc_retry::{HostBreaker, Retry};
+///
+/// let breaker = HostBreaker::new(5, Duration::from_secs(30)).half_open(2, 3);
+/// let reads = Retry::new(3).policy(500..=599).host_breaker_with(breaker.clone());
+/// let writes = Retry::new(1).policy(502..=504).host_breaker_with(breaker);
+/// ```
+#[derive(Clone)]
+pub struct HostBreaker {
+    /// Failures in a row which open a host's circuit
+    threshold: u32,
+    cooldown: Duration,
+    /// Attempts let through at a time while half-open
+    probes: u32,
+    /// Successful probes in a row which close the circuit
+    successes: u32,
+    /// Share of failures among the latest attempts, and how many, which opens a host's circuit
+    window: Option<(f64, usize)>,
+    hosts: Arc<Mutex<Circuits>>,
+}
+
+#[derive(Default)]
+struct Circuits {
+    hosts: HashMap<String, Circuit>,
+    /// The time of the retries' timer and of the system clock when the breaker was last used
+    clock: Option<(Instant, Instant)>,
+}
+
+impl Circuits {
+    /// The time of the retries' timer, going by the system clock since it was last read
+    fn now(&self) -> Instant {
+        let now = Instant::now();
+        self.clock.map_or(now, |(timer, system)| timer + now.saturating_duration_since(system))
+    }
+}
+
+enum Circuit {
+    /// `recent` are whether the latest attempts failed, oldest first, only kept for a window
+    Closed { failures: u32, recent: VecDeque<bool> },
+    /// Attempts are refused until `until`
+    Open { until: Instant, failures: u32 },
+    HalfOpen { in_flight: u32, successes: u32, since: Instant },
+}
+
+/// Where a host's circuit stands, see [`BreakerHandle`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum CircuitState {
+    /// Attempts are sent
+    Closed,
+    /// Attempts are refused until the next probe
+    Open,
+    /// Probes are let through
+    HalfOpen,
+}
+
+/// A host's circuit at the time of [`BreakerHandle::status`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[non_exhaustive]
+pub struct CircuitStatus {
+    pub state: CircuitState,
+    /// Failures in a row, those which opened the circuit while it's open
+    pub failures: u32,
+    /// How long until the circuit lets a probe through, while it's open
+    pub next_probe: Option<Duration>,
+}
+
+/// Reads the circuits of a [`HostBreaker`], for health endpoints and dashboards.
+///
+/// Clones look at the same circuits, and can be sent to other threads. Cool-downs follow the
+/// [`timer`](Retry::timer) of the retries, as of the last attempt the breaker saw plus the time
+/// passed on the system clock since.
+///
+/// ```
+/// use std::time::Duration;
+/// use awc_retry::{CircuitState, HostBreaker, Retry};
+///
+/// let breaker = HostBreaker::new(5, Duration::from_secs(30));
+/// let handle = breaker.handle();
+/// let retry = Retry::new(3).host_breaker_with(breaker);
+///
+/// assert_eq!(handle.status("api.example.com").state, CircuitState::Closed);
+/// let health = handle.clone();
+/// std::thread::spawn(move || {
+///     for (host, status) in health.hosts() {
+///         println!("{}: {:?} after {} failures", host, status.state, status.failures);
+///     }
+/// });
+/// ```
+#[derive(Clone)]
+pub struct BreakerHandle(Arc<Mutex<Circuits>>);
+
+impl BreakerHandle {
+    /// The circuit of `host`, an authority such as `example.com:8080`
+    pub fn status(&self, host: &str) -> CircuitStatus {
+        let circuits = lock(&self.0);
+        circuits.hosts.get(host).map_or(CLOSED, |circuit| circuit.status(circuits.now()))
+    }
+
+    /// The circuits of the hosts which failed since their last success, or with
+    /// [`failure_rate`](HostBreaker::failure_rate) among their latest attempts, in no particular
+    /// order
+    pub fn hosts(&self) -> Vec<(String, CircuitStatus)> {
+        let circuits = lock(&self.0);
+        let now = circuits.now();
+        circuits.hosts.iter().map(|(host, circuit)| (host.clone(), circuit.status(now))).collect()
+    }
+}
+
+/// Hosts tracked at most with [`HostBreaker::failure_rate`], beyond which the closed circuit
+/// with the fewest recent failures is forgotten. The other per host state is capped the same.
+pub(crate) const MAX_HOSTS: usize = 4096;
+
+fn evict(hosts: &mut HashMap<String, Circuit>) {
+    let healthiest = hosts
+        .iter()
+        .filter_map(|(host, circuit)| match circuit {
+            Circuit::Closed { recent, .. } => Some((recent.iter().filter(|failed| **failed).count(), host)),
+            _ => None,
+        })
+        .min()
+        .map(|(_, host)| host.clone());
+    if let Some(host) = healthiest {
+        hosts.remove(&host);
+    }
+}
+
+fn lock(circuits: &Mutex<Circuits>) -> MutexGuard<'_, Circuits> {
+    circuits.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+const CLOSED: CircuitStatus = CircuitStatus { state: CircuitState::Closed, failures: 0, next_probe: None };
+
+impl Circuit {
+    fn status(&self, now: Instant) -> CircuitStatus {
+        match self {
+            Circuit::Closed { failures, .. } => CircuitStatus { failures: *failures, ..CLOSED },
+            Circuit::Open { until, failures } => CircuitStatus {
+                state: CircuitState::Open,
+                failures: *failures,
+                next_probe: Some(until.saturating_duration_since(now)),
+            },
+            Circuit::HalfOpen { .. } => CircuitStatus { state: CircuitState::HalfOpen, ..CLOSED },
+        }
+    }
+}
+
+impl HostBreaker {
+    /// Opens after `failures` in a row for `cooldown`, then closes after a single successful
+    /// probe
+    pub fn new(failures: u32, cooldown: Duration) -> Self {
+        HostBreaker {
+            threshold: failures.max(1),
+            cooldown,
+            probes: 1,
+            successes: 1,
+            window: None,
+            hosts: Arc::default(),
+        }
+    }
+
+    /// Opens for `cooldown` once more than `rate` of the last `calls` attempts towards a host
+    /// failed, e.g. `0.5` of `100`. Hosts with fewer attempts so far never open, and hosts are
+    /// forgotten once none of their latest attempts failed.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use awc_retry::{HostBreaker, Retry};
+    ///
+    /// let breaker = HostBreaker::failure_rate(0.5, 100, Duration::from_secs(30));
+    /// let retry = Retry::new(3).host_breaker_with(breaker);
+    /// ```
+    pub fn failure_rate(rate: f64, calls: usize, cooldown: Duration) -> Self {
+        HostBreaker {
+            threshold: u32::MAX,
+            window: Some((rate, calls.max(1))),
+            ..HostBreaker::new(1, cooldown)
+        }
+    }
+
+    /// A handle to read the circuits from after handing the breaker to a [`Retry`]
+    pub fn handle(&self) -> BreakerHandle {
+        BreakerHandle(self.hosts.clone())
+    }
+
+    /// Lets `probes` attempts through at a time while half-open, closing the circuit after
+    /// `successes` of them succeeded in a row
+    pub fn half_open(mut self, probes: u32, successes: u32) -> Self {
+        self.probes = probes.max(1);
+        self.successes = successes.max(1);
+        self
+    }
+
+    /// Whether an attempt towards `host` may be sent at `now`, counting it as a probe while
+    /// the circuit is half-open
+    pub(crate) fn permit(&self, host: &str, now: Instant) -> bool {
+        let mut circuits = lock(&self.hosts);
+        circuits.clock = Some((now, Instant::now()));
+        let hosts = &mut circuits.hosts;
+        let circuit = match hosts.get_mut(host) {
+            Some(circuit) => circuit,
+            None => return true,
+        };
+
+        match circuit {
+            Circuit::Closed { .. } => true,
+            Circuit::Open { until, .. } if now < *until => false,
+            Circuit::Open { .. } => {
+                log::debug!("probing {} after its cool-down", host);
+                *circuit = Circuit::HalfOpen { in_flight: 1, successes: 0, since: now };
+                true
+            }
+            Circuit::HalfOpen { in_flight, since, .. } => {
+                if now.saturating_duration_since(*since) >= self.cooldown {
+                    *in_flight = 0;
+                    *since = now;
+                }
+                if *in_flight >= self.probes {
+                    return false;
+                }
+                *in_flight += 1;
+                true
+            }
+        }
+    }
+
+    /// Records how an attempt towards `host` went at `now`, `success` is false for errors and
+    /// retried responses
+    pub(crate) fn record(&self, host: &str, success: bool, now: Instant) {
+        let mut circuits = lock(&self.hosts);
+        circuits.clock = Some((now, Instant::now()));
+        let hosts = &mut circuits.hosts;
+        let circuit = match hosts.get_mut(host) {
+            Some(circuit) => circuit,
+            None if success => return,
+            None => {
+                if hosts.len() >= MAX_HOSTS {
+                    evict(hosts);
+                }
+                hosts.entry(host.to_owned()).or_insert(Circuit::Closed { failures: 0, recent: VecDeque::new() })
+            }
+        };
+
+        match circuit {
+            Circuit::Closed { .. } if success && self.window.is_none() => {
+                hosts.remove(host);
+            }
+            Circuit::Closed { failures, recent } => {
+                *failures = if success { 0 } else { *failures + 1 };
+                let rate = self.window.and_then(|(rate, calls)| {
+                    recent.push_back(!success);
+                    if recent.len() > calls {
+                        recent.pop_front();
+                    }
+                    let share = recent.iter().filter(|failed| **failed).count() as f64 / recent.len() as f64;
+                    Some(share).filter(|share| recent.len() >= calls && *share > rate)
+                });
+                // Nothing to open on among the latest attempts, forget the host
+                if success && !recent.contains(&true) {
+                    hosts.remove(host);
+                    return;
+                }
+
+                if let Some(share) = rate {
+                    log::warn!("opening the circuit of {} for {:?}, {:.0}% of its latest attempts failed", host, self.cooldown, share * 100.0);
+                } else if *failures >= self.threshold {
+                    log::warn!("opening the circuit of {} for {:?} after {} failures in a row", host, self.cooldown, failures);
+                } else {
+                    return;
+                }
+                *circuit = Circuit::Open { until: now + self.cooldown, failures: *failures };
+            }
+            // Attempts sent before the circuit opened
+            Circuit::Open { .. } => {}
+            Circuit::HalfOpen { in_flight, successes, .. } if success => {
+                *in_flight = in_flight.saturating_sub(1);
+                *successes += 1;
+                if *successes >= self.successes {
+                    log::debug!("closing the circuit of {} after {} successful probes", host, successes);
+                    hosts.remove(host);
+                }
+            }
+            Circuit::HalfOpen { .. } => {
+                log::warn!("opening the circuit of {} for {:?} again, a probe failed", host, self.cooldown);
+                *circuit = Circuit::Open { until: now + self.cooldown, failures: 1 };
+            }
+        }
+    }
+}
+
+impl Retry {
+    /// Stops sending requests to a host for `cooldown` once `failures` attempts in a row
+    /// towards it failed, attempts in the meantime fail with [`CircuitOpen`] and aren't retried.
+    ///
+    /// Errors and responses the policies retry count as failures, like for the failsafe
+    /// breaker, and a success resets the count. After the
+    /// cool-down one probe is let through, see [`HostBreaker`] for configuring them. Hosts are
+    /// told apart by the URI authority, so this only applies to the middleware.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use awc_retry::Retry;
+    ///
+    /// let retry = Retry::new(3).policy(500..=599).host_breaker(5, Duration::from_secs(30));
+    /// ```
+    pub fn host_breaker(self, failures: u32, cooldown: Duration) -> Self {
+        self.host_breaker_with(HostBreaker::new(failures, cooldown))
+    }
+
+    /// Guards the hosts with `breaker`, see [`host_breaker`](Retry::host_breaker)
+    pub fn host_breaker_with(mut self, breaker: HostBreaker) -> Self {
+        self.0.host_breakers = Some(breaker);
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::timer::{MockTimer, Timer};
+
+    const HOST: &str = "api.example.com";
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    fn fail(breaker: &HostBreaker, timer: &MockTimer, times: u32) {
+        for _ in 0..times {
+            assert!(breaker.permit(HOST, timer.now()));
+            breaker.record(HOST, false, timer.now());
+        }
+    }
+
+    #[test]
+    fn opens_after_failures_in_a_row() {
+        let (breaker, timer) = (HostBreaker::new(3, secs(30)), MockTimer::new());
+        fail(&breaker, &timer, 2);
+        breaker.record(HOST, true, timer.now());
+        fail(&breaker, &timer, 2);
+        assert!(breaker.permit(HOST, timer.now()));
+
+        breaker.record(HOST, false, timer.now());
+        assert!(!breaker.permit(HOST, timer.now()));
+        assert!(breaker.permit("other.example.com", timer.now()));
+    }
+
+    #[test]
+    fn probes_after_the_cooldown_and_closes_on_success() {
+        let (breaker, timer) = (HostBreaker::new(1, secs(30)), MockTimer::new());
+        fail(&breaker, &timer, 1);
+        timer.advance(secs(29));
+        assert!(!breaker.permit(HOST, timer.now()));
+
+        timer.advance(secs(1));
+        assert!(breaker.permit(HOST, timer.now()));
+        assert_eq!(breaker.handle().status(HOST).state, CircuitState::HalfOpen);
+        // Only one probe at a time
+        assert!(!breaker.permit(HOST, timer.now()));
+
+        breaker.record(HOST, true, timer.now());
+        assert_eq!(breaker.handle().status(HOST).state, CircuitState::Closed);
+        assert!(breaker.permit(HOST, timer.now()));
+    }
+
+    #[test]
+    fn failed_probe_opens_again() {
+        let (breaker, timer) = (HostBreaker::new(1, secs(30)), MockTimer::new());
+        fail(&breaker, &timer, 1);
+        timer.advance(secs(30));
+        fail(&breaker, &timer, 1);
+        assert!(!breaker.permit(HOST, timer.now()));
+        assert!(breaker.handle().status(HOST).next_probe.is_some_and(|next| next > secs(29)));
+    }
+
+    #[test]
+    fn half_open_closes_after_successful_probes_in_a_row() {
+        let (breaker, timer) = (HostBreaker::new(1, secs(30)).half_open(2, 2), MockTimer::new());
+        fail(&breaker, &timer, 1);
+        timer.advance(secs(30));
+
+        assert!(breaker.permit(HOST, timer.now()));
+        assert!(breaker.permit(HOST, timer.now()));
+        assert!(!breaker.permit(HOST, timer.now()));
+        breaker.record(HOST, true, timer.now());
+        assert_eq!(breaker.handle().status(HOST).state, CircuitState::HalfOpen);
+        breaker.record(HOST, true, timer.now());
+        assert_eq!(breaker.handle().status(HOST).state, CircuitState::Closed);
+    }
+
+    #[test]
+    fn probes_which_never_finish_are_given_up_on() {
+        let (breaker, timer) = (HostBreaker::new(1, secs(30)), MockTimer::new());
+        fail(&breaker, &timer, 1);
+        timer.advance(secs(30));
+        assert!(breaker.permit(HOST, timer.now()));
+        assert!(!breaker.permit(HOST, timer.now()));
+
+        timer.advance(secs(30));
+        assert!(breaker.permit(HOST, timer.now()));
+    }
+
+    #[test]
+    fn failure_rate_opens_once_the_window_is_full() {
+        let (breaker, timer) = (HostBreaker::failure_rate(0.5, 4, secs(30)), MockTimer::new());
+        fail(&breaker, &timer, 1);
+        breaker.record(HOST, true, timer.now());
+        fail(&breaker, &timer, 1);
+        // 2 of 3 failed, but the window isn't full yet
+        assert!(breaker.permit(HOST, timer.now()));
+
+        breaker.record(HOST, false, timer.now());
+        assert!(!breaker.permit(HOST, timer.now()));
+    }
+
+    #[test]
+    fn failure_rate_stays_closed_at_the_rate() {
+        let (breaker, timer) = (HostBreaker::failure_rate(0.5, 4, secs(30)), MockTimer::new());
+        for _ in 0..4 {
+            fail(&breaker, &timer, 1);
+            breaker.record(HOST, true, timer.now());
+        }
+        assert!(breaker.permit(HOST, timer.now()));
+        assert_eq!(breaker.handle().status(HOST).state, CircuitState::Closed);
+    }
+
+    #[test]
+    fn failure_rate_forgets_hosts_without_recent_failures() {
+        let (breaker, timer) = (HostBreaker::failure_rate(0.5, 2, secs(30)), MockTimer::new());
+        breaker.record(HOST, true, timer.now());
+        assert!(breaker.handle().hosts().is_empty());
+
+        fail(&breaker, &timer, 1);
+        assert_eq!(breaker.handle().hosts().len(), 1);
+        breaker.record(HOST, true, timer.now());
+        breaker.record(HOST, true, timer.now());
+        assert!(breaker.handle().hosts().is_empty());
+    }
+
+    #[test]
+    fn failure_rate_caps_the_hosts() {
+        let (breaker, timer) = (HostBreaker::failure_rate(0.5, 10, secs(30)), MockTimer::new());
+        for i in 0..MAX_HOSTS + 10 {
+            breaker.record(&format!("{}.example.com", i), false, timer.now());
+        }
+        assert_eq!(breaker.handle().hosts().len(), MAX_HOSTS);
+    }
+
+    #[test]
+    fn handle_follows_the_timer_from_another_thread() {
+        let (breaker, timer) = (HostBreaker::new(1, secs(30)), MockTimer::new());
+        fail(&breaker, &timer, 1);
+        timer.advance(secs(20));
+        // The breaker hears of the time with the next attempt
+        assert!(!breaker.permit(HOST, timer.now()));
+
+        let handle = breaker.handle();
+        let status = std::thread::spawn(move || handle.status(HOST)).join().unwrap();
+        assert_eq!(status.state, CircuitState::Open);
+        let next_probe = status.next_probe.unwrap();
+        assert!(next_probe <= secs(10) && next_probe > secs(9));
+    }
+}
@@ -0,0 +1,254 @@
+//! Capping the extra load retries add, with tokens earned by successes and spent by retries or
+//! with a most retries per time window.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::Retry;
+
+/// A token bucket of retries, see [`Retry::budget`].
+///
+/// Every successful attempt deposits `ratio` tokens and every retry withdraws one, so retries
+/// add at most about `ratio` times the successful traffic. The bucket holds up to `capacity`
+/// tokens and starts full, which lets a client retry a few failures before it saw successes.
+///
+/// Clones share their tokens and can be sent to other threads, so the clients of a whole
+/// process can draw from one budget.
+///
+/// ```
+/// use awc_retry::{Budget, Retry};
+///
+/// // Retries add at most 20% to the load, with a reserve of 50 retries
+/// let budget = Budget::new(0.2).capacity(50);
+///
+/// let api = Retry::new(3).budget(budget.clone());
+/// let storage = Retry::new(5).budget(budget.clone());
+/// println!("{} retries left", budget.remaining());
+/// ```
+#[derive(Clone)]
+pub struct Budget(Arc<Mutex<Bucket>>);
+
+struct Bucket {
+    ratio: f64,
+    capacity: f64,
+    balance: f64,
+}
+
+impl Budget {
+    /// A budget of `ratio` retries per successful attempt and a capacity of 10 retries
+    pub fn new(ratio: f64) -> Self {
+        Budget(Arc::new(Mutex::new(Bucket {
+            ratio: ratio.max(0.0),
+            capacity: 10.0,
+            balance: 10.0,
+        })))
+    }
+
+    /// Sets the most tokens the bucket holds and fills it up
+    pub fn capacity(self, capacity: u32) -> Self {
+        {
+            let mut bucket = self.bucket();
+            bucket.capacity = f64::from(capacity);
+            bucket.balance = bucket.capacity;
+        }
+        self
+    }
+
+    /// Retries the budget can still pay for
+    pub fn remaining(&self) -> u32 {
+        self.bucket().balance as u32
+    }
+
+    /// Earns the tokens of a successful attempt
+    pub(crate) fn deposit(&self) {
+        let mut bucket = self.bucket();
+        bucket.balance = (bucket.balance + bucket.ratio).min(bucket.capacity);
+    }
+
+    /// Spends the token of a retry, false if the budget is used up
+    pub(crate) fn withdraw(&self) -> bool {
+        let mut bucket = self.bucket();
+        if bucket.balance < 1.0 {
+            return false;
+        }
+        bucket.balance -= 1.0;
+        true
+    }
+
+    /// Gives back the token of a retry which wasn't made after all
+    pub(crate) fn refund(&self) {
+        let mut bucket = self.bucket();
+        bucket.balance = (bucket.balance + 1.0).min(bucket.capacity);
+    }
+
+    fn bucket(&self) -> MutexGuard<'_, Bucket> {
+        // A panic while holding the lock can't leave the numbers inconsistent
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// A most retries per time window, see [`Retry::retry_rate`].
+///
+/// The window slides: a retry is allowed while fewer than `max` retries were made in the
+/// `window` before it. Clones share the window like clones of a [`Budget`] share its tokens.
+///
+/// ```
+/// use std::time::Duration;
+/// use awc_retry::{Retry, RetryRate};
+///
+/// let retry = Retry::new(3).retry_rate(RetryRate::new(20, Duration::from_secs(1)));
+/// ```
+#[derive(Clone)]
+pub struct RetryRate {
+    max: usize,
+    window: Duration,
+    /// When the retries of the last window were made, oldest first
+    retries: Arc<Mutex<VecDeque<Instant>>>,
+}
+
+impl RetryRate {
+    pub fn new(max: u32, window: Duration) -> Self {
+        RetryRate {
+            max: max as usize,
+            window,
+            retries: Arc::new(Mutex::new(VecDeque::new())),
+        }
+    }
+
+    /// Counts a retry at `now`, false if it would exceed the rate
+    pub(crate) fn try_record(&self, now: Instant) -> bool {
+        let mut retries = self.retries();
+        while retries.front().is_some_and(|t| now.saturating_duration_since(*t) >= self.window) {
+            retries.pop_front();
+        }
+        if retries.len() >= self.max {
+            return false;
+        }
+        retries.push_back(now);
+        true
+    }
+
+    fn retries(&self) -> MutexGuard<'_, VecDeque<Instant>> {
+        self.retries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Retry {
+    /// Only retries while `budget` has tokens left, so retries can't multiply the traffic to
+    /// a failing upstream.
+    ///
+    /// Requests whose retry doesn't fit the budget get the outcome of their last attempt, right
+    /// away if it's used up already. Tokens are only spent on retries which are sent. Hand clones of one budget to several configurations to share it between clients.
+    pub fn budget(mut self, budget: Budget) -> Self {
+        self.0.budget = Some(budget);
+        self
+    }
+
+    /// Makes at most as many retries as `rate` allows, whatever their requests, so a burst of
+    /// failures can't multiply the traffic to a degraded upstream.
+    ///
+    /// Requests whose retry doesn't fit the rate once its backoff passed get the outcome of their
+    /// last attempt. Retries are counted when they're sent, by the [`timer`](Retry::timer).
+    pub fn retry_rate(mut self, rate: RetryRate) -> Self {
+        self.0.retry_rate = Some(rate);
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::timer::{MockTimer, Timer};
+    use crate::{retry_fn, Replayable};
+    use actix_service::{fn_service, Service};
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    #[test]
+    fn budget_starts_full_and_earns_fractions() {
+        let budget = Budget::new(0.5).capacity(2);
+        assert!(budget.withdraw());
+        assert!(budget.withdraw());
+        assert!(!budget.withdraw());
+
+        budget.deposit();
+        assert_eq!(budget.remaining(), 0);
+        budget.deposit();
+        assert_eq!(budget.remaining(), 1);
+        for _ in 0..10 {
+            budget.deposit();
+        }
+        assert_eq!(budget.remaining(), 2);
+    }
+
+    #[test]
+    fn refunds_stay_within_the_capacity() {
+        let budget = Budget::new(0.1).capacity(1);
+        budget.refund();
+        assert_eq!(budget.remaining(), 1);
+        assert!(budget.withdraw());
+        budget.refund();
+        assert_eq!(budget.remaining(), 1);
+    }
+
+    #[test]
+    fn retry_rate_window_slides() {
+        let (rate, timer) = (RetryRate::new(2, Duration::from_secs(10)), MockTimer::new());
+        assert!(rate.try_record(timer.now()));
+        timer.advance(Duration::from_secs(5));
+        assert!(rate.try_record(timer.now()));
+        assert!(!rate.try_record(timer.now()));
+
+        // The first retry left the window
+        timer.advance(Duration::from_secs(5));
+        assert!(rate.try_record(timer.now()));
+        assert!(!rate.try_record(timer.now()));
+    }
+
+    #[derive(Clone)]
+    struct Ping;
+
+    impl Replayable for Ping {
+        fn replay(&self) -> Self {
+            Ping
+        }
+    }
+
+    #[test]
+    fn retries_are_made_within_the_rate() {
+        let timer = MockTimer::new();
+        let calls = Rc::new(Cell::new(0));
+        let retry = Retry::new(3).retry_rate(RetryRate::new(1, Duration::from_secs(10))).timer(timer.clone());
+        let counted = calls.clone();
+        let service = retry_fn(fn_service(move |_: Ping| {
+            counted.set(counted.get() + 1);
+            async { Err::<(), _>("down") }
+        }), retry);
+
+        let system = actix_rt::System::new();
+        assert_eq!(system.block_on(service.call(Ping)), Err("down"));
+        assert_eq!(calls.get(), 2);
+        assert_eq!(system.block_on(service.call(Ping)), Err("down"));
+        assert_eq!(calls.get(), 3);
+
+        timer.advance(Duration::from_secs(10));
+        assert_eq!(system.block_on(service.call(Ping)), Err("down"));
+        assert_eq!(calls.get(), 5);
+    }
+
+    #[test]
+    fn retries_draw_from_the_budget() {
+        let budget = Budget::new(0.0).capacity(2);
+        let calls = Rc::new(Cell::new(0));
+        let counted = calls.clone();
+        let service = retry_fn(fn_service(move |_: Ping| {
+            counted.set(counted.get() + 1);
+            async { Err::<(), _>("down") }
+        }), Retry::new(3).budget(budget.clone()).timer(MockTimer::new()));
+
+        assert_eq!(actix_rt::System::new().block_on(service.call(Ping)), Err("down"));
+        assert_eq!(calls.get(), 3);
+        assert_eq!(budget.remaining(), 0);
+    }
+}
@@ -0,0 +1,89 @@
+//! Fallback credentials tried in order when a request is refused as unauthorized.
+
+use actix_http::http::{header, HeaderValue, StatusCode};
+use actix_http::RequestHeadType;
+use awc::ClientResponse;
+use futures::future::{FutureExt, LocalBoxFuture};
+use std::future::Future;
+
+use crate::Retry;
+
+#[derive(Default)]
+pub(crate) struct Credentials(Vec<Provider>);
+
+enum Provider {
+    Value(HeaderValue),
+    Source(Box<SourceFn>),
+}
+
+type SourceFn = dyn Fn() -> LocalBoxFuture<'static, Option<HeaderValue>>;
+
+impl Credentials {
+    /// The first credential after the `used` ones which were tried, skipping sources which had
+    /// nothing to give
+    pub(crate) async fn next(&self, used: &mut usize) -> Option<HeaderValue> {
+        while let Some(provider) = self.0.get(*used) {
+            *used += 1;
+            let value = match provider {
+                Provider::Value(value) => Some(value.clone()),
+                Provider::Source(source) => source().await,
+            };
+            if value.is_some() {
+                return value;
+            }
+        }
+
+        None
+    }
+
+    /// Whether `res` is an auth failure a different credential could get past
+    pub(crate) fn refused(res: &ClientResponse) -> bool {
+        matches!(res.status(), StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN)
+    }
+}
+
+/// Replaces the `Authorization` header of `head`
+pub(crate) fn authorize(head: &mut RequestHeadType, value: HeaderValue) {
+    crate::head::insert_header(head, header::AUTHORIZATION, value);
+}
+
+impl Retry {
+    /// Sends requests refused with `401 Unauthorized` or `403 Forbidden` again with these
+    /// `Authorization` values, in order, e.g. the new key while a key is being rotated.
+    ///
+    /// Switching credentials is a retry made straight away, within the same limits, request
+    /// policies, vetoes, budgets and bandwidth guard as other retries, and the last refusal goes
+    /// through the policies like any other response. Refusals don't count against the health
+    /// of the host. Streamed bodies are only ever sent once. Calling this again, or [`credential_source`](Retry::credential_source), adds to
+    /// the list.
+    ///
+    /// ```
+    /// use actix_http::http::HeaderValue;
+    /// use awc_retry::Retry;
+    ///
+    /// let retry = Retry::new(3).fallback_credentials(vec![
+    ///     HeaderValue::from_static("Bearer next-key"),
+    ///     HeaderValue::from_static("Bearer previous-key"),
+    /// ]);
+    /// ```
+    pub fn fallback_credentials<I>(mut self, values: I) -> Self
+        where
+            I: IntoIterator<Item=HeaderValue>,
+    {
+        self.0.credentials.0.extend(values.into_iter().map(Provider::Value));
+        self
+    }
+
+    /// Adds a fallback credential which is fetched when it's needed, such as a freshly issued
+    /// token, see [`fallback_credentials`](Retry::fallback_credentials).
+    ///
+    /// A source resolving to `None` is skipped over for the next credential.
+    pub fn credential_source<F, Fut>(mut self, source: F) -> Self
+        where
+            F: Fn() -> Fut + 'static,
+            Fut: Future<Output=Option<HeaderValue>> + 'static,
+    {
+        self.0.credentials.0.push(Provider::Source(Box::new(move || source().boxed_local())));
+        self
+    }
+}
@@ -0,0 +1,57 @@
+//! Falling back to HTTP/1.1 when HTTP/2 keeps failing.
+
+use actix_http::http::Version;
+use actix_http::RequestHeadType;
+use actix_service::Service;
+use awc::error::{ConnectError, SendRequestError};
+use awc::{ConnectRequest, ConnectResponse, ConnectorService};
+
+use crate::{policy, Retry};
+
+pub(crate) struct Downgrade {
+    /// Only speaks HTTP/1.1
+    pub(crate) connector: ConnectorService,
+    /// HTTP/2 failures in a row after which a request moves to `connector`
+    after: u32,
+}
+
+impl Downgrade {
+    /// Whether a request which failed `failures` times in a row over HTTP/2 moves to HTTP/1.1
+    pub(crate) fn applies(&self, failures: u32) -> bool {
+        failures >= self.after
+    }
+}
+
+/// Whether `err` is a failure of HTTP/2 itself, streams refused unprocessed are part of normal
+/// operation and don't count
+pub(crate) fn is_h2_failure(err: &SendRequestError) -> bool {
+    matches!(err, SendRequestError::H2(_) | SendRequestError::Connect(ConnectError::H2(_)))
+        && !policy::is_refused_stream(err)
+}
+
+/// Marks an owned head as HTTP/1.1, shared heads can't be changed and are left to the connector
+pub(crate) fn adjust(head: &mut RequestHeadType) {
+    if let RequestHeadType::Owned(head) = head {
+        head.version = Version::HTTP_11;
+    }
+}
+
+impl Retry {
+    /// Sends the remaining attempts of a request through `connector` once `after` attempts in a
+    /// row failed with HTTP/2 errors, like browsers falling back from a broken HTTP/2 server.
+    ///
+    /// `connector` should only offer HTTP/1.1, such as one wrapping an [`awc::Connector`] built
+    /// with `max_http_version(Version::HTTP_11)`. Attempts through it are sent as HTTP/1.1 and the
+    /// request stays on it for all of its remaining retries, ahead of any alternate
+    /// [`connector`](Retry::connector).
+    pub fn http1_fallback<S>(mut self, connector: S, after: u32) -> Self
+        where
+            S: Service<ConnectRequest, Response=ConnectResponse, Error=SendRequestError> + 'static,
+    {
+        self.0.downgrade = Some(Downgrade {
+            connector: actix_service::boxed::service(connector),
+            after: after.max(1),
+        });
+        self
+    }
+}
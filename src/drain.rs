@@ -0,0 +1,91 @@
+//! Stopping retries so a service can shut down without sitting out long backoffs.
+
+use futures::channel::oneshot;
+use futures::future::{FutureExt, Shared};
+use std::cell::{Cell, RefCell};
+use std::time::Duration;
+
+use crate::SharedRetry;
+
+/// How often [`SharedRetry::drain`] checks whether the requests in flight finished
+const DRAIN_POLL: Duration = Duration::from_millis(20);
+
+pub(crate) struct Drain {
+    /// Requests which started and haven't returned yet
+    in_flight: Cell<usize>,
+    /// Taken once draining starts, which resolves `stopped`
+    stop: RefCell<Option<oneshot::Sender<()>>>,
+    stopped: Shared<oneshot::Receiver<()>>,
+}
+
+impl Default for Drain {
+    fn default() -> Self {
+        let (stop, stopped) = oneshot::channel();
+        Drain {
+            in_flight: Cell::new(0),
+            stop: RefCell::new(Some(stop)),
+            stopped: stopped.shared(),
+        }
+    }
+}
+
+impl Drain {
+    pub(crate) fn is_draining(&self) -> bool {
+        self.stop.borrow().is_none()
+    }
+
+    /// Resolves once draining starts, for cutting backoffs short
+    pub(crate) fn stopped(&self) -> Shared<oneshot::Receiver<()>> {
+        self.stopped.clone()
+    }
+
+    pub(crate) fn enter(&self) {
+        self.in_flight.set(self.in_flight.get() + 1);
+    }
+
+    pub(crate) fn leave(&self) {
+        self.in_flight.set(self.in_flight.get().saturating_sub(1));
+    }
+}
+
+impl SharedRetry {
+    /// Stops retrying, for shutting down cleanly, resolving to whether every request finished
+    /// within `grace`.
+    ///
+    /// Attempts already sent are left to finish and their outcome is returned as it is rather
+    /// than retried, requests waiting out a backoff return the outcome they were going to retry
+    /// straight away. Requests sent after draining started still get their first attempt.
+    /// Draining can't be undone.
+    ///
+    /// ```no_run
+    /// use std::time::Duration;
+    /// use awc_retry::Retry;
+    ///
+    /// # async fn shutdown() {
+    /// let retry = Retry::new(3).shared();
+    /// let client = awc::Client::builder().wrap(retry.clone()).finish();
+    /// // ...
+    /// if !retry.drain(Duration::from_secs(10)).await {
+    ///     log::warn!("requests were still in flight at shutdown");
+    /// }
+    /// # }
+    /// ```
+    pub async fn drain(&self, grace: Duration) -> bool {
+        let drain = &self.0.drain;
+        if let Some(stop) = drain.stop.borrow_mut().take() {
+            let _ = stop.send(());
+        }
+
+        let timer = &self.0.timer;
+        let deadline = timer.now() + grace;
+        while drain.in_flight.get() > 0 {
+            let now = timer.now();
+            if now >= deadline {
+                return false;
+            }
+            timer.sleep(DRAIN_POLL.min(deadline - now)).await;
+        }
+
+        true
+    }
+}
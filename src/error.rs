@@ -0,0 +1,98 @@
+use actix_http::error::PayloadError;
+use actix_http::http::{Method, StatusCode};
+use awc::error::SendRequestError;
+use std::fmt;
+
+/// A [`Retry`](crate::Retry) configuration which can't behave sensibly, see [`Retry::validate`](crate::Retry::validate)
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ConfigError {
+    /// A status policy was given an empty list or range, so it never matches
+    EmptyStatusList,
+    /// A content type policy was given an empty list, so it retries every response with a body
+    EmptyContentTypeList,
+    /// An error code policy was given an empty list, so it never matches
+    EmptyErrorCodeList,
+    /// A method policy was given an empty list, so it never matches
+    EmptyMethodList,
+    /// A host policy was given an empty list, so it never matches
+    EmptyHostList,
+    /// Alternate connectors were added but retries are disabled, so they are never used
+    ConnectorsWithoutRetries,
+    /// Shadowing was enabled but retries are disabled, so nothing is ever mirrored
+    ShadowWithoutRetries,
+    /// Failover addresses were set but retries are disabled, so they are never used
+    FailoverWithoutRetries,
+    /// A policy reads response bodies but [`Retry::streaming_safe`](crate::Retry::streaming_safe) is enabled
+    BufferingPolicy,
+    /// A status passed to [`Retry::retry_redirects`](crate::Retry::retry_redirects) isn't a `3xx`
+    NotARedirect(StatusCode),
+    /// A [`jitter`](crate::Retry::jitter) was set without a backoff, so there are no delays to randomize
+    JitterWithoutBackoff,
+    /// A method policy retries a method such as `POST` without requiring an idempotency key,
+    /// see [`Retry::require_idempotency_key`](crate::Retry::require_idempotency_key)
+    UnkeyedMethod(Method),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::EmptyStatusList => write!(f, "status policy has no status codes"),
+            ConfigError::EmptyContentTypeList => write!(f, "content type policy has no content types"),
+            ConfigError::EmptyErrorCodeList => write!(f, "error code policy has no error codes"),
+            ConfigError::EmptyMethodList => write!(f, "method policy has no methods"),
+            ConfigError::EmptyHostList => write!(f, "host policy has no hosts"),
+            ConfigError::ConnectorsWithoutRetries => {
+                write!(f, "alternate connectors are only used by retries but max_retries is 0")
+            }
+            ConfigError::ShadowWithoutRetries => {
+                write!(f, "only retried requests are shadowed but max_retries is 0")
+            }
+            ConfigError::FailoverWithoutRetries => {
+                write!(f, "failover addresses are only used by retries but max_retries is 0")
+            }
+            ConfigError::BufferingPolicy => {
+                write!(f, "a policy buffers response bodies but streaming safe mode is enabled")
+            }
+            ConfigError::NotARedirect(status) => write!(f, "{} is not a redirect status", status),
+            ConfigError::JitterWithoutBackoff => write!(f, "jitter is set but there is no backoff to apply it to"),
+            ConfigError::UnkeyedMethod(method) => {
+                write!(f, "{} requests are retried without requiring an idempotency key", method)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Why [`Retry::send_and_body`](crate::Retry::send_and_body) got no body
+#[derive(Debug)]
+pub enum SendBodyError {
+    /// The last attempt failed before a response
+    Send(SendRequestError),
+    /// Reading the body of the last response failed
+    Payload(PayloadError),
+}
+
+impl fmt::Display for SendBodyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendBodyError::Send(e) => write!(f, "{}", e),
+            SendBodyError::Payload(e) => write!(f, "reading the body failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for SendBodyError {}
+
+impl From<SendRequestError> for SendBodyError {
+    fn from(e: SendRequestError) -> Self {
+        SendBodyError::Send(e)
+    }
+}
+
+impl From<PayloadError> for SendBodyError {
+    fn from(e: PayloadError) -> Self {
+        SendBodyError::Payload(e)
+    }
+}
@@ -0,0 +1,95 @@
+//! Per request notifications of finished attempts.
+
+use actix_http::http::StatusCode;
+use futures::channel::mpsc::{UnboundedReceiver, UnboundedSender};
+use futures::task::{Context, Poll};
+use futures::Stream;
+use std::pin::Pin;
+use std::time::Duration;
+
+use crate::attempt::Outcome;
+
+/// A finished attempt of a request sent with [`Retry::send_observed`](crate::Retry::send_observed)
+#[derive(Debug, Clone)]
+pub struct AttemptEvent {
+    pub(crate) attempt: u32,
+    pub(crate) status: Option<StatusCode>,
+    pub(crate) error: Option<String>,
+    pub(crate) timings: Timings,
+    pub(crate) retry_in: Option<Duration>,
+}
+
+/// How long the phases of an attempt took.
+///
+/// Resolving, connecting and the TLS handshake happen inside awc's connector, whose pooled
+/// connections often skip them, so they are only seen as part of [`to_head`](Timings::to_head).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Timings {
+    pub(crate) to_head: Duration,
+    pub(crate) checks: Duration,
+}
+
+impl Timings {
+    /// From sending the attempt until its response head arrived or it failed, including
+    /// acquiring a connection
+    pub fn to_head(&self) -> Duration {
+        self.to_head
+    }
+
+    /// Time spent by the policies, which includes reading the body for the policies which
+    /// inspect it
+    pub fn checks(&self) -> Duration {
+        self.checks
+    }
+}
+
+impl AttemptEvent {
+    pub(crate) fn new(attempt: u32, outcome: &Outcome, timings: Timings, retry_in: Option<Duration>) -> Self {
+        let (status, error) = match outcome {
+            Outcome::Response(res) => (Some(res.status()), None),
+            Outcome::Error(e) => (None, Some(e.to_string())),
+        };
+
+        AttemptEvent { attempt, status, error, timings, retry_in }
+    }
+
+    /// The attempt which finished, the first attempt is `1`
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    /// Status of the response, `None` when the attempt failed with an error
+    pub fn status(&self) -> Option<StatusCode> {
+        self.status
+    }
+
+    /// The error the attempt failed with
+    pub fn error(&self) -> Option<&str> {
+        self.error.as_deref()
+    }
+
+    /// How long the attempt took, split into phases
+    pub fn timings(&self) -> Timings {
+        self.timings
+    }
+
+    /// How long until the next attempt, `None` when this attempt's outcome is returned.
+    ///
+    /// A retry can still be vetoed after its delay, the stream then ends without another event.
+    pub fn retry_in(&self) -> Option<Duration> {
+        self.retry_in
+    }
+}
+
+/// The [`AttemptEvent`]s of one request, ending once the request finished
+pub struct AttemptEvents(pub(crate) UnboundedReceiver<AttemptEvent>);
+
+impl Stream for AttemptEvents {
+    type Item = AttemptEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<AttemptEvent>> {
+        Pin::new(&mut self.0).poll_next(cx)
+    }
+}
+
+pub(crate) type EventSender = UnboundedSender<AttemptEvent>;
@@ -0,0 +1,237 @@
+//! Addresses retries are sent to in place of the one the request resolved to.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use crate::breaker::MAX_HOSTS;
+use crate::Retry;
+
+pub(crate) enum Failover {
+    /// A fixed list, rotated through by consecutive retries
+    Static(Vec<SocketAddr>),
+    /// The targets of a DNS SRV record
+    #[cfg(feature = "srv")]
+    Srv(Box<srv::SrvTargets>),
+}
+
+impl Failover {
+    /// The address of the `retry`th retry, counting from 0, `None` leaves it to the connector.
+    ///
+    /// Addresses `outliers` ejected at `now` are skipped, unless all of them are.
+    pub(crate) async fn addr(&self, retry: u8, outliers: Option<&Outliers>, now: Instant) -> Option<SocketAddr> {
+        let pick = |addrs: &[SocketAddr]| {
+            let healthy: Vec<_> = addrs.iter().filter(|addr| outliers.is_none_or(|o| !o.ejected(addr, now))).collect();
+            match healthy.len() {
+                0 if addrs.is_empty() => None,
+                0 => Some(addrs[usize::from(retry) % addrs.len()]),
+                len => Some(*healthy[usize::from(retry) % len]),
+            }
+        };
+
+        match self {
+            Failover::Static(addrs) => pick(addrs),
+            #[cfg(feature = "srv")]
+            Failover::Srv(srv) => pick(&srv.addrs().await),
+        }
+    }
+}
+
+/// Failures of each address attempts connected to, see [`Retry::outlier_ejection`]
+pub(crate) struct Outliers {
+    /// Failures in a row which eject an address
+    threshold: u32,
+    duration: Duration,
+    /// Failures in a row of each address, and until when it's ejected, for up to [`MAX_HOSTS`]
+    /// addresses beyond which those not ejected, then those ejected for the least time, are
+    /// forgotten
+    addrs: RefCell<HashMap<SocketAddr, (u32, Option<Instant>)>>,
+}
+
+impl Outliers {
+    fn ejected(&self, addr: &SocketAddr, now: Instant) -> bool {
+        self.addrs.borrow().get(addr).and_then(|(_, until)| *until).is_some_and(|until| now < until)
+    }
+
+    /// Records how an attempt connecting to `addr` went at `now`, `success` is false for errors
+    /// and retried responses
+    pub(crate) fn record(&self, addr: SocketAddr, success: bool, now: Instant) {
+        let mut addrs = self.addrs.borrow_mut();
+        if success {
+            addrs.remove(&addr);
+            return;
+        }
+
+        if addrs.len() >= MAX_HOSTS && !addrs.contains_key(&addr) {
+            let healthiest = addrs.iter().min_by_key(|(_, (failures, until))| (*until, *failures)).map(|(addr, _)| *addr);
+            if let Some(healthiest) = healthiest {
+                addrs.remove(&healthiest);
+            }
+        }
+        let (failures, until) = addrs.entry(addr).or_insert((0, None));
+        *failures += 1;
+        if *failures >= self.threshold && until.is_none_or(|until| now >= until) {
+            log::warn!("ejecting {} for {:?} after {} failures in a row", addr, self.duration, failures);
+            *until = Some(now + self.duration);
+            *failures = 0;
+        }
+    }
+}
+
+impl Retry {
+    /// Sends retries to `addrs` in turn instead of the address the request resolved to.
+    ///
+    /// Only the connection target changes, the URI and `Host` header of the request are kept,
+    /// so the addresses should serve the same host. The first attempt goes where it was sent.
+    ///
+    /// ```
+    /// use awc_retry::Retry;
+    ///
+    /// let retry = Retry::new(2).failover_addrs(vec![
+    ///     "10.0.0.2:8080".parse().unwrap(),
+    ///     "10.0.0.3:8080".parse().unwrap(),
+    /// ]);
+    /// ```
+    pub fn failover_addrs(mut self, addrs: Vec<SocketAddr>) -> Self {
+        self.0.failover = Some(Failover::Static(addrs));
+        self
+    }
+
+    /// Stops sending retries to a failover address for `duration` once `failures` attempts in
+    /// a row connecting to it failed, so retries land on the healthy ones.
+    ///
+    /// Errors and responses the policies retry count as failures, a success resets the count.
+    /// When every address is ejected they are all used as if none were. Applies to
+    /// [`failover_addrs`](Retry::failover_addrs) and SRV failover.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use awc_retry::Retry;
+    ///
+    /// let retry = Retry::new(2)
+    ///     .failover_addrs(vec!["10.0.0.2:8080".parse().unwrap(), "10.0.0.3:8080".parse().unwrap()])
+    ///     .outlier_ejection(3, Duration::from_secs(30));
+    /// ```
+    pub fn outlier_ejection(mut self, failures: u32, duration: Duration) -> Self {
+        self.0.outliers = Some(Outliers {
+            threshold: failures.max(1),
+            duration,
+            addrs: RefCell::new(HashMap::new()),
+        });
+        self
+    }
+
+    /// Sends retries to the targets of the SRV record `name`, e.g. `_http._tcp.api.internal`,
+    /// like [`failover_addrs`](Retry::failover_addrs) does with a fixed list.
+    ///
+    /// Targets are ordered by priority, then by descending weight. The record is resolved with
+    /// the system's DNS configuration when it's first needed and again once its TTL or the TTL
+    /// of a target's address expires. A failed lookup keeps the previous targets for a few
+    /// seconds, while there are none retries go to the address the request resolved to.
+    /// Enabled by the `srv` feature.
+    #[cfg(feature = "srv")]
+    pub fn srv_failover<N: Into<String>>(mut self, name: N) -> Self {
+        self.0.failover = Some(Failover::Srv(Box::new(srv::SrvTargets::new(name.into()))));
+        self
+    }
+}
+
+#[cfg(feature = "srv")]
+mod srv {
+    use std::cell::RefCell;
+    use std::net::SocketAddr;
+    use std::time::{Duration, Instant};
+    use trust_dns_resolver::error::ResolveError;
+    use trust_dns_resolver::TokioAsyncResolver;
+
+    /// How long targets are used again after a lookup failed, before the next lookup
+    const FAILED_LOOKUP_TTL: Duration = Duration::from_secs(5);
+
+    pub(crate) struct SrvTargets {
+        name: String,
+        resolver: RefCell<Option<TokioAsyncResolver>>,
+        /// The last resolved addresses and when they expire
+        cache: RefCell<(Vec<SocketAddr>, Option<Instant>)>,
+    }
+
+    impl SrvTargets {
+        pub(crate) fn new(name: String) -> Self {
+            SrvTargets {
+                name,
+                resolver: RefCell::new(None),
+                cache: RefCell::new((Vec::new(), None)),
+            }
+        }
+
+        /// The current targets, resolving the record again when they expired
+        pub(crate) async fn addrs(&self) -> Vec<SocketAddr> {
+            {
+                let cache = self.cache.borrow();
+                if cache.1.is_some_and(|expiry| Instant::now() < expiry) {
+                    return cache.0.clone();
+                }
+            }
+
+            let (addrs, expiry) = match self.resolve().await {
+                Ok(resolved) => resolved,
+                Err(e) => {
+                    log::warn!("SRV lookup of {} failed: {}", self.name, e);
+                    let addrs = self.cache.borrow().0.clone();
+                    (addrs, Instant::now() + FAILED_LOOKUP_TTL)
+                }
+            };
+
+            *self.cache.borrow_mut() = (addrs.clone(), Some(expiry));
+            addrs
+        }
+
+        async fn resolve(&self) -> Result<(Vec<SocketAddr>, Instant), ResolveError> {
+            let resolver = match self.resolver.borrow().clone() {
+                Some(resolver) => resolver,
+                None => TokioAsyncResolver::tokio_from_system_conf()?,
+            };
+            *self.resolver.borrow_mut() = Some(resolver.clone());
+
+            let srv = resolver.srv_lookup(self.name.as_str()).await?;
+            let mut expiry = srv.as_lookup().valid_until();
+
+            let mut records: Vec<_> = srv.iter().collect();
+            records.sort_by_key(|r| (r.priority(), std::cmp::Reverse(r.weight())));
+
+            let mut addrs = Vec::new();
+            for record in records {
+                let ips = match resolver.lookup_ip(record.target().clone()).await {
+                    Ok(ips) => ips,
+                    Err(e) => {
+                        log::debug!("skipping SRV target {} of {}: {}", record.target(), self.name, e);
+                        continue;
+                    }
+                };
+                expiry = expiry.min(ips.valid_until());
+                addrs.extend(ips.iter().map(|ip| SocketAddr::new(ip, record.port())));
+            }
+
+            Ok((addrs, expiry))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    #[test]
+    fn ejections_outlast_the_cap_on_addresses() {
+        let outliers = Outliers { threshold: 1, duration: Duration::from_secs(30), addrs: RefCell::new(HashMap::new()) };
+        let (earlier, now) = (Instant::now(), Instant::now() + Duration::from_secs(60));
+        let ejected = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 1);
+        outliers.record(ejected, false, now);
+        for port in 2..MAX_HOSTS as u16 + 10 {
+            outliers.record(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port), false, earlier);
+        }
+        assert_eq!(outliers.addrs.borrow().len(), MAX_HOSTS);
+        assert!(outliers.ejected(&ejected, now));
+    }
+}
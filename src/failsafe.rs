@@ -0,0 +1,59 @@
+//! Interop with the [`failsafe`](::failsafe) crate, enabled by the `failsafe` feature.
+//!
+//! Its circuit breakers and backoff strategies can be handed to [`Retry`] in place of the
+//! equivalent options of this crate.
+
+use ::failsafe::{FailurePolicy, Instrument, StateMachine};
+use std::time::Duration;
+
+use crate::breaker::Breaker;
+use crate::Retry;
+
+impl<P: FailurePolicy, I: Instrument> Breaker for StateMachine<P, I> {
+    fn permit(&self) -> bool {
+        self.is_call_permitted()
+    }
+
+    fn record(&self, success: bool) {
+        if success {
+            self.on_success()
+        } else {
+            self.on_error()
+        }
+    }
+}
+
+impl Retry {
+    /// Guards every attempt with a failsafe circuit breaker.
+    ///
+    /// Attempts the breaker doesn't permit fail with [`CircuitOpen`](crate::CircuitOpen) and
+    /// aren't retried. Errors and responses the policies retry are recorded as failures,
+    /// everything else as a success. Clones of the state machine share their state, so one
+    /// breaker can guard several clients.
+    ///
+    /// ```
+    /// use awc_retry::Retry;
+    ///
+    /// let breaker = failsafe::Config::new().build();
+    /// let retry = Retry::new(3).failsafe_breaker(breaker);
+    /// ```
+    pub fn failsafe_breaker<P, I>(mut self, breaker: StateMachine<P, I>) -> Self
+        where
+            P: FailurePolicy + 'static,
+            I: Instrument + 'static,
+    {
+        self.0.breaker = Some(Box::new(breaker));
+        self
+    }
+
+    /// Waits between attempts according to a failsafe backoff strategy, e.g.
+    /// [`failsafe::backoff::equal_jittered`](::failsafe::backoff::equal_jittered).
+    ///
+    /// Failsafe strategies are plain iterators of delays, so this is the same as
+    /// [`Retry::backoff`].
+    pub fn failsafe_backoff<B>(self, backoff: B) -> Self
+        where B: Iterator<Item=Duration> + Clone + 'static
+    {
+        self.backoff(backoff)
+    }
+}
@@ -0,0 +1,158 @@
+//! Duplication of request heads, for middlewares which send a request more than once.
+//!
+//! A [`RequestHeadType`] can't be cloned directly because the extensions of a [`RequestHead`]
+//! are type-erased. [`clone_request_head_type`] copies everything else, [`HeadCloner`] also
+//! copies the extension types registered with it.
+//!
+//! Only owned heads are copied. A shared head (`RequestHeadType::Rc`) is cloned by sharing it,
+//! so every attempt sees the same extensions, registered or not, including those inserted by
+//! an earlier attempt. The requests the middleware makes on the side of a request, such as
+//! connection warm-ups, probes, resumed downloads and shadow requests, go out with copies of
+//! the head carrying no extensions at all.
+
+use actix_http::http::header::HeaderName;
+use actix_http::http::{HeaderMap, HeaderValue};
+use actix_http::{Extensions, RequestHeadType};
+use actix_web::dev::RequestHead;
+use std::borrow::Cow;
+
+/// Clones [RequestHeadType] except for the extensions of an owned head.
+///
+/// Shared heads (`RequestHeadType::Rc`) only have their reference count bumped, so they keep
+/// sharing their extensions.
+pub fn clone_request_head_type(head_type: &RequestHeadType) -> RequestHeadType {
+    match head_type {
+        RequestHeadType::Owned(h) => {
+            let mut inner_head = RequestHead::default();
+            inner_head.uri = h.uri.clone();
+            inner_head.method = h.method.clone();
+            inner_head.version = h.version;
+            inner_head.peer_addr = h.peer_addr;
+            inner_head.headers = h.headers.clone();
+
+            RequestHeadType::Owned(inner_head)
+        }
+        RequestHeadType::Rc(h, header_map) => {
+            RequestHeadType::Rc(h.clone(), header_map.clone())
+        }
+    }
+}
+
+/// The value of the header `name` as it will be sent, extra headers of a shared head take
+/// precedence over the head's own
+pub(crate) fn header<'a>(head_type: &'a RequestHeadType, name: &HeaderName) -> Option<&'a HeaderValue> {
+    head_type
+        .extra_headers()
+        .and_then(|extra| extra.get(name))
+        .or_else(|| head_type.as_ref().headers.get(name))
+}
+
+/// The headers as they will be sent, only copied when a shared head has extra headers
+pub(crate) fn headers(head_type: &RequestHeadType) -> Cow<'_, HeaderMap> {
+    match head_type.extra_headers() {
+        Some(extra) if !extra.is_empty() => {
+            let mut headers = head_type.as_ref().headers.clone();
+            for (name, value) in extra.iter() {
+                headers.insert(name.clone(), value.clone());
+            }
+            Cow::Owned(headers)
+        }
+        _ => Cow::Borrowed(&head_type.as_ref().headers),
+    }
+}
+
+/// Sets the header `name`, replacing any value it had
+pub(crate) fn insert_header(head_type: &mut RequestHeadType, name: HeaderName, value: HeaderValue) {
+    match head_type {
+        RequestHeadType::Owned(head) => {
+            head.headers.insert(name, value);
+        }
+        // Extra headers take precedence over the shared head's own when the request is written
+        RequestHeadType::Rc(_, extra) => {
+            extra.get_or_insert_with(HeaderMap::new).insert(name, value);
+        }
+    }
+}
+
+/// Copies `head_type` into a standalone head, with the extra headers of a shared head merged in.
+///
+/// Extensions aren't copied, not even those registered with a [`HeadCloner`], the copy is meant
+/// for requests made on the side.
+pub(crate) fn owned_head(head_type: &RequestHeadType) -> RequestHead {
+    let (h, extra) = match head_type {
+        RequestHeadType::Owned(h) => (h, None),
+        RequestHeadType::Rc(h, extra) => (h.as_ref(), extra.as_ref()),
+    };
+
+    let mut head = RequestHead::default();
+    head.uri = h.uri.clone();
+    head.method = h.method.clone();
+    head.version = h.version;
+    head.headers = h.headers.clone();
+    if let Some(extra) = extra {
+        for (name, value) in extra.iter() {
+            head.headers.insert(name.clone(), value.clone());
+        }
+    }
+
+    head
+}
+
+/// Clones request heads along with the extension types registered with it.
+///
+/// ```
+/// use actix_http::RequestHeadType;
+/// use actix_web::dev::RequestHead;
+/// use awc_retry::head::HeadCloner;
+///
+/// #[derive(Clone)]
+/// struct TraceId(u64);
+///
+/// let cloner = HeadCloner::new().extension::<TraceId>();
+///
+/// let head = RequestHead::default();
+/// head.extensions_mut().insert(TraceId(7));
+///
+/// let copy = cloner.clone_head(&RequestHeadType::Owned(head));
+/// assert_eq!(copy.as_ref().extensions().get::<TraceId>().map(|t| t.0), Some(7));
+/// ```
+#[derive(Default)]
+pub struct HeadCloner {
+    extensions: Vec<Box<CopyFn>>,
+}
+
+type CopyFn = dyn Fn(&Extensions, &mut Extensions);
+
+impl HeadCloner {
+    pub fn new() -> Self {
+        HeadCloner::default()
+    }
+
+    /// Registers an extension type which is copied to the cloned heads
+    pub fn extension<T: Clone + 'static>(mut self) -> Self {
+        self.extensions.push(Box::new(|from, to| {
+            if let Some(ext) = from.get::<T>() {
+                to.insert(ext.clone());
+            }
+        }));
+        self
+    }
+
+    /// Same as [`clone_request_head_type`], but also copies the registered extensions of owned
+    /// heads, shared heads keep sharing all of theirs
+    pub fn clone_head(&self, head_type: &RequestHeadType) -> RequestHeadType {
+        let cloned = clone_request_head_type(head_type);
+
+        if let (RequestHeadType::Owned(from), RequestHeadType::Owned(to)) = (head_type, &cloned) {
+            if !self.extensions.is_empty() {
+                let from = from.extensions();
+                let mut to = to.extensions_mut();
+                for copy in &self.extensions {
+                    copy(&from, &mut to);
+                }
+            }
+        }
+
+        cloned
+    }
+}
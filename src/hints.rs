@@ -0,0 +1,223 @@
+//! Waits a server asks for before it's sent a request again.
+
+use actix_http::http::header::{HeaderName, HttpDate, RETRY_AFTER};
+use actix_http::http::StatusCode;
+use actix_web::dev::ResponseHead;
+use awc::ClientResponse;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::policy::AttemptOutcome;
+use crate::{Inner, Retry, RetryContext};
+
+/// Longest wait a server is taken by, even without [`Retry::max_retry_after`]
+const MAX_HINT: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// A `Retry-After` given in seconds or as an HTTP-date, a date in the past is no wait
+pub(crate) fn retry_after(res: &ClientResponse) -> Option<Duration> {
+    let value = res.headers().get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(secs) = value.parse() {
+        return Some(Duration::from_secs(secs).min(MAX_HINT));
+    }
+
+    let date = SystemTime::from(value.parse::<HttpDate>().ok()?);
+    Some(date.duration_since(SystemTime::now()).unwrap_or_default().min(MAX_HINT))
+}
+
+/// `X-RateLimit-Reset` values this large are Unix timestamps rather than seconds to wait
+const TIMESTAMP_RESET: u64 = 1_000_000_000;
+
+/// How long until the rate limit `res` reports resets, from `RateLimit-Reset` in seconds or
+/// `X-RateLimit-Reset` in seconds or as a Unix timestamp
+pub(crate) fn rate_limit_reset(res: &ClientResponse) -> Option<Duration> {
+    if let Some(secs) = number(res, "ratelimit-reset") {
+        return Some(Duration::from_secs(secs).min(MAX_HINT));
+    }
+
+    let wait = match number(res, "x-ratelimit-reset")? {
+        at if at >= TIMESTAMP_RESET => {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+            Duration::from_secs(at).checked_sub(now).unwrap_or_default()
+        }
+        secs => Duration::from_secs(secs),
+    };
+    Some(wait.min(MAX_HINT))
+}
+
+/// The value of the header `name` as a whole number
+pub(crate) fn number(res: &ClientResponse, name: &'static str) -> Option<u64> {
+    res.headers()
+        .get(HeaderName::from_static(name))?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+impl Inner {
+    /// Runs the [`delay_from_head`](Retry::delay_from_head) extractors against `res`
+    pub(crate) fn head_delay(&self, res: ClientResponse) -> (ClientResponse, Option<Duration>) {
+        if self.head_delays.is_empty() {
+            return (res, None);
+        }
+
+        // `map_body` is the only way to get at the `ResponseHead` of a `ClientResponse`
+        let mut delay = None;
+        let res = res.map_body(|head, payload| {
+            delay = self.head_delays.iter().find_map(|f| f(head));
+            payload
+        });
+        (res, delay)
+    }
+
+    /// The wait the server asked for after the attempt of `ctx`. The first wait hint to answer
+    /// beats `head_delay`, from [`Inner::head_delay`], which beats the headers read by default.
+    pub(crate) fn wait_hint(&self, ctx: &RetryContext<'_>, head_delay: Option<Duration>) -> Option<Duration> {
+        if let Some(wait) = self.wait_hints.iter().find_map(|f| f(ctx)).or(head_delay) {
+            return Some(wait);
+        }
+
+        let res = match ctx.outcome {
+            AttemptOutcome::Response(res) => res,
+            AttemptOutcome::Error(_) => return None,
+        };
+        let mut wait = None;
+        if self.retry_after && throttled(res.status()) {
+            wait = retry_after(res);
+        }
+        if self.rate_limit_reset && wait.is_none() && res.status() == StatusCode::TOO_MANY_REQUESTS {
+            wait = rate_limit_reset(res);
+        }
+
+        wait.map(|wait| self.max_retry_after.map_or(wait, |max| wait.min(max)))
+    }
+}
+
+fn throttled(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status == StatusCode::SERVICE_UNAVAILABLE
+}
+
+impl Retry {
+    /// Waits as long as the `Retry-After` header of `429 Too Many Requests` and
+    /// `503 Service Unavailable` responses asks before retrying them, instead of the delay of
+    /// the backoff.
+    ///
+    /// Both the delay in seconds and the HTTP-date form are understood, see
+    /// [`max_retry_after`](Retry::max_retry_after) to cap the wait. Only decides how long to
+    /// wait, the policies still decide whether these responses are retried at all.
+    ///
+    /// ```
+    /// use actix_http::http::StatusCode;
+    /// use awc_retry::Retry;
+    ///
+    /// let retry = Retry::new(3)
+    ///     .policy(vec![StatusCode::TOO_MANY_REQUESTS, StatusCode::SERVICE_UNAVAILABLE])
+    ///     .respect_retry_after(true);
+    /// ```
+    pub fn respect_retry_after(mut self, enabled: bool) -> Self {
+        self.0.retry_after = enabled;
+        self
+    }
+
+    /// Waits at most `max` for a `Retry-After` or rate limit reset, so a broken or hostile server
+    /// can't have a request sleep for hours. Without it the waits are cut to a day.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use awc_retry::Retry;
+    ///
+    /// let retry = Retry::new(3).respect_retry_after(true).max_retry_after(Duration::from_secs(60));
+    /// ```
+    pub fn max_retry_after(mut self, max: Duration) -> Self {
+        self.0.max_retry_after = Some(max);
+        self
+    }
+
+    /// Waits until the rate limit resets before retrying `429 Too Many Requests` responses, as
+    /// reported by `RateLimit-Reset` or `X-RateLimit-Reset`, the latter in seconds or as a Unix
+    /// timestamp.
+    ///
+    /// A `Retry-After` honoured by [`respect_retry_after`](Retry::respect_retry_after) takes
+    /// precedence. See [`rate_limit_throttle`](Retry::rate_limit_throttle) to avoid the `429`
+    /// in the first place.
+    ///
+    /// ```
+    /// use actix_http::http::StatusCode;
+    /// use awc_retry::Retry;
+    ///
+    /// let retry = Retry::new(3)
+    ///     .policy(vec![StatusCode::TOO_MANY_REQUESTS])
+    ///     .respect_rate_limit_reset(true);
+    /// ```
+    pub fn respect_rate_limit_reset(mut self, enabled: bool) -> Self {
+        self.0.rate_limit_reset = enabled;
+        self
+    }
+
+    /// Reads the wait before retrying a response from its head, for vendor specific throttling
+    /// headers.
+    ///
+    /// The extractors run in the order they were added, the first to return a delay wins over
+    /// the backoff and over [`respect_retry_after`](Retry::respect_retry_after) and
+    /// [`respect_rate_limit_reset`](Retry::respect_rate_limit_reset).
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use awc_retry::Retry;
+    ///
+    /// let retry = Retry::new(3).delay_from_head(|head| {
+    ///     let millis = head.headers.get("x-backoff-ms")?.to_str().ok()?.parse().ok()?;
+    ///     Some(Duration::from_millis(millis))
+    /// });
+    /// ```
+    pub fn delay_from_head<F>(mut self, f: F) -> Self
+        where F: Fn(&ResponseHead) -> Option<Duration> + 'static
+    {
+        self.0.head_delays.push(Box::new(f));
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use awc::test::TestResponse;
+
+    fn response(name: &'static str, value: &str) -> ClientResponse {
+        TestResponse::default().header(name, value).finish()
+    }
+
+    #[test]
+    fn retry_after_in_seconds() {
+        assert_eq!(retry_after(&response("retry-after", "120")), Some(Duration::from_secs(120)));
+        assert_eq!(retry_after(&response("retry-after", " 5 ")), Some(Duration::from_secs(5)));
+        assert_eq!(retry_after(&response("retry-after", "-5")), None);
+        assert_eq!(retry_after(&response("retry-after", "soon")), None);
+        assert_eq!(retry_after(&response("retry-after", "18446744073709551615")), Some(MAX_HINT));
+        assert_eq!(retry_after(&TestResponse::default().finish()), None);
+    }
+
+    #[test]
+    fn retry_after_as_http_date() {
+        let in_a_minute = HttpDate::from(SystemTime::now() + Duration::from_secs(60)).to_string();
+        let wait = retry_after(&response("retry-after", &in_a_minute)).unwrap();
+        // HTTP-dates have whole seconds
+        assert!(wait > Duration::from_secs(58) && wait <= Duration::from_secs(60));
+
+        assert_eq!(retry_after(&response("retry-after", "Wed, 21 Oct 2015 07:28:00 GMT")), Some(Duration::from_secs(0)));
+    }
+
+    #[test]
+    fn rate_limit_reset_in_seconds_or_as_timestamp() {
+        assert_eq!(rate_limit_reset(&response("ratelimit-reset", "30")), Some(Duration::from_secs(30)));
+        assert_eq!(rate_limit_reset(&response("x-ratelimit-reset", "30")), Some(Duration::from_secs(30)));
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let wait = rate_limit_reset(&response("x-ratelimit-reset", &(now + 60).to_string())).unwrap();
+        assert!(wait > Duration::from_secs(58) && wait <= Duration::from_secs(60));
+        assert_eq!(rate_limit_reset(&response("x-ratelimit-reset", &(now - 60).to_string())), Some(Duration::from_secs(0)));
+        assert_eq!(rate_limit_reset(&response("x-ratelimit-reset", "never")), None);
+        assert_eq!(rate_limit_reset(&response("ratelimit-reset", "18446744073709551615")), Some(MAX_HINT));
+        assert_eq!(rate_limit_reset(&response("x-ratelimit-reset", "18446744073709551615")), Some(MAX_HINT));
+    }
+}
@@ -0,0 +1,100 @@
+//! Keys servers deduplicate retried requests by.
+
+use actix_http::http::header::HeaderName;
+use actix_http::http::{HeaderValue, Method};
+use actix_http::RequestHeadType;
+
+use crate::{head, Retry};
+
+/// Methods which are only safe to retry with an idempotency key
+pub(crate) const KEYED_METHODS: [Method; 2] = [Method::POST, Method::PATCH];
+
+/// Gives a `POST` or `PATCH` without the header `name` a fresh key in it, which every attempt
+/// of the request then sends
+pub(crate) fn ensure_key(head_type: &mut RequestHeadType, name: &HeaderName) {
+    if !KEYED_METHODS.contains(&head_type.as_ref().method) || head::header(head_type, name).is_some() {
+        return;
+    }
+
+    head::insert_header(head_type, name.clone(), uuid_v4());
+}
+
+/// A random version 4 UUID, in its hyphenated form
+fn uuid_v4() -> HeaderValue {
+    let random = rand::random::<u128>();
+    // Version 4 in the top half of the 7th byte, variant 1 in the top two bits of the 9th
+    let uuid = (random & !(0xf << 76) & !(0x3 << 62)) | (0x4 << 76) | (0x2 << 62);
+    let text = format!(
+        "{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+        uuid >> 96,
+        (uuid >> 80) & 0xffff,
+        (uuid >> 64) & 0xffff,
+        (uuid >> 48) & 0xffff,
+        uuid & 0xffff_ffff_ffff,
+    );
+    HeaderValue::from_str(&text).expect("a hyphenated UUID is a valid header value")
+}
+
+impl Retry {
+    /// Puts a random UUID into the `header` of every `POST` and `PATCH` which doesn't carry one
+    /// yet, usually `Idempotency-Key`, and sends the same key with all of its retries, so the
+    /// server can tell a retry from a new request.
+    ///
+    /// Requests with a key pass [`require_idempotency_key`](Retry::require_idempotency_key).
+    ///
+    /// ```
+    /// use actix_http::http::header::HeaderName;
+    /// use awc_retry::Retry;
+    ///
+    /// let key = HeaderName::from_static("idempotency-key");
+    /// let retry = Retry::new(3)
+    ///     .policy(500..=599)
+    ///     .generate_idempotency_keys(key.clone())
+    ///     .require_idempotency_key(key);
+    /// ```
+    pub fn generate_idempotency_keys(mut self, header: HeaderName) -> Self {
+        self.0.idempotency_key = Some(header);
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::dev::RequestHead;
+
+    fn request(method: Method) -> RequestHeadType {
+        let mut head = RequestHead::default();
+        head.method = method;
+        RequestHeadType::Owned(head)
+    }
+
+    #[test]
+    fn keys_are_hyphenated_version_4_uuids() {
+        for _ in 0..100 {
+            let key = uuid_v4();
+            let key = key.to_str().unwrap();
+            let groups: Vec<_> = key.split('-').map(str::len).collect();
+            assert_eq!(groups, [8, 4, 4, 4, 12], "{}", key);
+            assert!(key.chars().all(|c| c == '-' || c.is_ascii_digit() || ('a'..='f').contains(&c)), "{}", key);
+            assert_eq!(&key[14..15], "4", "{}", key);
+            assert!("89ab".contains(&key[19..20]), "{}", key);
+        }
+        assert_ne!(uuid_v4(), uuid_v4());
+    }
+
+    #[test]
+    fn only_keyed_methods_without_a_key_get_one() {
+        let name = HeaderName::from_static("idempotency-key");
+
+        let mut post = request(Method::POST);
+        ensure_key(&mut post, &name);
+        let key = head::header(&post, &name).cloned().unwrap();
+        ensure_key(&mut post, &name);
+        assert_eq!(head::header(&post, &name), Some(&key));
+
+        let mut get = request(Method::GET);
+        ensure_key(&mut get, &name);
+        assert_eq!(head::header(&get, &name), None);
+    }
+}
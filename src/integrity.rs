@@ -0,0 +1,133 @@
+//! Checking received bodies against the length and digest their headers declare.
+
+use actix_http::http::header::{self, HeaderName};
+use actix_http::http::{Method, StatusCode};
+use actix_http::RequestHeadType;
+use awc::ClientResponse;
+use md5::{Digest, Md5};
+
+use crate::body::{self, Buffered};
+use crate::Retry;
+
+pub(crate) struct Integrity {
+    /// Longest body checked, longer ones pass
+    limit: usize,
+}
+
+impl Integrity {
+    /// Reads the body of `res`, the response to `req`, and tells whether it has the length of its
+    /// `Content-Length` and the digest of its `Content-MD5`.
+    ///
+    /// Responses declaring neither, declaring more than `limit` bytes, without a body or with a
+    /// `Content-Encoding` pass.
+    pub(crate) async fn verify(&self, res: ClientResponse, req: Option<&RequestHeadType>) -> (ClientResponse, bool) {
+        let status = res.status();
+        if req.is_some_and(|req| req.as_ref().method == Method::HEAD)
+            || status.is_informational()
+            || status == StatusCode::NO_CONTENT
+            || status == StatusCode::NOT_MODIFIED
+        {
+            return (res, true);
+        }
+
+        // Both describe the encoded body, which clients decompressing it don't get to see
+        let encoded = res.headers().contains_key(header::CONTENT_ENCODING);
+        let length = body::declared_length(&res);
+        let digest = res.headers()
+            .get(HeaderName::from_static("content-md5"))
+            .and_then(|v| base64::decode(v.as_bytes()).ok())
+            .filter(|_| !encoded);
+        if (length.is_none() && digest.is_none()) || length.is_some_and(|len| len > self.limit) {
+            return (res, true);
+        }
+
+        let (res, buffered) = body::buffer(res, self.limit).await;
+        let intact = match buffered {
+            Buffered::Complete(bytes) => {
+                length.is_none_or(|len| bytes.len() == len)
+                    && digest.is_none_or(|digest| Md5::digest(&bytes)[..] == digest[..])
+            }
+            Buffered::TooLong(_) => true,
+            // Connections closed early end the body with an error
+            Buffered::Failed => false,
+        };
+        (res, intact)
+    }
+}
+
+impl Retry {
+    /// Reads bodies of up to `limit` bytes and retries a response once if its body is cut short
+    /// or doesn't match its `Content-Length` or `Content-MD5`, as flaky proxies truncating
+    /// downloads cause.
+    ///
+    /// Intact responses are returned with their body replayed from memory, as are responses
+    /// still failing the check on the retry. [`validate`](Retry::validate) rejects this together
+    /// with [`streaming_safe`](Retry::streaming_safe).
+    ///
+    /// ```
+    /// use awc_retry::Retry;
+    ///
+    /// let retry = Retry::new(3).verify_integrity(1024 * 1024);
+    /// ```
+    pub fn verify_integrity(mut self, limit: usize) -> Self {
+        self.0.integrity = Some(Integrity { limit });
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::dev::RequestHead;
+    use awc::test::TestResponse;
+
+    const BODY: &[u8] = b"hello world";
+
+    fn digest(body: &[u8]) -> String {
+        base64::encode(Md5::digest(body))
+    }
+
+    fn verify(res: TestResponse, req: Option<&RequestHeadType>) -> bool {
+        let integrity = Integrity { limit: 1024 };
+        actix_rt::System::new().block_on(async {
+            let (mut res, intact) = integrity.verify(res.set_payload(BODY).finish(), req).await;
+            // The body is still there to be read
+            assert_eq!(res.body().await.unwrap(), BODY);
+            intact
+        })
+    }
+
+    #[test]
+    fn bodies_matching_their_digest_and_length_are_intact() {
+        let res = TestResponse::default().header("content-md5", digest(BODY)).header("content-length", "11");
+        assert!(verify(res, None));
+    }
+
+    #[test]
+    fn bodies_not_matching_their_digest_are_not() {
+        assert!(!verify(TestResponse::default().header("content-md5", digest(b"hello")), None));
+        // Not base64, so there's no digest to compare with
+        assert!(verify(TestResponse::default().header("content-md5", "%%%"), None));
+    }
+
+    #[test]
+    fn bodies_not_matching_their_length_are_not() {
+        assert!(!verify(TestResponse::default().header("content-length", "20"), None));
+    }
+
+    #[test]
+    fn encoded_bodies_and_head_requests_pass() {
+        let encoded = TestResponse::default().header("content-md5", digest(b"hello")).header("content-encoding", "gzip");
+        assert!(verify(encoded, None));
+
+        let mut head = RequestHead::default();
+        head.method = Method::HEAD;
+        let res = TestResponse::default().header("content-length", "20");
+        assert!(verify(res, Some(&RequestHeadType::Owned(head))));
+    }
+
+    #[test]
+    fn bodies_longer_than_the_limit_pass() {
+        assert!(verify(TestResponse::default().header("content-length", "4096"), None));
+    }
+}
@@ -0,0 +1,97 @@
+//! Retry decisions read from JSON response bodies, enabled by the `serde_json` feature.
+
+use actix_web::HttpMessage;
+use awc::ClientResponse;
+use serde_json::Value;
+
+use crate::{Retry, RetryPolicy};
+
+/// Longest JSON body parsed, longer bodies pass
+const JSON_LIMIT: usize = 64 * 1024;
+
+impl RetryPolicy {
+    /// Retries responses whose JSON body has `value` at the JSON pointer `pointer` (RFC 6901),
+    /// for APIs which say in the payload whether a failure is worth retrying.
+    ///
+    /// Bodies of up to 64 KiB are parsed when the response has no `Content-Type` or a JSON one,
+    /// other bodies and bodies which aren't JSON pass. See [`RetryPolicy::body`] for how the
+    /// body is kept for the caller.
+    ///
+    /// ```
+    /// use awc_retry::{Policy, Retry};
+    /// use serde_json::json;
+    ///
+    /// let retry = Retry::new(3).policy(Policy::json_pointer("/error/retryable", json!(true)));
+    /// ```
+    pub fn json_pointer<P: Into<String>>(pointer: P, value: Value) -> Self {
+        let pointer = pointer.into();
+        RetryPolicy::body(JSON_LIMIT, move |res, body| {
+            if !is_json(res) {
+                return true;
+            }
+            match serde_json::from_slice::<Value>(body) {
+                Ok(doc) => doc.pointer(&pointer) != Some(&value),
+                Err(_) => true,
+            }
+        })
+    }
+
+    /// Retries GraphQL responses whose `errors` are all transient: every error reported has an
+    /// `extensions.code` among `codes`, such as `["SERVICE_UNAVAILABLE", "RATE_LIMITED"]`.
+    ///
+    /// GraphQL servers answer `200 OK` for failed operations too, so the body is parsed like for
+    /// [`RetryPolicy::json_pointer`]. Responses without errors, with an error lacking a code or
+    /// with any other code pass, and are returned with their body replayed.
+    ///
+    /// ```
+    /// use awc_retry::{Policy, Retry};
+    ///
+    /// let retry = Retry::new(3).policy(Policy::graphql_errors(&["SERVICE_UNAVAILABLE", "RATE_LIMITED"]));
+    /// ```
+    pub fn graphql_errors<I>(codes: I) -> Self
+        where
+            I: IntoIterator,
+            I::Item: AsRef<str>,
+    {
+        let codes: Vec<String> = codes.into_iter().map(|c| c.as_ref().to_owned()).collect();
+        RetryPolicy::body(JSON_LIMIT, move |res, body| {
+            if !is_json(res) {
+                return true;
+            }
+            let doc = match serde_json::from_slice::<Value>(body) {
+                Ok(doc) => doc,
+                Err(_) => return true,
+            };
+            let errors = match doc.get("errors").and_then(Value::as_array) {
+                Some(errors) if !errors.is_empty() => errors,
+                _ => return true,
+            };
+
+            !errors.iter().all(|error| {
+                error.pointer("/extensions/code")
+                    .and_then(Value::as_str)
+                    .is_some_and(|code| codes.iter().any(|c| c == code))
+            })
+        })
+    }
+}
+
+fn is_json(res: &ClientResponse) -> bool {
+    let essence = res.content_type().split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+    essence.is_empty() || essence == "application/json" || essence.ends_with("+json")
+}
+
+impl Retry {
+    /// Retries responses whose JSON body has `value` at `pointer`, a shorthand for a
+    /// [`RetryPolicy::json_pointer`] policy.
+    ///
+    /// ```
+    /// use awc_retry::Retry;
+    /// use serde_json::json;
+    ///
+    /// let retry = Retry::new(3).retry_if_json("/error/retryable", json!(true));
+    /// ```
+    pub fn retry_if_json<P: Into<String>>(self, pointer: P, value: Value) -> Self {
+        self.policy(RetryPolicy::json_pointer(pointer, value))
+    }
+}
@@ -1,39 +1,220 @@
 use actix_service::Service;
 use actix_web::body::Body;
-use awc::error::{ConnectError, SendRequestError};
+use awc::error::SendRequestError;
 use awc::middleware::Transform;
-use awc::{ClientResponse, ConnectRequest, ConnectResponse};
+use awc::{ClientResponse, ConnectRequest, ConnectResponse, ConnectorService};
 use bytes::Bytes;
-use futures::future::{ok, LocalBoxFuture, Ready};
+use futures::future::{poll_fn, LocalBoxFuture};
 use futures::task::{Context, Poll};
 use std::rc::Rc;
 use actix_http::RequestHeadType;
 use actix_web::dev::{RequestHead, ResponseHead};
-use actix_http::http::StatusCode;
-use actix_web::guard::Guard;
-use std::ops::Deref;
-use std::borrow::Borrow;
+use actix_http::http::header::HeaderName;
+use actix_http::http::uri::PathAndQuery;
+use actix_http::http::{HeaderValue, Method, StatusCode, Uri, Version};
+use std::fmt;
+use std::time::Duration;
+
+mod adaptive;
+mod attempt;
+mod audit;
+mod backoff;
+mod bandwidth;
+mod body;
+mod breaker;
+mod budget;
+mod credentials;
+mod downgrade;
+mod drain;
+mod error;
+mod events;
+mod failover;
+#[cfg(feature = "failsafe")]
+mod failsafe;
+pub mod head;
+mod hints;
+mod idempotency;
+mod integrity;
+#[cfg(feature = "serde_json")]
+mod json;
+mod policy;
+mod poll;
+#[cfg(feature = "presets")]
+pub mod presets;
+mod probe;
+mod replay;
+mod resume;
+mod send;
+mod shadow;
+mod state;
+mod throttle;
+mod timer;
+pub mod tls;
+
+use attempt::{Attempts, Outcome, Step};
+pub use backoff::{Backoff, ExponentialBackoff, FibonacciBackoff, Jitter, LinearBackoff};
+pub use breaker::{BreakerHandle, CircuitOpen, CircuitState, CircuitStatus, HostBreaker};
+pub use budget::{Budget, RetryRate};
+pub use error::{ConfigError, SendBodyError};
+pub use events::{AttemptEvent, AttemptEvents, Timings};
+pub use state::{HistoryEntry, SharedState, Stats};
+pub use timer::{RuntimeTimer, Timer};
+pub use replay::{retry_fn, Replayable, RetryFn};
+pub use send::run;
+pub use policy::{AttemptContext, AttemptOutcome, Combine, IntoRetryPolicy, Policy, RetryContext, RetryDecision, RetryPolicy, StatefulPolicy, Verdict};
 
 pub struct Retry(Inner);
 
 struct Inner {
     /// Number of retries. So each request will be tried [max_retries + 1] times
     max_retries: u8,
-    policies: Vec<RetryPolicy>,
+    policies: policy::Policies,
+    /// Alternate connectors, in the order they are switched to
+    connectors: Vec<ConnectorService>,
+    /// Where requests go once HTTP/2 keeps failing them
+    downgrade: Option<downgrade::Downgrade>,
+    /// Decides whether a failed attempt moves on to the next connector in [`connectors`]
+    switch_connector: Box<dyn Fn(&SendRequestError) -> bool>,
+    /// Give up straight away on TLS failures which [`tls::TlsFailure::is_retryable`] rejects
+    classify_tls: bool,
+    /// Leave certificate failures to the policies instead of giving up on them
+    retry_certificate_errors: bool,
+    /// Retry HTTP/2 streams the server refused unprocessed, whatever the policies
+    retry_refused_streams: bool,
+    /// Where to mirror requests which needed at least one retry
+    shadow: Option<shadow::Shadow>,
+    /// Only log the retries that would have been made
+    dry_run: bool,
+    /// Creates the per request copies of every [`StatefulPolicy`]
+    stateful: Vec<Box<dyn Fn() -> Box<dyn StatefulPolicy>>>,
+    /// Builds the response returned in place of the final error
+    fallback: Option<Box<FallbackFn>>,
+    /// Post-processes the final response of a request
+    map_response: Option<Box<dyn Fn(ClientResponse) -> ClientResponse>>,
+    /// Post-processes the final error of a request
+    map_error: Option<Box<dyn Fn(SendRequestError) -> SendRequestError>>,
+    /// Checked right before each retry, any of them returning true cancels it
+    vetoes: Vec<Box<VetoFn>>,
+    /// Retry limits replacing `max_retries` for the outcomes their function returns true for
+    retry_limits: Vec<(Box<OutcomeFn>, u8)>,
+    /// Redirect statuses which are retried against the same URI instead of being returned
+    retry_redirects: Vec<StatusCode>,
+    /// Duplicates the request head for every attempt
+    head_cloner: head::HeadCloner,
+    /// Treat a connector failing `poll_ready` before a retry as another failed attempt
+    retry_readiness_errors: bool,
+    /// Refuses attempts while its circuit is open
+    breaker: Option<Box<dyn breaker::Breaker>>,
+    /// Refuses attempts towards hosts whose circuit is open
+    host_breakers: Option<HostBreaker>,
+    /// Tokens retries are paid from
+    budget: Option<Budget>,
+    /// Retries allowed per time window
+    retry_rate: Option<RetryRate>,
+    /// Creates the per request iterator of delays before each retry
+    backoff: Option<Box<BackoffFn>>,
+    /// Backoffs replacing `backoff` for the outcomes their function returns true for
+    class_backoffs: Vec<(Box<OutcomeFn>, Box<BackoffFn>)>,
+    /// Randomizes the delays of `backoff`
+    jitter: Option<backoff::Jitter>,
+    /// Read the wait a server asked for from an attempt, the first one to answer beats the backoff
+    wait_hints: Vec<Box<WaitHintFn>>,
+    /// Read the wait before a retry from the response head
+    head_delays: Vec<Box<HeadDelayFn>>,
+    /// Wait for the `Retry-After` of throttled responses
+    retry_after: bool,
+    /// Wait for the rate limit reset of `429` responses
+    rate_limit_reset: bool,
+    /// Longest `Retry-After` or rate limit reset honoured, longer ones are shortened to it
+    max_retry_after: Option<Duration>,
+    /// Where retries are sent instead of the address the request resolved to
+    failover: Option<failover::Failover>,
+    /// Failover addresses retries skip for a while after failing
+    outliers: Option<failover::Outliers>,
+    /// Fraction of requests whose attempts are logged, between 0 and 1
+    telemetry_ratio: f64,
+    /// Process wide counters this configuration reports into
+    state: Option<SharedState>,
+    /// Caps the request body bytes re-sent by retries
+    bandwidth: Option<bandwidth::BandwidthGuard>,
+    /// Where every retry decision is recorded
+    audit: Option<audit::AuditLog>,
+    /// How long each attempt may take, by attempt number
+    attempt_timeout: Option<Box<AttemptTimeoutFn>>,
+    /// How long a request may take across all of its attempts
+    total_deadline: Option<Duration>,
+    /// How long a first attempt waits for the circuit breaker to let it through
+    queue_first: Option<Duration>,
+    /// Lowers `max_retries` for hosts which keep failing
+    adaptive: Option<adaptive::AdaptiveLimit>,
+    /// Drops retries while many attempts fail
+    adaptive_throttle: Option<adaptive::AdaptiveThrottle>,
+    /// Method of the request which opens a connection while backing off after an error
+    prewarm: Option<Method>,
+    /// Checks the origin is back before large bodies are sent again
+    probe: Option<probe::Probe>,
+    /// Reject policies which buffer bodies in [`Retry::validate`]
+    streaming_safe: bool,
+    /// `Authorization` values tried in turn on auth failures
+    credentials: credentials::Credentials,
+    /// Holds attempts back while a host's rate limit is nearly used up
+    throttle: Option<throttle::Throttle>,
+    /// Requests in flight, and whether retries were stopped for shutting down
+    drain: drain::Drain,
+    /// Clock of the waits and deadlines
+    timer: Box<dyn Timer>,
+    /// Header generated idempotency keys are sent in
+    idempotency_key: Option<HeaderName>,
+    /// Retries responses whose body doesn't match its length or digest
+    integrity: Option<integrity::Integrity>,
+    /// Requests the rest of bodies which broke off
+    resume: Option<resume::Resume>,
 }
 
+type BackoffFn = dyn Fn() -> Box<dyn backoff::Delays>;
+type WaitHintFn = dyn Fn(&RetryContext<'_>) -> Option<Duration>;
+type HeadDelayFn = dyn Fn(&ResponseHead) -> Option<Duration>;
+type FallbackFn = dyn Fn(&RequestHead, &SendRequestError) -> Option<ClientResponse>;
+type VetoFn = dyn Fn(&RetryContext<'_>) -> bool;
+type OutcomeFn = dyn Fn(&AttemptOutcome<'_>) -> bool;
+type AttemptTimeoutFn = dyn Fn(u32) -> Option<Duration>;
+
 impl Inner {
-    pub fn is_valid_response(&self, head: &ResponseHead) -> bool {
-        self.policies.iter().all(|policy| {
-            match policy {
-                RetryPolicy::Status(v) => {
-                    true
-                }
-                RetryPolicy::Custom(func) => {
-                    (func.deref())(head)
-                }
-            }
-        })
+    /// Runs every policy against `res` to `req` at `ctx`, handing the response back along with
+    /// whether it was valid
+    async fn check_response(
+        &self,
+        res: ClientResponse,
+        req: Option<&RequestHeadType>,
+        ctx: &AttemptContext,
+    ) -> (ClientResponse, bool) {
+        if self.retry_redirects.contains(&res.status()) {
+            return (res, false);
+        }
+
+        self.policies.check(res, req, ctx, !self.streaming_safe).await
+    }
+
+    /// Applies the [`map_response`](Retry::map_response) and [`map_error`](Retry::map_error) functions
+    fn finish(&self, result: Result<ClientResponse, SendRequestError>) -> Result<ClientResponse, SendRequestError> {
+        match result {
+            Ok(res) => match self.map_response.as_ref() {
+                Some(f) => Ok(f(res)),
+                None => Ok(res),
+            },
+            Err(e) => match self.map_error.as_ref() {
+                Some(f) => Err(f(e)),
+                None => Err(e),
+            },
+        }
+    }
+
+    fn is_terminal_error(&self, err: &SendRequestError) -> bool {
+        match tls::classify(err) {
+            Some(tls::TlsFailure::Certificate) if !self.retry_certificate_errors => true,
+            Some(failure) => self.classify_tls && !failure.is_retryable(),
+            None => false,
+        }
     }
 }
 
@@ -41,14 +222,684 @@ impl Retry {
     pub fn new(retries: u8) -> Self {
         Retry(Inner {
             max_retries: retries,
-            policies: vec![],
+            policies: policy::Policies::default(),
+            connectors: vec![],
+            downgrade: None,
+            switch_connector: Box::new(|e| matches!(e, SendRequestError::Connect(_))),
+            classify_tls: false,
+            retry_certificate_errors: false,
+            retry_refused_streams: true,
+            shadow: None,
+            dry_run: false,
+            stateful: vec![],
+            fallback: None,
+            map_response: None,
+            map_error: None,
+            vetoes: vec![],
+            retry_limits: vec![],
+            retry_redirects: vec![],
+            head_cloner: head::HeadCloner::new(),
+            retry_readiness_errors: false,
+            breaker: None,
+            host_breakers: None,
+            budget: None,
+            retry_rate: None,
+            backoff: None,
+            class_backoffs: vec![],
+            jitter: None,
+            wait_hints: Vec::new(),
+            head_delays: Vec::new(),
+            retry_after: false,
+            rate_limit_reset: false,
+            max_retry_after: None,
+            failover: None,
+            outliers: None,
+            telemetry_ratio: 1.0,
+            state: None,
+            bandwidth: None,
+            audit: None,
+            attempt_timeout: None,
+            total_deadline: None,
+            queue_first: None,
+            adaptive: None,
+            adaptive_throttle: None,
+            prewarm: None,
+            probe: None,
+            streaming_safe: false,
+            credentials: credentials::Credentials::default(),
+            throttle: None,
+            drain: drain::Drain::default(),
+            timer: Box::new(RuntimeTimer),
+            idempotency_key: None,
+            integrity: None,
+            resume: None,
+        })
+    }
+
+    /// Retries `5xx` responses and [transient errors](RetryPolicy::transient_errors) up to
+    /// `retries` times, but only for the methods RFC 7231 defines as idempotent, which is what
+    /// most HTTP clients consider safe.
+    ///
+    /// A `POST` or `PATCH` is sent once, whatever comes back. More policies can be added as
+    /// usual, they are restricted to idempotent methods as well.
+    ///
+    /// ```
+    /// use awc_retry::Retry;
+    ///
+    /// let client = awc::Client::builder().wrap(Retry::idempotent(3)).finish();
+    /// ```
+    pub fn idempotent(retries: u8) -> Self {
+        Retry::new(retries)
+            .policy(RetryPolicy::server_errors())
+            .policy(RetryPolicy::transient_errors())
+            .policy(RetryPolicy::idempotent_methods())
+    }
+
+    /// Adds an alternate connector to the end of the fallback list.
+    ///
+    /// Every request starts on the connector the client was built with. When an attempt fails
+    /// with an error accepted by [`switch_connector_on`](Retry::switch_connector_on) (by default
+    /// any [`SendRequestError::Connect`]) the next attempt moves on to the next connector in the
+    /// list, e.g. falling back from a direct mTLS connector to one going through a proxy.
+    /// Once the end of the list is reached the remaining attempts stay on the last connector.
+    ///
+    /// Only the retries of a request use alternate connectors, so they only take effect when
+    /// [`max_retries`](Retry::new) is greater than zero.
+    pub fn connector<S>(mut self, connector: S) -> Self
+        where
+            S: Service<ConnectRequest, Response=ConnectResponse, Error=SendRequestError> + 'static,
+    {
+        self.0.connectors.push(actix_service::boxed::service(connector));
+        self
+    }
+
+    /// Sets which errors cause the next attempt to use the next alternate [`connector`](Retry::connector).
+    ///
+    /// ```
+    /// use awc::error::{ConnectError, SendRequestError};
+    /// use awc_retry::Retry;
+    ///
+    /// // Only fall back when the connection couldn't be made in time
+    /// let retry = Retry::new(3)
+    ///     .switch_connector_on(|e| matches!(e, SendRequestError::Connect(ConnectError::Timeout)));
+    /// ```
+    pub fn switch_connector_on<F>(mut self, f: F) -> Self
+        where F: Fn(&SendRequestError) -> bool + 'static
+    {
+        self.0.switch_connector = Box::new(f);
+        self
+    }
+
+    /// Stops retrying when an attempt fails with a TLS error that can't succeed on another try.
+    ///
+    /// Handshake timeouts are still retried, while certificate and protocol failures are returned
+    /// immediately. The concrete TLS errors of both the `rustls` and `openssl` backends are
+    /// understood, see [`tls::classify`].
+    pub fn classify_tls_errors(mut self) -> Self {
+        self.0.classify_tls = true;
+        self
+    }
+
+    /// Sets whether attempts failing because the server's certificate was rejected are retried,
+    /// off by default.
+    ///
+    /// Another attempt won't get past a certificate which can't be verified, and retrying one
+    /// only hides what may be a man-in-the-middle. Turning this on leaves them to the policies
+    /// like any other error, [`classify_tls_errors`](Retry::classify_tls_errors) still stops
+    /// them when enabled.
+    ///
+    /// ```
+    /// use awc_retry::Retry;
+    ///
+    /// // The test environment rotates its self-signed certificates
+    /// let retry = Retry::new(3).retry_certificate_errors(true);
+    /// ```
+    pub fn retry_certificate_errors(mut self, enabled: bool) -> Self {
+        self.0.retry_certificate_errors = enabled;
+        self
+    }
+
+    /// Sets whether HTTP/2 streams the server refused without processing them are retried, on
+    /// by default.
+    ///
+    /// A stream reset with `REFUSED_STREAM`, or cut off by a graceful `GOAWAY` of a connection
+    /// being torn down, never reached the application, so RFC 7540 §8.1.4 allows retrying it on
+    /// a fresh connection whatever its method. These errors are retried even when the policies,
+    /// such as [`RetryPolicy::idempotent_methods`], wouldn't, but still count against
+    /// `max_retries`.
+    ///
+    /// ```
+    /// use awc_retry::Retry;
+    ///
+    /// let retry = Retry::idempotent(3).retry_refused_streams(false);
+    /// ```
+    pub fn retry_refused_streams(mut self, enabled: bool) -> Self {
+        self.0.retry_refused_streams = enabled;
+        self
+    }
+
+    /// Mirrors `percent`% of the requests which needed at least one retry to `endpoint`.
+    ///
+    /// The mirrored request keeps the method, headers, body, path and query of the original,
+    /// only the scheme and authority are taken from `endpoint`, which also replaces an explicit
+    /// `Host` header. It is sent through the wrapped connector, to the address `endpoint`
+    /// resolves to, once the original request has finished and its response is dropped, so it
+    /// never delays or alters what the caller receives.
+    ///
+    /// ```
+    /// use actix_http::http::Uri;
+    /// use awc_retry::Retry;
+    ///
+    /// // Capture a tenth of the flaky traffic on a debug server
+    /// let retry = Retry::new(3)
+    ///     .shadow_retried(Uri::from_static("http://debug.internal:8080"), 10);
+    /// ```
+    pub fn shadow_retried(mut self, endpoint: Uri, percent: u8) -> Self {
+        self.0.shadow = Some(shadow::Shadow::new(endpoint, percent));
+        self
+    }
+
+    /// Only logs the attempts of `percent`% of requests, chosen at random when they are sent.
+    ///
+    /// Requests which give up after running out of retries or backoff are always logged at
+    /// `warn` level, so a low percentage keeps the log volume of busy services down without
+    /// hiding the failures which matter. Dry run lines are not sampled either.
+    ///
+    /// ```
+    /// use awc_retry::Retry;
+    ///
+    /// let retry = Retry::new(3).sample_telemetry(5);
+    /// ```
+    pub fn sample_telemetry(mut self, percent: u8) -> Self {
+        self.0.telemetry_ratio = f64::from(percent.min(100)) / 100.0;
+        self
+    }
+
+    /// Caps the request body bytes retries re-send to `max_bytes` per `window`.
+    ///
+    /// Once a retry's body doesn't fit in what is left of the current window the retry is
+    /// skipped and the last outcome returned, so a flapping upload endpoint can't multiply the
+    /// egress traffic. Retries of requests without a body are never held back. The first
+    /// attempt of a request isn't counted, only what would be sent because of retries, and
+    /// retries which are vetoed or ruled out otherwise aren't charged.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use awc_retry::Retry;
+    ///
+    /// // At most 100 MiB of re-sent bodies a minute
+    /// let retry = Retry::new(3).resend_bandwidth(100 * 1024 * 1024, Duration::from_secs(60));
+    /// ```
+    pub fn resend_bandwidth(mut self, max_bytes: u64, window: Duration) -> Self {
+        self.0.bandwidth = Some(bandwidth::BandwidthGuard::new(max_bytes, window));
+        self
+    }
+
+    /// Opens the connection for a retry while backing off, by sending a bodiless `method`
+    /// request to the same URI through the connector the retry will use.
+    ///
+    /// Only done after attempts which failed with an error and when there is a delay to
+    /// overlap with. awc opens connections as part of sending a request, so this is the only
+    /// way to get one into its pool ahead of the retry, which then only pays for the request
+    /// itself. The warm-up request carries the original headers and its response is dropped,
+    /// pick a method without side effects like `OPTIONS` or `HEAD`.
+    ///
+    /// ```
+    /// use actix_http::http::Method;
+    /// use awc_retry::Retry;
+    ///
+    /// let retry = Retry::new(3).prewarm_connection(Method::OPTIONS);
+    /// ```
+    pub fn prewarm_connection(mut self, method: Method) -> Self {
+        self.0.prewarm = Some(method);
+        self
+    }
+
+    /// Guarantees no body is buffered by this configuration, for memory constrained deployments.
+    ///
+    /// [`validate`](Retry::validate) then rejects policies which read response bodies, such as
+    /// [`RetryPolicy::xml_error_code`], and those policies are skipped if the configuration is
+    /// used anyway. Request bodies are never buffered either way: only bodies awc already holds
+    /// in memory are sent again, streamed bodies are sent once.
+    ///
+    /// ```
+    /// use awc_retry::{ConfigError, Retry, RetryPolicy};
+    ///
+    /// let retry = Retry::new(3)
+    ///     .streaming_safe(true)
+    ///     .policy(RetryPolicy::xml_error_code(vec!["SlowDown"]));
+    /// assert_eq!(retry.validate(), Err(ConfigError::BufferingPolicy));
+    /// ```
+    pub fn streaming_safe(mut self, enabled: bool) -> Self {
+        self.0.streaming_safe = enabled;
+        self
+    }
+
+    /// Sends a `HEAD` request to the origin before retrying requests whose body is at least
+    /// `min_body` bytes, only sending the body again when the probe gets an answer other than
+    /// `429` or `5xx`.
+    ///
+    /// The probe goes to `path` when given and to the request's own URI otherwise, after the
+    /// backoff and through the connector the retry would use. When it fails the outcome of the
+    /// previous attempt is returned, so a flapping upload endpoint costs a `HEAD` request rather
+    /// than another upload.
+    ///
+    /// ```
+    /// use actix_http::http::uri::PathAndQuery;
+    /// use awc_retry::Retry;
+    ///
+    /// let retry = Retry::new(3).probe_before_retry(1024 * 1024, Some(PathAndQuery::from_static("/health")));
+    /// ```
+    pub fn probe_before_retry(mut self, min_body: usize, path: Option<PathAndQuery>) -> Self {
+        self.0.probe = Some(probe::Probe::new(min_body, path));
+        self
+    }
+
+    /// Scales the retries of a request down by the recent failure rate of its host.
+    ///
+    /// Attempts towards each URI authority are tracked over the last one to two `window`s,
+    /// with those the policies retried counting as failures. Once there are at least
+    /// `min_samples` of them, a request gets `max_retries` times the success rate of its host,
+    /// rounded, so retries tuned for occasional blips don't multiply the load of an outage and
+    /// come back as the host recovers. Only applies to the middleware, which knows the host.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use awc_retry::Retry;
+    ///
+    /// let retry = Retry::new(3).adaptive_retries(Duration::from_secs(30), 20);
+    /// ```
+    pub fn adaptive_retries(mut self, window: Duration, min_samples: u32) -> Self {
+        self.0.adaptive = Some(adaptive::AdaptiveLimit::new(window, min_samples));
+        self
+    }
+
+    /// Drops retries at random while the upstream is in trouble, more of them the worse it
+    /// gets, and lets them through again as it recovers.
+    ///
+    /// Attempts of all requests over the last one to two `window`s are counted, with those the
+    /// policies retried counting as failures. A retry is dropped with probability
+    /// `(attempts - overload * successes) / (attempts + 1)`, so nothing is dropped while there
+    /// are fewer than `overload` attempts per success, `2.0` being a common choice. Unlike
+    /// [`adaptive_retries`](Retry::adaptive_retries) this looks at the whole client rather than
+    /// one host.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use awc_retry::Retry;
+    ///
+    /// let retry = Retry::new(3).adaptive_throttling(Duration::from_secs(60), 2.0);
+    /// ```
+    pub fn adaptive_throttling(mut self, window: Duration, overload: f64) -> Self {
+        self.0.adaptive_throttle = Some(adaptive::AdaptiveThrottle::new(window, overload));
+        self
+    }
+
+    /// Makes first attempts wait up to `max` for the circuit breaker to let them through,
+    /// instead of failing with [`CircuitOpen`] straight away.
+    ///
+    /// The breaker is asked again every 50 milliseconds, a request which is still refused after
+    /// `max` fails as usual. Retries are never queued. Suits batch workloads which would rather
+    /// be late than fail.
+    pub fn queue_first_attempt(mut self, max: Duration) -> Self {
+        self.0.queue_first = Some(max);
+        self
+    }
+
+    /// Bounds a request's attempts and the waits between them to `deadline` in total.
+    ///
+    /// An attempt still running at the deadline fails with [`SendRequestError::Timeout`], and a
+    /// retry which couldn't start before the deadline isn't made, the last outcome is returned
+    /// instead even if retries remain.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use awc_retry::Retry;
+    ///
+    /// let retry = Retry::new(5).delay(Duration::from_secs(1)).total_deadline(Duration::from_secs(10));
+    /// ```
+    pub fn total_deadline(mut self, deadline: Duration) -> Self {
+        self.0.total_deadline = Some(deadline);
+        self
+    }
+
+    /// Gives the attempts of a request their own timeouts, so early attempts fail fast and
+    /// later ones get more time, the last timeout applies to any further attempts.
+    ///
+    /// An attempt which runs out of time fails with [`SendRequestError::Timeout`], which the
+    /// policies judge like any other error. The client's own timeout still bounds the request as
+    /// a whole, so it should be long enough for every attempt and backoff.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use awc_retry::Retry;
+    ///
+    /// let retry = Retry::new(2).attempt_timeouts(vec![
+    ///     Duration::from_secs(1),
+    ///     Duration::from_secs(2),
+    ///     Duration::from_secs(5),
+    /// ]);
+    /// ```
+    pub fn attempt_timeouts<I>(self, timeouts: I) -> Self
+        where I: IntoIterator<Item=Duration>
+    {
+        let timeouts: Vec<_> = timeouts.into_iter().collect();
+        self.attempt_timeout_fn(move |attempt| {
+            let last = timeouts.len().checked_sub(1)?;
+            timeouts.get(attempt as usize - 1).or_else(|| timeouts.get(last)).copied()
         })
     }
 
+    /// Like [`attempt_timeouts`](Retry::attempt_timeouts) with the timeout of each attempt
+    /// computed by `f`, from the attempt number starting at `1`. `None` leaves an attempt
+    /// without a timeout of its own.
+    pub fn attempt_timeout_fn<F>(mut self, f: F) -> Self
+        where F: Fn(u32) -> Option<Duration> + 'static
+    {
+        self.0.attempt_timeout = Some(Box::new(f));
+        self
+    }
+
+    /// Writes every retry decision to `writer`, one JSON object per line.
+    ///
+    /// Each line has the `time_ms` since the Unix epoch, a random `request_id` shared by the
+    /// lines of one request, a `fingerprint` hashed from its method and URI, the `target`
+    /// itself, the `attempt` which was judged, the `decision` (`retry` or `return`), the
+    /// `reason` for it, the `delay_ms` before the retry and the attempt's `outcome`. Lines are
+    /// flushed as they are written, write errors are logged and otherwise ignored.
+    ///
+    /// ```no_run
+    /// use std::fs::OpenOptions;
+    /// use awc_retry::Retry;
+    ///
+    /// let file = OpenOptions::new().create(true).append(true).open("retries.jsonl").unwrap();
+    /// let retry = Retry::new(3).audit_log(file);
+    /// ```
+    pub fn audit_log<W: std::io::Write + 'static>(mut self, writer: W) -> Self {
+        self.0.audit = Some(audit::AuditLog::new(writer));
+        self
+    }
+
+    /// Reports into `state`, which can be shared with the configurations of other clients
+    pub fn shared_state(mut self, state: SharedState) -> Self {
+        self.0.state = Some(state);
+        self
+    }
+
+    /// Evaluates policies as usual but never re-issues a request.
+    ///
+    /// Every retry which would have been made is logged at `info` level instead and the outcome
+    /// of the first attempt is returned as-is, so a new configuration can be rolled out and
+    /// observed in production before it's allowed to change any traffic.
+    pub fn dry_run(mut self, enabled: bool) -> Self {
+        self.0.dry_run = enabled;
+        self
+    }
+
+    /// Adds a [`StatefulPolicy`], every request gets its own clone of `policy`
+    pub fn stateful_policy<P>(mut self, policy: P) -> Self
+        where P: StatefulPolicy + Clone + 'static
+    {
+        self.0.stateful.push(Box::new(move || Box::new(policy.clone())));
+        self
+    }
+
+    /// Registers a function which can replace the error of a request the middleware gave up on.
+    ///
+    /// It's called with the request and the error of its last attempt, returning `Some` hands
+    /// that response to the caller instead of the error, e.g. a cached copy or an empty
+    /// collection. Returning `None` keeps the error.
+    ///
+    /// ```
+    /// use awc::test::TestResponse;
+    /// use awc_retry::Retry;
+    ///
+    /// let retry = Retry::new(3).fallback(|head, _err| {
+    ///     if head.uri.path() == "/recommendations" {
+    ///         Some(TestResponse::default().set_payload("[]").finish())
+    ///     } else {
+    ///         None
+    ///     }
+    /// });
+    /// ```
+    pub fn fallback<F>(mut self, f: F) -> Self
+        where F: Fn(&RequestHead, &SendRequestError) -> Option<ClientResponse> + 'static
+    {
+        self.0.fallback = Some(Box::new(f));
+        self
+    }
+
+    /// Transforms the final response of every request, after any [`fallback`](Retry::fallback).
+    ///
+    /// Calling it again runs `f` on the output of the previously registered function.
+    ///
+    /// ```
+    /// use actix_web::HttpMessage;
+    /// use awc_retry::Retry;
+    ///
+    /// struct ViaRetry;
+    ///
+    /// let retry = Retry::new(3).map_response(|res| {
+    ///     res.extensions_mut().insert(ViaRetry);
+    ///     res
+    /// });
+    /// ```
+    pub fn map_response<F>(mut self, f: F) -> Self
+        where F: Fn(ClientResponse) -> ClientResponse + 'static
+    {
+        self.0.map_response = Some(match self.0.map_response.take() {
+            Some(prev) => Box::new(move |res| f(prev(res))),
+            None => Box::new(f),
+        });
+        self
+    }
+
+    /// Transforms the final error of every request the middleware gave up on.
+    ///
+    /// Calling it again runs `f` on the output of the previously registered function. The client
+    /// requires the error to stay a [`SendRequestError`], a domain specific error can be carried
+    /// in [`SendRequestError::Body`] and read back with `as_error`.
+    ///
+    /// ```
+    /// use actix_web::ResponseError;
+    /// use awc::error::SendRequestError;
+    /// use awc_retry::Retry;
+    ///
+    /// #[derive(Debug)]
+    /// struct UpstreamUnavailable;
+    ///
+    /// impl std::fmt::Display for UpstreamUnavailable {
+    ///     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    ///         write!(f, "upstream unavailable")
+    ///     }
+    /// }
+    ///
+    /// impl ResponseError for UpstreamUnavailable {}
+    ///
+    /// let retry = Retry::new(3).map_error(|_| SendRequestError::Body(UpstreamUnavailable.into()));
+    /// ```
+    pub fn map_error<F>(mut self, f: F) -> Self
+        where F: Fn(SendRequestError) -> SendRequestError + 'static
+    {
+        self.0.map_error = Some(match self.0.map_error.take() {
+            Some(prev) => Box::new(move |err| f(prev(err))),
+            None => Box::new(f),
+        });
+        self
+    }
+
+    /// Retries the outcomes `f` returns true for up to `retries` times, rather than
+    /// [`max_retries`](Retry::new) times.
+    ///
+    /// Each limit counts the retries of the outcomes it applies to on its own, a request
+    /// retried twice for `503`s can still be retried six times for `429`s. The first limit `f`
+    /// applies to wins, outcomes without a limit share `max_retries`, of which every retry
+    /// counts. The policies still decide whether an outcome is retried at all.
+    ///
+    /// ```
+    /// use actix_http::http::StatusCode;
+    /// use awc::error::SendRequestError;
+    /// use awc_retry::{AttemptOutcome, Retry};
+    ///
+    /// let retry = Retry::new(3)
+    ///     .policy(vec![StatusCode::TOO_MANY_REQUESTS, StatusCode::SERVICE_UNAVAILABLE])
+    ///     .status_retries(StatusCode::TOO_MANY_REQUESTS, 6)
+    ///     .outcome_retries(2, |outcome| matches!(outcome, AttemptOutcome::Error(SendRequestError::Connect(_))));
+    /// ```
+    pub fn outcome_retries<F>(mut self, retries: u8, f: F) -> Self
+        where F: Fn(&AttemptOutcome<'_>) -> bool + 'static
+    {
+        self.0.retry_limits.push((Box::new(f), retries));
+        self
+    }
+
+    /// Retries responses with `status` up to `retries` times, see
+    /// [`outcome_retries`](Retry::outcome_retries)
+    pub fn status_retries(self, status: StatusCode, retries: u8) -> Self {
+        self.outcome_retries(retries, move |outcome| matches!(outcome, AttemptOutcome::Response(res) if res.status() == status))
+    }
+
+    /// Registers a hook which can cancel a retry before its backoff and again right before it's
+    /// sent.
+    ///
+    /// `f` gets the context of the attempt which is about to be retried, returning true vetoes
+    /// the retry and that attempt's outcome is returned to the caller straight away. Use it to
+    /// stop retrying when conditions changed since the retry was scheduled, e.g. the service
+    /// started shutting down.
+    ///
+    /// ```
+    /// use std::cell::Cell;
+    /// use std::rc::Rc;
+    /// use awc_retry::Retry;
+    ///
+    /// let shutting_down = Rc::new(Cell::new(false));
+    ///
+    /// let flag = shutting_down.clone();
+    /// let retry = Retry::new(3).veto(move |_ctx| flag.get());
+    /// ```
+    pub fn veto<F>(mut self, f: F) -> Self
+        where F: Fn(&RetryContext<'_>) -> bool + 'static
+    {
+        self.0.vetoes.push(Box::new(f));
+        self
+    }
+
+    /// Retries the given redirect statuses against the same URI, without following `Location`.
+    ///
+    /// Some load balancers answer with a 307 or 308 pointing back at the same URI while they
+    /// fail over. This treats those responses like any other retryable response, it is separate
+    /// from the redirect following done by the client. Only `3xx` codes are accepted by
+    /// [`validate`](Retry::validate).
+    ///
+    /// ```
+    /// use actix_http::http::StatusCode;
+    /// use awc_retry::Retry;
+    ///
+    /// let retry = Retry::new(3)
+    ///     .retry_redirects(vec![StatusCode::TEMPORARY_REDIRECT, StatusCode::PERMANENT_REDIRECT]);
+    /// ```
+    pub fn retry_redirects(mut self, statuses: Vec<StatusCode>) -> Self {
+        self.0.retry_redirects.extend(statuses);
+        self
+    }
+
+    /// Adds a retry policy which only applies to responses negotiated over `version`,
+    /// see [`RetryPolicy::for_version`]. Stateful policies can check [`RetryContext::version`].
+    pub fn policy_for_version<T>(self, version: Version, p: T) -> Self
+        where T: IntoRetryPolicy
+    {
+        self.policy(p.into_policy().for_version(version))
+    }
+
+    /// Copies extensions of type `T` to the request of every retry.
+    ///
+    /// By default retries of an owned request head are sent without its extensions, see
+    /// [`head::HeadCloner`].
+    pub fn clone_extension<T: Clone + 'static>(mut self) -> Self {
+        self.0.head_cloner = self.0.head_cloner.extension::<T>();
+        self
+    }
+
+    /// Keeps waiting for the connector when it fails to become ready before a retry.
+    ///
+    /// By default such an error is returned straight away. When enabled each failed readiness
+    /// check uses up one retry, so a connector which stays broken still ends the request once
+    /// the retries run out.
+    pub fn retry_readiness_errors(mut self, enabled: bool) -> Self {
+        self.0.retry_readiness_errors = enabled;
+        self
+    }
+
+    /// Turns the configuration into a handle which can wrap any number of clients.
+    ///
+    /// All clients wrapped with clones of the handle use the same configuration instance rather
+    /// than a copy of it. The handle is `!Send`, so it's shared between the clients of one worker.
+    ///
+    /// ```
+    /// use awc_retry::Retry;
+    ///
+    /// let retry = Retry::new(3).shared();
+    ///
+    /// let api = awc::Client::builder().wrap(retry.clone()).finish();
+    /// let auth = awc::Client::builder().wrap(retry).finish();
+    /// ```
+    pub fn shared(self) -> SharedRetry {
+        SharedRetry(Rc::new(self.0))
+    }
+
+    /// Checks for configurations which would silently misbehave at runtime.
+    ///
+    /// ```
+    /// use awc_retry::{ConfigError, Retry};
+    ///
+    /// let retry = Retry::new(3).policy(vec![]);
+    /// assert_eq!(retry.validate(), Err(ConfigError::EmptyStatusList));
+    /// ```
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let inner = &self.0;
+
+        for policy in inner.policies.iter() {
+            policy.validate()?;
+            if inner.streaming_safe && policy.buffers_body() {
+                return Err(ConfigError::BufferingPolicy);
+            }
+        }
+        if inner.streaming_safe && inner.integrity.is_some() {
+            return Err(ConfigError::BufferingPolicy);
+        }
+
+        if let Some(status) = inner.retry_redirects.iter().find(|s| !s.is_redirection()) {
+            return Err(ConfigError::NotARedirect(*status));
+        }
+
+        if inner.jitter.is_some() && inner.backoff.is_none() && inner.class_backoffs.is_empty() {
+            return Err(ConfigError::JitterWithoutBackoff);
+        }
+
+        if let Some(method) = inner.policies.unkeyed_method().filter(|_| inner.idempotency_key.is_none()) {
+            return Err(ConfigError::UnkeyedMethod(method));
+        }
+
+        if inner.max_retries == 0 && inner.retry_limits.iter().all(|(_, retries)| *retries == 0) {
+            if !inner.connectors.is_empty() || inner.downgrade.is_some() {
+                return Err(ConfigError::ConnectorsWithoutRetries);
+            }
+            if inner.shadow.is_some() {
+                return Err(ConfigError::ShadowWithoutRetries);
+            }
+            if inner.failover.is_some() {
+                return Err(ConfigError::FailoverWithoutRetries);
+            }
+        }
+
+        Ok(())
+    }
+
     /// Allows you to add a retry policy to the [`policies`]
-    /// It allows two types of policy:
+    /// It allows three types of policy:
     ///  - `Vec<StatusCode>` and will retry if one of them is received
     ///  - `Fn(&ResponseHead) -> bool` and will retry when this function resolves to false
+    ///  - [`RetryPolicy::response`] which gets the whole [`ClientResponse`] and will retry when it resolves to false
     ///
     /// # example
     ///
@@ -80,32 +931,82 @@ impl Retry {
     pub fn policy<T>(mut self, p: T) -> Self
         where T: IntoRetryPolicy
     {
-        self.0.policies.push(p.into_policy());
+        let policy = p.into_policy();
+        self.0.policies.push(policy.default_priority(), policy);
         self
     }
-}
 
-#[non_exhaustive]
-pub enum RetryPolicy {
-    Status(Vec<StatusCode>),
-    Custom(Box<dyn Fn(&ResponseHead) -> bool>),
-}
+    /// Sets whether a response is retried when any of the policies rejects it, the default, or
+    /// only when all of them do.
+    ///
+    /// ```
+    /// use actix_http::http::StatusCode;
+    /// use actix_web::dev::ResponseHead;
+    /// use awc_retry::{Combine, Retry};
+    ///
+    /// // Retries 503s, but only those which didn't come from the origin itself
+    /// let retry = Retry::new(3)
+    ///     .policy(StatusCode::SERVICE_UNAVAILABLE)
+    ///     .policy(|head: &ResponseHead| head.headers().contains_key("x-origin"))
+    ///     .combine_policies(Combine::All);
+    /// ```
+    pub fn combine_policies(mut self, combine: Combine) -> Self {
+        self.0.policies.combine = combine;
+        self
+    }
 
-pub trait IntoRetryPolicy {
-    fn into_policy(self) -> RetryPolicy;
-}
+    /// Only retries requests with one of `methods`, whatever the other policies decide, a
+    /// shorthand for a [`RetryPolicy::methods`] policy.
+    ///
+    /// ```
+    /// use actix_http::http::Method;
+    /// use awc_retry::Retry;
+    ///
+    /// let retry = Retry::new(3)
+    ///     .policy(500..=599)
+    ///     .methods(vec![Method::GET, Method::HEAD, Method::PUT, Method::DELETE]);
+    /// ```
+    pub fn methods<I: IntoIterator<Item=Method>>(self, methods: I) -> Self {
+        self.policy(RetryPolicy::methods(methods))
+    }
 
-impl<T> IntoRetryPolicy for T
-    where T: for<'a> Fn(&'a ResponseHead) -> bool + 'static
-{
-    fn into_policy(self) -> RetryPolicy {
-        RetryPolicy::Custom(Box::new(self))
+    /// Never retries a `POST` or `PATCH` which doesn't carry `header`, usually
+    /// `Idempotency-Key`, so it fails on its first error instead. A shorthand for a
+    /// [`RetryPolicy::idempotency_key`] policy.
+    ///
+    /// [`validate`](Retry::validate) rejects method policies which retry `POST` or `PATCH`
+    /// without it or [`generate_idempotency_keys`](Retry::generate_idempotency_keys).
+    ///
+    /// ```
+    /// use actix_http::http::header::HeaderName;
+    /// use awc_retry::Retry;
+    ///
+    /// let retry = Retry::new(3)
+    ///     .policy(500..=599)
+    ///     .require_idempotency_key(HeaderName::from_static("idempotency-key"));
+    /// ```
+    pub fn require_idempotency_key(self, header: HeaderName) -> Self {
+        self.policy(RetryPolicy::idempotency_key(header))
     }
-}
 
-impl IntoRetryPolicy for Vec<StatusCode> {
-    fn into_policy(self) -> RetryPolicy {
-        RetryPolicy::Status(self)
+    /// Adds a retry policy which is checked in order of `priority`, lowest first.
+    ///
+    /// Checking stops at the first policy deciding the verdict on a response, so cheap policies
+    /// should get a low priority. Without a priority status lists get `0` and custom policies `100`.
+    ///
+    /// ```
+    /// use actix_web::HttpMessage;
+    /// use awc::ClientResponse;
+    /// use awc_retry::{Retry, RetryPolicy};
+    ///
+    /// let retry = Retry::new(3)
+    ///     .policy_with_priority(RetryPolicy::response(|res: &ClientResponse| res.content_type() != "text/html"), 200);
+    /// ```
+    pub fn policy_with_priority<T>(mut self, p: T, priority: i32) -> Self
+        where T: IntoRetryPolicy
+    {
+        self.0.policies.push(priority, p.into_policy());
+        self
     }
 }
 
@@ -115,9 +1016,24 @@ impl<S> Transform<S, ConnectRequest> for Retry
 {
     type Transform = RetryService<S>;
 
+    fn new_transform(self, service: S) -> Self::Transform {
+        self.shared().new_transform(service)
+    }
+}
+
+/// A [`Retry`] configuration which can be attached to several clients, see [`Retry::shared`]
+#[derive(Clone)]
+pub struct SharedRetry(Rc<Inner>);
+
+impl<S> Transform<S, ConnectRequest> for SharedRetry
+    where
+        S: Service<ConnectRequest, Response=ConnectResponse, Error=SendRequestError> + 'static,
+{
+    type Transform = RetryService<S>;
+
     fn new_transform(self, service: S) -> Self::Transform {
         RetryService {
-            inner: Rc::new(self.0),
+            inner: self.0,
             connector: Rc::new(service),
         }
     }
@@ -128,6 +1044,16 @@ pub struct RetryService<S> {
     connector: Rc<S>,
 }
 
+/// Clones share the configuration and the wrapped connector
+impl<S> Clone for RetryService<S> {
+    fn clone(&self) -> Self {
+        RetryService {
+            inner: self.inner.clone(),
+            connector: self.connector.clone(),
+        }
+    }
+}
+
 impl<S> Service<ConnectRequest> for RetryService<S>
     where
         S: Service<ConnectRequest, Response=ConnectResponse, Error=SendRequestError> + 'static,
@@ -145,116 +1071,587 @@ impl<S> Service<ConnectRequest> for RetryService<S>
         let inner = self.inner.clone();
 
         Box::pin(async move {
-            let mut tries = 0;
             match req {
-                ConnectRequest::Client(head, body, addr) => {
-                    match body {
-                        Body::Bytes(b) => {
-                            println!("{}", "Bytes received");
-                            loop {
-                                let h = clone_request_head_type(&head);
-
-                                match connector.call(ConnectRequest::Client(h, Body::Bytes(b.clone()), addr)).await
-                                {
-                                    Ok(res) => {
-                                        // ConnectResponse
-                                        match &res {
-                                            ConnectResponse::Client(ref r) => {
-                                                // TODO: Need to work out how to get the ResponseHead
-                                                if inner.is_valid_response(&ResponseHead::new(StatusCode::OK)) {
-                                                    return Ok(res);
-                                                } else {
-                                                    tries += 1;
-                                                }
-                                            }
-                                            ConnectResponse::Tunnel(ref head, _) => {
-                                                if inner.is_valid_response(head) {
-                                                    tries += 1;
-                                                } else {
-                                                    tries += 1;
-                                                }
-                                            }
-                                        };
+                ConnectRequest::Client(mut head, body, addr) => {
+                    let body = match body {
+                        Body::Bytes(b) => ReplayBody::Bytes(b),
+                        Body::Empty => ReplayBody::Empty,
+                        Body::None => ReplayBody::None,
+                        // Streaming bodies are consumed by the first attempt so can't be retried
+                        body => return connector.call(ConnectRequest::Client(head, body, addr)).await,
+                    };
 
-                                        return Ok(res);
-                                    }
-                                    // SendRequestError
-                                    Err(e) => {
-                                        if tries == inner.max_retries {
-                                            return Err(e);
-                                        } else {
-                                            tries += 1;
-                                        }
+                    if let Some(name) = &inner.idempotency_key {
+                        idempotency::ensure_key(&mut head, name);
+                    }
+                    let mut attempts = Attempts::new(&inner);
+                    attempts.sends(&head);
+                    attempts.resends(&body);
+                    let target = Target(&head);
+                    // 0 is the wrapped connector, n is `inner.connectors[n - 1]`
+                    let mut current = 0;
+                    let (mut credential, mut used_credentials) = (None, 0);
+                    // HTTP/2 failures in a row, and whether the request moved to HTTP/1.1
+                    let (mut h2_failures, mut downgraded) = (0, false);
+                    // How the latest attempt was sent, so a resumed download asks the same way
+                    let mut sent = None;
+                    let result = loop {
+                        // Let retries queue behind the connector's backpressure (e.g. an exhausted
+                        // pool) instead of piling onto it, the first attempt was readied by the caller
+                        if attempts.tries() > 0 {
+                            let ready = match (inner.downgrade.as_ref().filter(|_| downgraded), current) {
+                                (Some(downgrade), _) => poll_fn(|cx| downgrade.connector.poll_ready(cx)).await,
+                                (None, 0) => poll_fn(|cx| connector.poll_ready(cx)).await,
+                                (None, n) => poll_fn(|cx| inner.connectors[n - 1].poll_ready(cx)).await,
+                            };
+                            if let Err(e) = ready {
+                                let error = format_args!("readiness error: {}", e);
+                                if let Some(delay) = attempts.schedule(inner.retry_readiness_errors, None, Some(&AttemptOutcome::Error(&e)), &target, &error) {
+                                    if attempts.wait(delay, None, &target, &error).await {
+                                        continue;
                                     }
                                 }
+                                break Err(e);
                             }
                         }
-                        Body::Empty => {
-                            loop {
-                                let h = clone_request_head_type(&head);
-
-                                match connector.call(ConnectRequest::Client(h, Body::Empty, addr)).await
-                                {
-                                    Ok(res) => return Ok(res),
-                                    Err(e) => {
-                                        println!("{}", e);
-                                        if tries == inner.max_retries {
-                                            return Err(e);
-                                        } else {
-                                            tries += 1;
-                                        }
+
+                        if let Err(e) = attempts.permit().await {
+                            break Err(e);
+                        }
+
+                        let attempt_addr = match (&inner.failover, attempts.tries()) {
+                            (Some(failover), tries) if tries > 0 => failover.addr(tries - 1, inner.outliers.as_ref(), inner.timer.now()).await.or(addr),
+                            _ => addr,
+                        };
+                        let mut attempt_head = inner.head_cloner.clone_head(&head);
+                        if let Some(value) = &credential {
+                            credentials::authorize(&mut attempt_head, HeaderValue::clone(value));
+                        }
+                        let downgrade = inner.downgrade.as_ref().filter(|_| downgraded);
+                        if downgrade.is_some() {
+                            downgrade::adjust(&mut attempt_head);
+                        }
+                        if inner.resume.is_some() {
+                            sent = Some((head::clone_request_head_type(&attempt_head), attempt_addr, downgraded, current));
+                        }
+                        let req = ConnectRequest::Client(attempt_head, body.to_body(), attempt_addr);
+                        attempts.connects(attempt_addr);
+                        attempts.start();
+                        let res = attempts.bounded(async {
+                            match (downgrade, current) {
+                                (Some(downgrade), _) => downgrade.connector.call(req).await,
+                                (None, 0) => connector.call(req).await,
+                                (None, n) => inner.connectors[n - 1].call(req).await,
+                            }
+                        }).await;
+
+                        let res = match res {
+                            Ok(ConnectResponse::Client(res)) => Ok(res),
+                            Ok(res) => break Ok(res),
+                            Err(e) => Err(e),
+                        };
+
+                        let next_credential = match &res {
+                            Ok(res) if credentials::Credentials::refused(res) => inner.credentials.next(&mut used_credentials).await,
+                            _ => None,
+                        };
+                        let step = match res {
+                            Ok(res) if next_credential.is_some() => attempts.judge_refused(res, &target),
+                            res => attempts.judge(res, &target).await,
+                        };
+
+                        match step {
+                            Step::Retry(outcome, delay) => {
+                                if next_credential.is_some() {
+                                    if !attempts.retry(&outcome, delay, &target).await {
+                                        break outcome.into_result().map(ConnectResponse::Client);
                                     }
+                                    log::debug!("sending {} with the next credential after {}", target, outcome);
+                                    credential = next_credential;
+                                    continue;
                                 }
-                            }
-                        }
-                        _ => {
-                            loop {
-                                let h = clone_request_head_type(&head);
-
-                                match connector.call(ConnectRequest::Client(h, Body::None, addr)).await
-                                {
-                                    Ok(res) => {
-                                        /// This is [ConnectResponse]
-                                        return Ok(res);
+                                h2_failures = match &outcome {
+                                    Outcome::Error(e) if downgrade::is_h2_failure(e) => h2_failures + 1,
+                                    _ => 0,
+                                };
+                                if !downgraded && inner.downgrade.as_ref().is_some_and(|d| d.applies(h2_failures)) {
+                                    log::debug!("sending {} over HTTP/1.1 after {} HTTP/2 failures", target, h2_failures);
+                                    downgraded = true;
+                                }
+                                if let Outcome::Error(e) = &outcome {
+                                    if current < inner.connectors.len() && (inner.switch_connector)(e) {
+                                        current += 1;
                                     }
-                                    Err(e) => {
-                                        if tries == inner.max_retries {
-                                            return Err(e);
-                                        } else {
-                                            tries += 1;
+
+                                    // The failed connection is gone, open the next one while backing off
+                                    if let Some(method) = inner.prewarm.as_ref().filter(|_| delay > Duration::from_secs(0)) {
+                                        let mut warm = head::owned_head(&head);
+                                        warm.method = method.clone();
+                                        let mut warm = RequestHeadType::Owned(warm);
+                                        if downgraded {
+                                            downgrade::adjust(&mut warm);
                                         }
+                                        let warm_addr = match &inner.failover {
+                                            Some(failover) => failover.addr(attempts.tries(), inner.outliers.as_ref(), inner.timer.now()).await.or(addr),
+                                            None => addr,
+                                        };
+                                        let req = ConnectRequest::Client(warm, Body::None, warm_addr);
+                                        let (connector, inner) = (connector.clone(), inner.clone());
+                                        actix_rt::spawn(async move {
+                                            let _ = match (inner.downgrade.as_ref().filter(|_| downgraded), current) {
+                                                (Some(downgrade), _) => downgrade.connector.call(req).await,
+                                                (None, 0) => connector.call(req).await,
+                                                (None, n) => inner.connectors[n - 1].call(req).await,
+                                            };
+                                        });
+                                    }
+                                }
+                                if !attempts.retry(&outcome, delay, &target).await {
+                                    break outcome.into_result().map(ConnectResponse::Client);
+                                }
+
+                                if let Some(probe) = inner.probe.as_ref().filter(|p| p.applies_to(&body)) {
+                                    let probe_addr = match &inner.failover {
+                                        Some(failover) => failover.addr(attempts.tries() - 1, inner.outliers.as_ref(), inner.timer.now()).await.or(addr),
+                                        None => addr,
+                                    };
+                                    let mut probe_head = probe.head(&head);
+                                    let downgrade = inner.downgrade.as_ref().filter(|_| downgraded);
+                                    if downgrade.is_some() {
+                                        downgrade::adjust(&mut probe_head);
+                                    }
+                                    let req = ConnectRequest::Client(probe_head, Body::None, probe_addr);
+                                    let res = match (downgrade, current) {
+                                        (Some(downgrade), _) => downgrade.connector.call(req).await,
+                                        (None, 0) => connector.call(req).await,
+                                        (None, n) => inner.connectors[n - 1].call(req).await,
+                                    };
+                                    if !probe::Probe::passed(&res) {
+                                        log::debug!("not retrying {}, its probe failed", target);
+                                        attempts.audit("return", "probe_failed", None, &target, &outcome);
+                                        break outcome.into_result().map(ConnectResponse::Client);
                                     }
                                 }
                             }
+                            Step::Done(outcome) => break outcome.into_result().map(ConnectResponse::Client),
+                        }
+                    };
+
+                    let result = match result {
+                        Err(e) => match inner.fallback.as_ref().and_then(|f| f(head.as_ref(), &e)) {
+                            Some(res) => {
+                                log::debug!("using fallback response for {} after error: {}", target, e);
+                                Ok(ConnectResponse::Client(res))
+                            }
+                            None => Err(e),
+                        },
+                        res => res,
+                    };
+
+                    let result = match (result, &inner.resume, sent) {
+                        (Ok(ConnectResponse::Client(res)), Some(resume), Some((sent_head, sent_addr, downgraded, current))) => {
+                            let (connector, via) = (connector.clone(), inner.clone());
+                            let send: Rc<resume::SendFn> = Rc::new(move |req| {
+                                let (connector, inner) = (connector.clone(), via.clone());
+                                Box::pin(async move {
+                                    match (inner.downgrade.as_ref().filter(|_| downgraded), current) {
+                                        (Some(downgrade), _) => downgrade.connector.call(req).await,
+                                        (None, 0) => connector.call(req).await,
+                                        (None, n) => inner.connectors[n - 1].call(req).await,
+                                    }
+                                })
+                            });
+                            Ok(ConnectResponse::Client(resume.wrap(res, sent_head, sent_addr, send, attempts.retries_left())))
+                        }
+                        (result, ..) => result,
+                    };
+
+                    let result = match result {
+                        Ok(ConnectResponse::Client(res)) => inner.finish(Ok(res)).map(ConnectResponse::Client),
+                        Err(e) => inner.finish(Err(e)).map(ConnectResponse::Client),
+                        res => res,
+                    };
+
+                    if attempts.tries() > 0 {
+                        if let Some(shadow) = inner.shadow.as_ref().filter(|s| s.sample()) {
+                            // The address of the original would send the mirror back to it
+                            let req = ConnectRequest::Client(shadow.head(&head), body.to_body(), None);
+                            // Fire-and-forget, the mirrored response is never looked at
+                            actix_rt::spawn(async move {
+                                let _ = connector.call(req).await;
+                            });
                         }
                     }
+
+                    result
                 }
                 ConnectRequest::Tunnel(head, addr) => {
-                    match connector.call(ConnectRequest::Tunnel(head, addr)).await {
-                        Ok(r) => Ok(r),
-                        Err(e) => Err(e)
-                    }
+                    connector.call(ConnectRequest::Tunnel(head, addr)).await
                 }
             }
         })
     }
 }
 
-/// Clones [RequestHeadType] except for the extensions (not required for this middleware)
-fn clone_request_head_type(head_type: &RequestHeadType) -> RequestHeadType {
-    match head_type {
-        RequestHeadType::Owned(h) => {
-            let mut inner_head = RequestHead::default();
-            inner_head.uri = h.uri.clone();
-            inner_head.method = h.method.clone();
-            inner_head.version = h.version.clone();
-            inner_head.peer_addr = h.peer_addr.clone();
-            inner_head.headers = h.headers.clone();
-
-            RequestHeadType::Owned(inner_head)
+/// A request body which can be sent more than once
+enum ReplayBody {
+    Bytes(Bytes),
+    Empty,
+    None,
+}
+
+impl ReplayBody {
+    fn to_body(&self) -> Body {
+        match self {
+            ReplayBody::Bytes(b) => Body::Bytes(b.clone()),
+            ReplayBody::Empty => Body::Empty,
+            ReplayBody::None => Body::None,
+        }
+    }
+}
+
+/// Method and URI of a request, for log lines
+struct Target<'a>(&'a RequestHeadType);
+
+impl fmt::Display for Target<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let head = self.0.as_ref();
+        write!(f, "{} {}", head.method, head.uri)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::timer::MockTimer;
+    use actix_service::fn_service;
+    use awc::error::ConnectError;
+    use awc::test::TestResponse;
+    use futures::future::ready;
+    use std::cell::{Cell, RefCell};
+    use std::io;
+    use std::net::SocketAddr;
+
+    /// A request as it reached the connector
+    struct Sent {
+        head: RequestHead,
+        addr: Option<SocketAddr>,
+    }
+
+    /// A connector answering its `n`th request with `answer(n, request)`, and the requests it got
+    fn connector<F>(answer: F) -> (impl Service<ConnectRequest, Response=ConnectResponse, Error=SendRequestError> + Clone, Rc<RefCell<Vec<Sent>>>)
+        where F: Fn(usize, &RequestHead) -> Result<ClientResponse, SendRequestError> + 'static
+    {
+        let (sent, answer) = (Rc::new(RefCell::new(Vec::new())), Rc::new(answer));
+        let log = sent.clone();
+        let connector = fn_service(move |req: ConnectRequest| {
+            let res = match req {
+                ConnectRequest::Client(head, _, addr) => {
+                    let head = head::owned_head(&head);
+                    let res = answer(log.borrow().len(), &head);
+                    log.borrow_mut().push(Sent { head, addr });
+                    res.map(ConnectResponse::Client)
+                }
+                ConnectRequest::Tunnel(..) => unreachable!("no tunnels are opened"),
+            };
+            ready(res)
+        });
+        (connector, sent)
+    }
+
+    /// Sends `head` through `retry` to a [`connector`] answering with `answer`, returning the
+    /// outcome and the requests the connector got
+    fn send<F>(retry: Retry, head: RequestHead, answer: F) -> (Result<ClientResponse, SendRequestError>, Vec<Sent>)
+        where F: Fn(usize, &RequestHead) -> Result<ClientResponse, SendRequestError> + 'static
+    {
+        let (mut res, sent) = send_each(retry, vec![(head, Body::None)], answer);
+        (res.remove(0), sent)
+    }
+
+    /// [`send`] for several requests in turn through the same service
+    fn send_each<F>(retry: Retry, reqs: Vec<(RequestHead, Body)>, answer: F) -> (Vec<Result<ClientResponse, SendRequestError>>, Vec<Sent>)
+        where F: Fn(usize, &RequestHead) -> Result<ClientResponse, SendRequestError> + 'static
+    {
+        let (connector, sent) = connector(answer);
+        let service = retry.new_transform(connector);
+        let res = actix_rt::System::new().block_on(async {
+            let mut res = Vec::new();
+            for (head, body) in reqs {
+                let req = ConnectRequest::Client(RequestHeadType::Owned(head), body, None);
+                res.push(service.call(req).await.map(|res| match res {
+                    ConnectResponse::Client(res) => res,
+                    ConnectResponse::Tunnel(..) => unreachable!("no tunnels are opened"),
+                }));
+            }
+            res
+        });
+        let sent = sent.take();
+        (res, sent)
+    }
+
+    fn get(uri: &str) -> RequestHead {
+        let mut head = RequestHead::default();
+        head.uri = uri.parse().unwrap();
+        head
+    }
+
+    /// A response with `status` and `headers`
+    fn response(status: StatusCode, headers: &[(&'static str, &str)]) -> Result<ClientResponse, SendRequestError> {
+        let res = headers.iter().fold(TestResponse::default(), |res, (name, value)| res.header(*name, *value));
+        Ok(res.finish().map_body(|head, payload| {
+            head.status = status;
+            payload
+        }))
+    }
+
+    #[test]
+    fn huge_retry_after_gives_up_at_the_deadline() {
+        let retry = Retry::new(3)
+            .policy(vec![StatusCode::SERVICE_UNAVAILABLE])
+            .respect_retry_after(true)
+            .total_deadline(Duration::from_secs(10))
+            .timer(MockTimer::new());
+        let (res, sent) = send(retry, get("http://example.com/"), |_, _| {
+            response(StatusCode::SERVICE_UNAVAILABLE, &[("retry-after", "18446744073709551615")])
+        });
+        assert_eq!(res.unwrap().status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(sent.len(), 1);
+        assert_eq!((sent[0].head.uri.path(), sent[0].addr), ("/", None));
+    }
+
+    fn authorization(sent: &Sent) -> Option<&str> {
+        sent.head.headers.get("authorization").map(|v| v.to_str().unwrap())
+    }
+
+    fn refused_until_next_key(_: usize, head: &RequestHead) -> Result<ClientResponse, SendRequestError> {
+        match head.headers.get("authorization") {
+            Some(key) if key == "Bearer next-key" => response(StatusCode::OK, &[]),
+            _ => response(StatusCode::UNAUTHORIZED, &[]),
+        }
+    }
+
+    fn with_next_key(retry: Retry) -> Retry {
+        retry.fallback_credentials(vec![HeaderValue::from_static("Bearer next-key")]).timer(MockTimer::new())
+    }
+
+    #[test]
+    fn refused_credentials_are_rotated() {
+        let (res, sent) = send(with_next_key(Retry::new(1)), get("http://example.com/"), refused_until_next_key);
+        assert_eq!(res.unwrap().status(), StatusCode::OK);
+        assert_eq!(sent.iter().map(authorization).collect::<Vec<_>>(), [None, Some("Bearer next-key")]);
+    }
+
+    #[test]
+    fn credentials_are_only_rotated_where_retries_are_allowed() {
+        let retries = vec![
+            Retry::new(1).policy(Policy::hosts(vec!["other.example.com"])),
+            Retry::new(1).dry_run(true),
+            Retry::new(1).veto(|_| true),
+            Retry::new(0),
+        ];
+        for retry in retries {
+            let (res, sent) = send(with_next_key(retry), get("http://example.com/"), refused_until_next_key);
+            assert_eq!(res.unwrap().status(), StatusCode::UNAUTHORIZED);
+            assert_eq!(sent.len(), 1);
+        }
+    }
+
+    #[test]
+    fn refused_credentials_leave_the_host_healthy() {
+        let breaker = HostBreaker::new(1, Duration::from_secs(30));
+        let handle = breaker.handle();
+        let retry = with_next_key(Retry::new(1).host_breaker_with(breaker));
+        let (res, _) = send(retry, get("http://example.com/"), refused_until_next_key);
+        assert_eq!(res.unwrap().status(), StatusCode::OK);
+        assert_eq!(handle.status("example.com").state, CircuitState::Closed);
+    }
+
+    #[test]
+    fn vetoed_retries_leave_the_bandwidth_untouched() {
+        let vetoes = Rc::new(Cell::new(1u32));
+        let left = vetoes.clone();
+        let retry = Retry::new(1)
+            .policy(vec![StatusCode::SERVICE_UNAVAILABLE])
+            .resend_bandwidth(10, Duration::from_secs(60))
+            .veto(move |_| left.replace(left.get().saturating_sub(1)) > 0)
+            .timer(MockTimer::new());
+        let upload = || (get("http://example.com/"), Body::from_slice(b"0123456789"));
+        let (res, sent) = send_each(retry, vec![upload(), upload()], |n, _| match n {
+            0 | 1 => response(StatusCode::SERVICE_UNAVAILABLE, &[]),
+            _ => response(StatusCode::OK, &[]),
+        });
+        let statuses = res.into_iter().map(|res| res.unwrap().status()).collect::<Vec<_>>();
+        assert_eq!(statuses, [StatusCode::SERVICE_UNAVAILABLE, StatusCode::OK]);
+        assert_eq!((sent.len(), vetoes.get()), (3, 0));
+    }
+
+    #[test]
+    fn configs_share_the_circuits_of_cloned_breakers() {
+        let breaker = HostBreaker::new(1, Duration::from_secs(30));
+        let handle = breaker.handle();
+        let reads = Retry::new(0).policy(vec![StatusCode::SERVICE_UNAVAILABLE]).host_breaker_with(breaker.clone());
+        let writes = Retry::new(0).host_breaker_with(breaker);
+
+        let (res, _) = send(reads, get("http://example.com/"), |_, _| response(StatusCode::SERVICE_UNAVAILABLE, &[]));
+        assert_eq!(res.unwrap().status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(handle.status("example.com").state, CircuitState::Open);
+
+        let (res, sent) = send(writes, get("http://example.com/"), |_, _| response(StatusCode::OK, &[]));
+        assert!(CircuitOpen::matches(&res.unwrap_err()));
+        assert!(sent.is_empty());
+    }
+
+    /// Four bytes of `0123456789` from the `Range` of `head`, cut short of the ten declared
+    fn cut_short(_: usize, head: &RequestHead) -> Result<ClientResponse, SendRequestError> {
+        let from = head.headers.get("range").map_or(0, |range| range.to_str().unwrap()["bytes=".len()..].trim_end_matches('-').parse().unwrap());
+        let part = &b"0123456789"[from..(from + 4).min(10)];
+        let (status, range) = match from {
+            0 => (StatusCode::OK, None),
+            _ => (StatusCode::PARTIAL_CONTENT, Some(format!("bytes {}-{}/10", from, from + part.len() - 1))),
+        };
+        let mut res = TestResponse::default()
+            .header("etag", "\"v1\"")
+            .header("accept-ranges", "bytes")
+            .header("content-length", "10");
+        if let Some(range) = range {
+            res = res.header("content-range", range);
         }
-        RequestHeadType::Rc(h, header_map) => {
-            RequestHeadType::Rc(h.clone(), header_map.clone())
+        Ok(res.set_payload(part).finish().map_body(|head, payload| {
+            head.status = status;
+            payload
+        }))
+    }
+
+    #[test]
+    fn downloads_are_resumed_as_often_as_retries_are_left() {
+        let retry = |budget| Retry::new(3).resume_downloads(1).budget(Budget::new(0.0).capacity(budget)).timer(MockTimer::new());
+        for (budget, body) in [(3, Some("0123456789")), (1, None)] {
+            let (res, _) = send(retry(budget), get("http://example.com/file"), cut_short);
+            let read = actix_rt::System::new().block_on(res.unwrap().body());
+            assert_eq!(read.ok().as_deref(), body.map(str::as_bytes), "with a budget of {}", budget);
         }
     }
+
+    fn h2_failure(_: usize, _: &RequestHead) -> Result<ClientResponse, SendRequestError> {
+        Err(SendRequestError::H2(h2::Error::from(h2::Reason::PROTOCOL_ERROR)))
+    }
+
+    #[test]
+    fn connections_are_prewarmed_through_the_http1_fallback() {
+        let (http1, over_http1) = connector(|_, _| response(StatusCode::OK, &[]));
+        let retry = Retry::new(1)
+            .http1_fallback(http1, 1)
+            .prewarm_connection(Method::OPTIONS)
+            .delay(Duration::from_millis(1));
+        let (res, sent) = send(retry, get("http://example.com/"), h2_failure);
+        assert_eq!(res.unwrap().status(), StatusCode::OK);
+        assert_eq!(sent.len(), 1);
+        let over_http1 = over_http1.take().into_iter().map(|sent| (sent.head.method, sent.head.version)).collect::<Vec<_>>();
+        assert_eq!(over_http1, [(Method::OPTIONS, Version::HTTP_11), (Method::GET, Version::HTTP_11)]);
+    }
+
+    #[test]
+    fn probes_go_through_the_http1_fallback() {
+        let (http1, over_http1) = connector(|_, _| response(StatusCode::OK, &[]));
+        let retry = Retry::new(1)
+            .http1_fallback(http1, 1)
+            .probe_before_retry(1, None)
+            .timer(MockTimer::new());
+        let mut upload = get("http://example.com/");
+        upload.method = Method::PUT;
+        let (res, sent) = send_each(retry, vec![(upload, Body::from_slice(b"data"))], h2_failure);
+        assert_eq!(res.into_iter().map(|res| res.unwrap().status()).collect::<Vec<_>>(), [StatusCode::OK]);
+        assert_eq!(sent.len(), 1);
+        let over_http1 = over_http1.take().into_iter().map(|sent| (sent.head.method, sent.head.version)).collect::<Vec<_>>();
+        assert_eq!(over_http1, [(Method::HEAD, Version::HTTP_11), (Method::PUT, Version::HTTP_11)]);
+    }
+
+    #[test]
+    fn draining_returns_retries_held_back_for_the_quota() {
+        let retry = Retry::new(1)
+            .policy(RetryPolicy::response_header(HeaderName::from_static("x-retry"), HeaderValue::from_static("yes")))
+            .delay(Duration::from_secs(0))
+            .rate_limit_throttle(0, Duration::from_secs(60))
+            .shared();
+        let (connector, sent) = connector(|_, _| {
+            response(StatusCode::OK, &[("ratelimit-remaining", "0"), ("ratelimit-reset", "30"), ("x-retry", "yes")])
+        });
+        let service = retry.clone().new_transform(connector);
+        let res = actix_rt::System::new().block_on(async move {
+            actix_rt::spawn(async move {
+                actix_rt::time::sleep(Duration::from_millis(10)).await;
+                retry.drain(Duration::from_secs(1)).await;
+            });
+            let req = ConnectRequest::Client(RequestHeadType::Owned(get("http://example.com/")), Body::None, None);
+            service.call(req).await
+        });
+        assert!(matches!(res, Ok(ConnectResponse::Client(res)) if res.status() == StatusCode::OK));
+        assert_eq!(sent.borrow().len(), 1);
+    }
+
+    #[test]
+    fn requests_move_to_http1_after_repeated_http2_failures() {
+        let (http1, over_http1) = connector(|_, _| response(StatusCode::OK, &[]));
+        let retry = Retry::new(3).http1_fallback(http1, 2).timer(MockTimer::new());
+        let (res, sent) = send(retry, get("http://example.com/"), h2_failure);
+        assert_eq!(res.unwrap().status(), StatusCode::OK);
+        assert_eq!(sent.len(), 2);
+        let versions = over_http1.take().into_iter().map(|sent| sent.head.version).collect::<Vec<_>>();
+        assert_eq!(versions, [Version::HTTP_11]);
+    }
+
+    fn refused(_: usize, _: &RequestHead) -> Result<ClientResponse, SendRequestError> {
+        Err(SendRequestError::Connect(ConnectError::Io(io::ErrorKind::ConnectionRefused.into())))
+    }
+
+    #[test]
+    fn retries_rotate_through_the_failover_addresses() {
+        let addrs: Vec<SocketAddr> = vec!["10.0.0.2:8080".parse().unwrap(), "10.0.0.3:8080".parse().unwrap()];
+        let retry = Retry::new(3).failover_addrs(addrs.clone()).timer(MockTimer::new());
+        let (res, sent) = send(retry, get("http://example.com/"), refused);
+        assert!(res.is_err());
+        let sent_to = sent.iter().map(|sent| sent.addr).collect::<Vec<_>>();
+        assert_eq!(sent_to, [None, Some(addrs[0]), Some(addrs[1]), Some(addrs[0])]);
+    }
+
+    #[test]
+    fn retries_skip_ejected_failover_addresses() {
+        let addrs: Vec<SocketAddr> = vec!["10.0.0.2:8080".parse().unwrap(), "10.0.0.3:8080".parse().unwrap()];
+        let retry = Retry::new(1)
+            .failover_addrs(addrs.clone())
+            .outlier_ejection(1, Duration::from_secs(30))
+            .timer(MockTimer::new());
+        let request = || (get("http://example.com/"), Body::None);
+        let (res, sent) = send_each(retry, vec![request(), request()], |n, head| match n {
+            0..=2 => refused(n, head),
+            _ => response(StatusCode::OK, &[]),
+        });
+        assert!(res[0].is_err());
+        assert_eq!(res[1].as_ref().unwrap().status(), StatusCode::OK);
+        let sent_to = sent.iter().map(|sent| sent.addr).collect::<Vec<_>>();
+        assert_eq!(sent_to, [None, Some(addrs[0]), None, Some(addrs[1])]);
+    }
+
+    #[test]
+    fn retried_requests_are_mirrored_to_the_shadow_endpoint() {
+        let retry = Retry::new(1)
+            .policy(vec![StatusCode::SERVICE_UNAVAILABLE])
+            .shadow_retried(Uri::from_static("http://debug.internal:8080"), 100)
+            .timer(MockTimer::new());
+        let (connector, sent) = connector(|n, _| match n {
+            0 => response(StatusCode::SERVICE_UNAVAILABLE, &[]),
+            _ => response(StatusCode::OK, &[]),
+        });
+        let service = retry.new_transform(connector);
+        let res = actix_rt::System::new().block_on(async move {
+            let req = ConnectRequest::Client(RequestHeadType::Owned(get("http://example.com/items?page=2")), Body::None, None);
+            let res = service.call(req).await;
+            // The mirror is sent once the request is done
+            actix_rt::task::yield_now().await;
+            res
+        });
+        assert!(matches!(res, Ok(ConnectResponse::Client(res)) if res.status() == StatusCode::OK));
+        let uris = sent.take().into_iter().map(|sent| sent.head.uri.to_string()).collect::<Vec<_>>();
+        assert_eq!(uris, ["http://example.com/items?page=2", "http://example.com/items?page=2", "http://debug.internal:8080/items?page=2"]);
+    }
 }
@@ -1,5 +1,3 @@
-mod lib;
-
 fn main() {
     println!("Hello, world!");
 }
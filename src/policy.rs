@@ -0,0 +1,1362 @@
+use actix_http::http::header::{self, HeaderName};
+use actix_http::http::{HeaderMap, HeaderValue, Method, StatusCode, Version};
+use actix_http::RequestHeadType;
+use actix_web::dev::{RequestHead, ResponseHead};
+use actix_web::HttpMessage;
+use awc::error::{ConnectError, SendRequestError};
+use awc::ClientResponse;
+use futures::future::LocalBoxFuture;
+use std::io;
+use std::ops::RangeInclusive;
+use std::time::Duration;
+
+use crate::body::{self, Buffered};
+use crate::idempotency::KEYED_METHODS;
+use crate::ConfigError;
+
+/// Decides which responses are retried, built from the constructors and combinators below or
+/// from anything implementing [`IntoRetryPolicy`].
+///
+/// ```
+/// use actix_http::http::StatusCode;
+/// use awc_retry::{Policy, Retry};
+///
+/// let retry = Retry::new(3).policy(
+///     Policy::server_errors()
+///         .and(!Policy::status(StatusCode::NOT_IMPLEMENTED))
+///         .or(Policy::status(StatusCode::TOO_MANY_REQUESTS)),
+/// );
+/// ```
+#[non_exhaustive]
+pub enum RetryPolicy {
+    /// Retries responses with one of these statuses
+    Status(Vec<StatusCode>),
+    /// Retries responses whose status code falls in one of these ranges, see
+    /// [`RetryPolicy::status_range`]
+    StatusRanges(Vec<RangeInclusive<u16>>),
+    /// Retries responses whose head the function returns false for, what a policy closure
+    /// given to [`Retry::policy`](crate::Retry::policy) becomes
+    Custom(Box<dyn Fn(&ResponseHead) -> bool>),
+    /// Like [`RetryPolicy::Custom`] but the verdict is awaited, so it can consult other systems
+    Async(Box<AsyncFn>),
+    /// Like [`RetryPolicy::Custom`] but also given the request, see [`RetryPolicy::exchange`]
+    Exchange(Box<ExchangeFn>),
+    /// Retries responses the function returns false for, with the typed accessors, version and
+    /// extensions of the whole response at hand, see [`RetryPolicy::response`]
+    Response(Box<dyn Fn(&ClientResponse) -> bool>),
+    /// Like [`RetryPolicy::Response`] but also told where the request stands, see
+    /// [`RetryPolicy::response_with_context`]
+    ResponseWithContext(Box<ContextFn>),
+    /// Only applies the inner policy to responses of this HTTP version
+    Version(Version, Box<RetryPolicy>),
+    /// Retries responses whose `Content-Type` is none of these, see [`RetryPolicy::content_type`]
+    ContentType(Vec<String>),
+    /// Retries XML error bodies with one of these codes, see [`RetryPolicy::xml_error_code`]
+    XmlErrorCode(Vec<String>),
+    /// Retries responses whose body, of at most this many bytes, the function returns false
+    /// for, see [`RetryPolicy::body`]
+    Body(usize, Box<BodyFn>),
+    /// Retries responses where the header has this value, see [`RetryPolicy::response_header`]
+    ResponseHeader(HeaderName, HeaderValue),
+    /// Retries the errors the function returns true for, see [`RetryPolicy::error`]
+    Error(Box<dyn Fn(&SendRequestError) -> bool>),
+    /// Retries when both policies would, see [`RetryPolicy::and`]
+    And(Box<RetryPolicy>, Box<RetryPolicy>),
+    /// Retries when either policy would, see [`RetryPolicy::or`]
+    Or(Box<RetryPolicy>, Box<RetryPolicy>),
+    /// Retries when the policy wouldn't, see the [`Not`](std::ops::Not) implementation
+    Not(Box<RetryPolicy>),
+    /// Retries requests with one of these methods, see [`RetryPolicy::methods`]
+    Methods(Vec<Method>),
+    /// Retries requests carrying this header, see [`RetryPolicy::idempotency_key`]
+    RequestHeader(HeaderName),
+    /// Retries requests whose headers the function returns true for, see
+    /// [`RetryPolicy::request_headers`]
+    RequestHeaders(Box<dyn Fn(&HeaderMap) -> bool>),
+    /// Retries requests to one of these hosts, see [`RetryPolicy::hosts`]
+    Hosts(Vec<String>),
+    /// Retries requests whose path starts with this one, see [`RetryPolicy::path_prefix`]
+    PathPrefix(String),
+    /// Retries requests whose path matches this glob, see [`RetryPolicy::path_glob`]
+    PathGlob(String),
+    /// Retries requests whose URI matches this regex, see [`RetryPolicy::uri_regex`]
+    #[cfg(feature = "regex")]
+    UriRegex(regex::Regex),
+}
+
+type ContextFn = dyn Fn(&AttemptContext, &ClientResponse) -> bool;
+type AsyncFn = dyn Fn(&ResponseHead) -> LocalBoxFuture<'static, bool>;
+type ExchangeFn = dyn Fn(&RequestHead, &ResponseHead) -> bool;
+type BodyFn = dyn Fn(&ClientResponse, &[u8]) -> bool;
+
+/// The methods RFC 7231 defines as idempotent
+const IDEMPOTENT_METHODS: [Method; 6] = [Method::GET, Method::HEAD, Method::OPTIONS, Method::TRACE, Method::PUT, Method::DELETE];
+
+/// The gRPC status codes proxies such as Envoy retry: `CANCELLED`, `DEADLINE_EXCEEDED`,
+/// `RESOURCE_EXHAUSTED`, `INTERNAL` and `UNAVAILABLE`
+const GRPC_RETRYABLE_CODES: [u32; 5] = [1, 4, 8, 13, 14];
+
+/// Bodies longer than this are never XML error documents worth parsing
+const XML_ERROR_LIMIT: usize = 16 * 1024;
+
+impl RetryPolicy {
+    /// Retries responses whose status code falls in `range`, such as `500..=599`.
+    ///
+    /// Ranges, single statuses and arrays of statuses can also be passed to
+    /// [`Retry::policy`](crate::Retry::policy) directly.
+    ///
+    /// ```
+    /// use actix_http::http::StatusCode;
+    /// use awc_retry::{Retry, RetryPolicy};
+    ///
+    /// let retry = Retry::new(3).policy(RetryPolicy::status_range(502..=504));
+    /// let retry = Retry::new(3).policy(502..=504);
+    /// let retry = Retry::new(3).policy(StatusCode::BAD_GATEWAY);
+    /// let retry = Retry::new(3).policy([StatusCode::BAD_GATEWAY, StatusCode::GATEWAY_TIMEOUT]);
+    /// ```
+    pub fn status_range(range: RangeInclusive<u16>) -> Self {
+        RetryPolicy::StatusRanges(vec![range])
+    }
+
+    /// Retries responses with `status`
+    pub fn status(status: StatusCode) -> Self {
+        RetryPolicy::Status(vec![status])
+    }
+
+    /// Retries every `4xx` response
+    pub fn client_errors() -> Self {
+        RetryPolicy::status_range(400..=499)
+    }
+
+    /// Retries every `5xx` response
+    pub fn server_errors() -> Self {
+        RetryPolicy::status_range(500..=599)
+    }
+
+    /// Retries `429 Too Many Requests` and every `5xx` response
+    pub fn too_many_requests_or_5xx() -> Self {
+        RetryPolicy::StatusRanges(vec![429..=429, 500..=599])
+    }
+
+    /// Creates a policy over the whole [`ClientResponse`], retrying when `f` resolves to false.
+    ///
+    /// Use this over a `Fn(&ResponseHead) -> bool` policy when the decision needs the typed
+    /// accessors, the negotiated [`version`](ClientResponse::version) or the extensions added by
+    /// other transforms.
+    ///
+    /// ```
+    /// use actix_web::HttpMessage;
+    /// use awc::ClientResponse;
+    /// use awc_retry::{Retry, RetryPolicy};
+    ///
+    /// // Retry when a proxy answered with something other than JSON
+    /// let retry = Retry::new(3)
+    ///     .policy(RetryPolicy::response(|res: &ClientResponse| res.content_type() == "application/json"));
+    /// ```
+    pub fn response<F>(f: F) -> Self
+        where F: Fn(&ClientResponse) -> bool + 'static
+    {
+        RetryPolicy::Response(Box::new(f))
+    }
+
+    /// Like a policy closure taking a [`ResponseHead`], but `f` also gets the head of the request
+    /// the response belongs to. Responses to requests sent through [`run`](crate::run), whose
+    /// head isn't known, pass.
+    ///
+    /// ```
+    /// use actix_http::http::{Method, StatusCode};
+    /// use awc_retry::{Policy, Retry};
+    ///
+    /// // A 404 of the cache means it wasn't filled yet
+    /// let retry = Retry::new(3).policy(Policy::exchange(|req, res| {
+    ///     !(res.status == StatusCode::NOT_FOUND && req.method == Method::GET && req.uri.path().starts_with("/cache/"))
+    /// }));
+    /// ```
+    pub fn exchange<F>(f: F) -> Self
+        where F: Fn(&RequestHead, &ResponseHead) -> bool + 'static
+    {
+        RetryPolicy::Exchange(Box::new(f))
+    }
+
+    /// Like [`RetryPolicy::response`], but `f` is also given the [`AttemptContext`] of the
+    /// request, so it can decide by how far the request got without keeping state of its own.
+    ///
+    /// ```
+    /// use awc_retry::{Policy, Retry};
+    ///
+    /// // 5xx responses of the first two attempts are retried, later ones are returned
+    /// let retry = Retry::new(5).policy(Policy::response_with_context(|ctx, res| {
+    ///     !res.status().is_server_error() || ctx.attempt() > 2
+    /// }));
+    /// ```
+    pub fn response_with_context<F>(f: F) -> Self
+        where F: Fn(&AttemptContext, &ClientResponse) -> bool + 'static
+    {
+        RetryPolicy::ResponseWithContext(Box::new(f))
+    }
+
+    /// Retries responses whose header `name` is `value`, ignoring case, whatever their status,
+    /// for services which flag transient failures in a header.
+    ///
+    /// ```
+    /// use actix_http::http::header::{HeaderName, HeaderValue};
+    /// use awc_retry::{Policy, Retry};
+    ///
+    /// let retry = Retry::new(3).policy(Policy::server_errors().or(Policy::response_header(
+    ///     HeaderName::from_static("x-should-retry"),
+    ///     HeaderValue::from_static("true"),
+    /// )));
+    /// ```
+    pub fn response_header(name: HeaderName, value: HeaderValue) -> Self {
+        RetryPolicy::ResponseHeader(name, value)
+    }
+
+    /// Retries gRPC responses whose `grpc-status` header is one of `codes`, such as `14` for
+    /// `UNAVAILABLE`.
+    ///
+    /// Only the status of trailers-only responses, which gRPC servers send when a call fails
+    /// before any message, is in the headers. Statuses sent in trailers after a body aren't seen,
+    /// nor are responses without the header.
+    ///
+    /// ```
+    /// use awc_retry::{Policy, Retry};
+    ///
+    /// // UNAVAILABLE and ABORTED
+    /// let retry = Retry::new(3).policy(Policy::grpc_status(vec![14, 10]));
+    /// ```
+    pub fn grpc_status<I: IntoIterator<Item=u32>>(codes: I) -> Self {
+        let codes: Vec<u32> = codes.into_iter().collect();
+        let name = HeaderName::from_static("grpc-status");
+        RetryPolicy::Custom(Box::new(move |head| {
+            head.headers
+                .get(&name)
+                .and_then(|v| v.to_str().ok()?.trim().parse::<u32>().ok())
+                .is_none_or(|code| !codes.contains(&code))
+        }))
+    }
+
+    /// Retries the gRPC statuses commonly treated as transient: `CANCELLED`,
+    /// `DEADLINE_EXCEEDED`, `RESOURCE_EXHAUSTED`, `INTERNAL` and `UNAVAILABLE`, see
+    /// [`RetryPolicy::grpc_status`].
+    ///
+    /// ```
+    /// use awc_retry::{Policy, Retry};
+    ///
+    /// let retry = Retry::new(3).policy(Policy::grpc_retryable().or(Policy::server_errors()));
+    /// ```
+    pub fn grpc_retryable() -> Self {
+        RetryPolicy::grpc_status(GRPC_RETRYABLE_CODES.iter().copied())
+    }
+
+    /// Retries the errors `f` returns true for.
+    ///
+    /// Every error is retried until an error policy is added, from then on only the errors an
+    /// error policy retries are. Combining policies works as for responses, so
+    /// `Policy::server_errors().or(Policy::timeouts())` retries both. Response policies don't
+    /// match errors, and error policies don't match responses.
+    ///
+    /// ```
+    /// use awc::error::SendRequestError;
+    /// use awc_retry::{Policy, Retry};
+    ///
+    /// let retry = Retry::new(3)
+    ///     .policy(Policy::server_errors())
+    ///     .policy(Policy::error(|e| matches!(e, SendRequestError::Connect(_) | SendRequestError::Timeout)));
+    /// ```
+    pub fn error<F>(f: F) -> Self
+        where F: Fn(&SendRequestError) -> bool + 'static
+    {
+        RetryPolicy::Error(Box::new(f))
+    }
+
+    /// Retries failures to connect to the host, see [`RetryPolicy::error`]
+    pub fn connect_errors() -> Self {
+        RetryPolicy::error(|e| matches!(e, SendRequestError::Connect(_)))
+    }
+
+    /// Retries attempts which timed out waiting for the response, see [`RetryPolicy::error`]
+    pub fn timeouts() -> Self {
+        RetryPolicy::error(|e| matches!(e, SendRequestError::Timeout))
+    }
+
+    /// Retries errors another attempt may not run into: failing to connect, timeouts, I/O
+    /// errors while sending and HTTP/2 errors. Invalid URLs, malformed requests and bodies which
+    /// failed to stream fail the same way every time, so they aren't retried. See
+    /// [`RetryPolicy::error`].
+    pub fn transient_errors() -> Self {
+        RetryPolicy::error(|e| {
+            matches!(
+                e,
+                SendRequestError::Connect(_)
+                    | SendRequestError::Timeout
+                    | SendRequestError::Send(_)
+                    | SendRequestError::H2(_)
+            )
+        })
+    }
+
+    /// Retries only connect errors which mean the request never reached the server, so retrying
+    /// is safe whatever its method: connect timeouts, refused or reset connections and failed
+    /// DNS lookups. See [`RetryPolicy::error`].
+    ///
+    /// ```
+    /// use awc_retry::{Policy, Retry};
+    ///
+    /// let retry = Retry::new(2).policy(Policy::safe_connect_errors());
+    /// ```
+    pub fn safe_connect_errors() -> Self {
+        RetryPolicy::error(|e| match e {
+            SendRequestError::Connect(e) => is_safe_connect_error(e),
+            _ => false,
+        })
+    }
+
+    /// Retries connect and send errors caused by an I/O error of one of `kinds`, such as
+    /// [`ConnectionReset`](io::ErrorKind::ConnectionReset) or [`BrokenPipe`](io::ErrorKind::BrokenPipe).
+    /// See [`RetryPolicy::error`].
+    ///
+    /// ```
+    /// use std::io::ErrorKind;
+    /// use awc_retry::{Policy, Retry};
+    ///
+    /// let retry = Retry::new(3).policy(Policy::io_errors(vec![
+    ///     ErrorKind::ConnectionReset,
+    ///     ErrorKind::ConnectionAborted,
+    ///     ErrorKind::BrokenPipe,
+    /// ]));
+    /// ```
+    pub fn io_errors<I: IntoIterator<Item=io::ErrorKind>>(kinds: I) -> Self {
+        let kinds: Vec<_> = kinds.into_iter().collect();
+        RetryPolicy::error(move |e| io_error(e).is_some_and(|e| kinds.contains(&e.kind())))
+    }
+
+    /// Scopes this policy to responses negotiated over `version`, other responses pass it.
+    ///
+    /// ```
+    /// use actix_http::http::{header, StatusCode, Version};
+    /// use awc_retry::{IntoRetryPolicy, Retry};
+    ///
+    /// // Only HTTP/2 upstreams are known to send spurious 502s
+    /// let retry = Retry::new(3)
+    ///     .policy(vec![StatusCode::BAD_GATEWAY].into_policy().for_version(Version::HTTP_2));
+    /// ```
+    pub fn for_version(self, version: Version) -> Self {
+        RetryPolicy::Version(version, Box::new(self))
+    }
+
+    /// Retries responses whose media type isn't one of `expected`, such as the HTML error page
+    /// of a failing proxy in front of a JSON API.
+    ///
+    /// Parameters like `charset` are ignored and `type/*` matches any subtype. Responses without
+    /// a `Content-Type` pass, they usually have no body to mismatch.
+    ///
+    /// ```
+    /// use awc_retry::{Retry, RetryPolicy};
+    ///
+    /// let retry = Retry::new(3).policy(RetryPolicy::content_type(&["application/json", "text/*"]));
+    /// ```
+    pub fn content_type<I>(expected: I) -> Self
+        where
+            I: IntoIterator,
+            I::Item: AsRef<str>,
+    {
+        RetryPolicy::ContentType(expected.into_iter().map(|t| t.as_ref().to_ascii_lowercase()).collect())
+    }
+
+    /// Retries XML error documents, as returned by S3 compatible object stores, whose `<Code>`
+    /// is one of `codes`.
+    ///
+    /// Only bodies with an XML `Content-Type` and of at most 16 KiB are read, the body is still
+    /// returned in full to the caller. Compressed bodies are decoded by their `Content-Encoding`
+    /// first, bodies in encodings other than `gzip`, `deflate` and `br` pass.
+    ///
+    /// ```
+    /// use awc_retry::{Retry, RetryPolicy};
+    ///
+    /// let retry = Retry::new(3)
+    ///     .policy(RetryPolicy::xml_error_code(&["SlowDown", "InternalError", "RequestTimeout"]));
+    /// ```
+    pub fn xml_error_code<I>(codes: I) -> Self
+        where
+            I: IntoIterator,
+            I::Item: AsRef<str>,
+    {
+        RetryPolicy::XmlErrorCode(codes.into_iter().map(|c| c.as_ref().to_owned()).collect())
+    }
+
+    /// Reads bodies of up to `limit` bytes into memory and retries the responses `f` returns
+    /// false for, such as APIs answering `200 OK` with an error payload.
+    ///
+    /// A retried response is dropped along with its buffered body, any other response is returned
+    /// with its body replayed from the buffer, so the caller reads it as if nothing had looked at
+    /// it. Longer bodies and bodies failing to be read pass without `f` being asked. `f` gets
+    /// compressed bodies decoded by their `Content-Encoding`, bodies in encodings other than
+    /// `gzip`, `deflate` and `br` pass.
+    /// [`Retry::streaming_safe`](crate::Retry::streaming_safe) rejects this policy.
+    ///
+    /// ```
+    /// use awc_retry::{Policy, Retry};
+    ///
+    /// let retry = Retry::new(3).policy(Policy::body(4096, |res, body| {
+    ///     !(res.status().is_success() && body.starts_with(b"{\"error\":\"busy\""))
+    /// }));
+    /// ```
+    pub fn body<F>(limit: usize, f: F) -> Self
+        where F: Fn(&ClientResponse, &[u8]) -> bool + 'static
+    {
+        RetryPolicy::Body(limit, Box::new(f))
+    }
+
+    /// Retries only responses both this policy and `other` would retry.
+    ///
+    /// ```
+    /// use actix_http::http::StatusCode;
+    /// use awc_retry::{Retry, RetryPolicy};
+    ///
+    /// // Every 5xx except 501 Not Implemented, or a 429
+    /// let policy = RetryPolicy::server_errors()
+    ///     .and(!RetryPolicy::Status(vec![StatusCode::NOT_IMPLEMENTED]))
+    ///     .or(StatusCode::TOO_MANY_REQUESTS);
+    /// let retry = Retry::new(3).policy(policy);
+    /// ```
+    pub fn and<T: IntoRetryPolicy>(self, other: T) -> Self {
+        RetryPolicy::And(Box::new(self), Box::new(other.into_policy()))
+    }
+
+    /// Retries requests with one of `methods`.
+    ///
+    /// Policies which only look at the request restrict the retries of every other policy when
+    /// given to [`Retry::policy`](crate::Retry::policy) on their own: a request they don't match
+    /// is never retried, whatever the response or error. Combined with other policies they are
+    /// one more condition. Requests sent without a known head, through [`run`](crate::run),
+    /// match every request policy.
+    ///
+    /// ```
+    /// use actix_http::http::Method;
+    /// use awc_retry::{Policy, Retry};
+    ///
+    /// // 5xx responses are retried, but only for reads
+    /// let retry = Retry::new(3)
+    ///     .policy(Policy::server_errors())
+    ///     .policy(Policy::methods(vec![Method::GET, Method::HEAD]));
+    ///
+    /// // 503s are always retried, other 5xx only for idempotent methods
+    /// let retry = Retry::new(3).policy(
+    ///     Policy::server_errors()
+    ///         .and(Policy::idempotent_methods())
+    ///         .or(Policy::status(actix_http::http::StatusCode::SERVICE_UNAVAILABLE)),
+    /// );
+    /// ```
+    pub fn methods<I: IntoIterator<Item=Method>>(methods: I) -> Self {
+        RetryPolicy::Methods(methods.into_iter().collect())
+    }
+
+    /// Retries requests with a method RFC 7231 defines as idempotent: `GET`, `HEAD`, `OPTIONS`,
+    /// `TRACE`, `PUT` and `DELETE`, see [`RetryPolicy::methods`]
+    pub fn idempotent_methods() -> Self {
+        RetryPolicy::methods(IDEMPOTENT_METHODS.iter().cloned())
+    }
+
+    /// Retries requests to one of `hosts`, compared without regard to case. A host starting
+    /// with `*.` matches its subdomains, but not itself. A request policy, see
+    /// [`RetryPolicy::methods`].
+    ///
+    /// ```
+    /// use awc_retry::{Policy, Retry};
+    ///
+    /// // Only internal services are retried
+    /// let retry = Retry::new(3)
+    ///     .policy(Policy::server_errors())
+    ///     .policy(Policy::hosts(vec!["*.svc.cluster.local", "auth.example.com"]));
+    ///
+    /// // Payments are never retried
+    /// let retry = Retry::new(3)
+    ///     .policy(Policy::server_errors())
+    ///     .policy(!Policy::hosts(vec!["api.stripe.com"]));
+    /// ```
+    pub fn hosts<I>(hosts: I) -> Self
+        where
+            I: IntoIterator,
+            I::Item: Into<String>,
+    {
+        RetryPolicy::Hosts(hosts.into_iter().map(|host| host.into().to_ascii_lowercase()).collect())
+    }
+
+    /// Retries requests whose path is `prefix` or below it, such as `/api/v1/reports/42` for
+    /// `/api/v1/reports`, but not `/api/v1/reports-archive`. A request policy, see
+    /// [`RetryPolicy::methods`].
+    ///
+    /// ```
+    /// use awc_retry::{Policy, Retry};
+    ///
+    /// // Report generation is slow to recover, everything else is retried as usual
+    /// let retry = Retry::new(3)
+    ///     .policy(Policy::server_errors())
+    ///     .policy(!Policy::path_prefix("/api/v1/reports"));
+    /// ```
+    pub fn path_prefix<S: Into<String>>(prefix: S) -> Self {
+        RetryPolicy::PathPrefix(prefix.into())
+    }
+
+    /// Retries requests whose path matches `pattern`, where `?` is any character but `/`, `*`
+    /// is any run of characters within a segment and `**` is any number of segments. A request
+    /// policy, see [`RetryPolicy::methods`].
+    ///
+    /// ```
+    /// use awc_retry::{Policy, Retry};
+    ///
+    /// let retry = Retry::new(3)
+    ///     .policy(Policy::server_errors())
+    ///     .policy(Policy::path_glob("/users/*/avatar").or(Policy::path_glob("/static/**")));
+    /// ```
+    pub fn path_glob<S: Into<String>>(pattern: S) -> Self {
+        RetryPolicy::PathGlob(pattern.into())
+    }
+
+    /// Retries requests whose full URI, scheme and query included, matches `regex`. Enabled by
+    /// the `regex` feature. A request policy, see [`RetryPolicy::methods`].
+    ///
+    /// ```
+    /// use awc_retry::{Policy, Retry};
+    /// use regex::Regex;
+    ///
+    /// let retry = Retry::new(3)
+    ///     .policy(Policy::server_errors())
+    ///     .policy(Policy::uri_regex(Regex::new(r"^https://[a-z]+\.internal/(reports|exports)/").unwrap()));
+    /// ```
+    #[cfg(feature = "regex")]
+    pub fn uri_regex(regex: regex::Regex) -> Self {
+        RetryPolicy::UriRegex(regex)
+    }
+
+    /// Retries requests whose headers, as they are sent, `f` returns true for. A request policy,
+    /// see [`RetryPolicy::methods`], so it's checked before the first retry is sent.
+    ///
+    /// ```
+    /// use actix_http::http::header::AUTHORIZATION;
+    /// use awc_retry::{Policy, Retry};
+    ///
+    /// // Callers opt out with `X-No-Retry`, and short-lived bearer tokens aren't sent again
+    /// let retry = Retry::new(3)
+    ///     .policy(Policy::server_errors())
+    ///     .policy(Policy::request_headers(|headers| {
+    ///         let bearer = headers.get(AUTHORIZATION)
+    ///             .and_then(|v| v.to_str().ok())
+    ///             .is_some_and(|v| v.starts_with("Bearer "));
+    ///         !headers.contains_key("x-no-retry") && !bearer
+    ///     }));
+    /// ```
+    pub fn request_headers<F>(f: F) -> Self
+        where F: Fn(&HeaderMap) -> bool + 'static
+    {
+        RetryPolicy::RequestHeaders(Box::new(f))
+    }
+
+    /// Retries `POST` and `PATCH` requests only when they carry the `header` servers deduplicate
+    /// them by, usually `Idempotency-Key`, any other request is retried. A request policy, see
+    /// [`RetryPolicy::methods`].
+    ///
+    /// ```
+    /// use actix_http::http::header::HeaderName;
+    /// use awc_retry::{Policy, Retry};
+    ///
+    /// let retry = Retry::new(3)
+    ///     .policy(Policy::server_errors())
+    ///     .policy(Policy::idempotency_key(HeaderName::from_static("idempotency-key")));
+    /// ```
+    pub fn idempotency_key(header: HeaderName) -> Self {
+        (!RetryPolicy::methods(KEYED_METHODS.iter().cloned())).or(RetryPolicy::RequestHeader(header))
+    }
+
+    /// Retries responses either this policy or `other` would retry.
+    ///
+    /// Two status policies are merged into one, so chains of them are checked as a single list
+    /// of ranges.
+    pub fn or<T: IntoRetryPolicy>(self, other: T) -> Self {
+        match (self.status_ranges(), other.into_policy()) {
+            (Ok(mut ranges), other) => match other.status_ranges() {
+                Ok(more) => {
+                    ranges.extend(more);
+                    RetryPolicy::StatusRanges(ranges)
+                }
+                Err(other) => RetryPolicy::Or(Box::new(RetryPolicy::StatusRanges(ranges)), Box::new(other)),
+            },
+            (Err(policy), other) => RetryPolicy::Or(Box::new(policy), Box::new(other)),
+        }
+    }
+
+    /// The statuses a status policy retries as ranges, other policies are handed back
+    fn status_ranges(self) -> Result<Vec<RangeInclusive<u16>>, Self> {
+        match self {
+            RetryPolicy::Status(statuses) if !statuses.is_empty() => {
+                Ok(statuses.iter().map(|s| s.as_u16()..=s.as_u16()).collect())
+            }
+            RetryPolicy::StatusRanges(ranges) if !ranges.is_empty() && !ranges.iter().any(|r| r.is_empty()) => Ok(ranges),
+            policy => Err(policy),
+        }
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), ConfigError> {
+        match self {
+            RetryPolicy::Status(v) if v.is_empty() => Err(ConfigError::EmptyStatusList),
+            RetryPolicy::StatusRanges(v) if v.is_empty() || v.iter().any(|r| r.is_empty()) => {
+                Err(ConfigError::EmptyStatusList)
+            }
+            RetryPolicy::ContentType(v) if v.is_empty() => Err(ConfigError::EmptyContentTypeList),
+            RetryPolicy::XmlErrorCode(v) if v.is_empty() => Err(ConfigError::EmptyErrorCodeList),
+            RetryPolicy::Methods(v) if v.is_empty() => Err(ConfigError::EmptyMethodList),
+            RetryPolicy::Hosts(v) if v.is_empty() => Err(ConfigError::EmptyHostList),
+            RetryPolicy::Version(_, policy) | RetryPolicy::Not(policy) => policy.validate(),
+            RetryPolicy::And(a, b) | RetryPolicy::Or(a, b) => a.validate().and_then(|_| b.validate()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Where the policy runs when no priority is given, cheap status checks come first
+    pub(crate) fn default_priority(&self) -> i32 {
+        match self {
+            RetryPolicy::Status(_) | RetryPolicy::StatusRanges(_) | RetryPolicy::ContentType(_) => 0,
+            RetryPolicy::ResponseHeader(..) => 0,
+            RetryPolicy::Methods(_) | RetryPolicy::RequestHeader(_) | RetryPolicy::Hosts(_) => 0,
+            RetryPolicy::RequestHeaders(_) => 100,
+            RetryPolicy::PathPrefix(_) | RetryPolicy::PathGlob(_) => 0,
+            #[cfg(feature = "regex")]
+            RetryPolicy::UriRegex(_) => 0,
+            RetryPolicy::Custom(_) | RetryPolicy::Response(_) | RetryPolicy::Error(_) => 100,
+            RetryPolicy::ResponseWithContext(_) | RetryPolicy::Async(_) | RetryPolicy::Exchange(_) => 100,
+            // Reading the body is the most expensive check, so it goes last
+            RetryPolicy::XmlErrorCode(_) | RetryPolicy::Body(..) => 200,
+            RetryPolicy::Version(_, policy) | RetryPolicy::Not(policy) => policy.default_priority(),
+            RetryPolicy::And(a, b) | RetryPolicy::Or(a, b) => a.default_priority().max(b.default_priority()),
+        }
+    }
+
+    /// Whether the policy reads the response body into memory
+    pub(crate) fn buffers_body(&self) -> bool {
+        match self {
+            RetryPolicy::XmlErrorCode(_) | RetryPolicy::Body(..) => true,
+            RetryPolicy::Version(_, policy) | RetryPolicy::Not(policy) => policy.buffers_body(),
+            RetryPolicy::And(a, b) | RetryPolicy::Or(a, b) => a.buffers_body() || b.buffers_body(),
+            _ => false,
+        }
+    }
+
+    /// Whether the policy only looks at the request, so it can restrict the retries of the other
+    /// policies
+    pub(crate) fn is_request_policy(&self) -> bool {
+        match self {
+            RetryPolicy::Methods(_) | RetryPolicy::RequestHeader(_) | RetryPolicy::Hosts(_) => true,
+            RetryPolicy::RequestHeaders(_) => true,
+            RetryPolicy::PathPrefix(_) | RetryPolicy::PathGlob(_) => true,
+            #[cfg(feature = "regex")]
+            RetryPolicy::UriRegex(_) => true,
+            RetryPolicy::Not(policy) => policy.is_request_policy(),
+            RetryPolicy::And(a, b) | RetryPolicy::Or(a, b) => a.is_request_policy() && b.is_request_policy(),
+            _ => false,
+        }
+    }
+
+    /// A method without idempotency keys the policy explicitly retries, e.g. `POST`, or retries
+    /// by leaving it out of a negated method policy
+    fn keyed_method(&self) -> Option<Method> {
+        self.keyed_method_negated(false)
+    }
+
+    fn keyed_method_negated(&self, negated: bool) -> Option<Method> {
+        match self {
+            RetryPolicy::Methods(methods) => KEYED_METHODS.iter().find(|m| methods.contains(m) != negated).cloned(),
+            RetryPolicy::Not(policy) => policy.keyed_method_negated(!negated),
+            RetryPolicy::Version(_, policy) => policy.keyed_method_negated(negated),
+            RetryPolicy::And(a, b) | RetryPolicy::Or(a, b) => {
+                a.keyed_method_negated(negated).or_else(|| b.keyed_method_negated(negated))
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether the policy looks at the request headers, such as for an idempotency key
+    fn reads_request_headers(&self) -> bool {
+        match self {
+            RetryPolicy::RequestHeader(_) | RetryPolicy::RequestHeaders(_) => true,
+            RetryPolicy::Version(_, policy) | RetryPolicy::Not(policy) => policy.reads_request_headers(),
+            RetryPolicy::And(a, b) | RetryPolicy::Or(a, b) => a.reads_request_headers() || b.reads_request_headers(),
+            _ => false,
+        }
+    }
+
+    /// Whether the policy decides which errors are retried
+    fn has_error_policy(&self) -> bool {
+        match self {
+            RetryPolicy::Error(_) => true,
+            RetryPolicy::Version(_, policy) | RetryPolicy::Not(policy) => policy.has_error_policy(),
+            RetryPolicy::And(a, b) | RetryPolicy::Or(a, b) => a.has_error_policy() || b.has_error_policy(),
+            _ => false,
+        }
+    }
+
+    /// Whether the policy only decides errors and the requests they are retried for, so it has
+    /// no say over responses
+    fn is_error_policy(&self) -> bool {
+        let decides = |policy: &RetryPolicy| policy.is_error_policy() || policy.is_request_policy();
+        match self {
+            RetryPolicy::Error(_) => true,
+            RetryPolicy::Not(policy) => policy.is_error_policy(),
+            RetryPolicy::And(a, b) | RetryPolicy::Or(a, b) => {
+                decides(a) && decides(b) && (a.is_error_policy() || b.is_error_policy())
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether the policy retries `err` of an attempt of `req`, policies which only look at
+    /// responses don't match it
+    fn retries_error(&self, err: &SendRequestError, req: Option<&RequestHeadType>) -> bool {
+        match self {
+            RetryPolicy::Error(f) => f(err),
+            RetryPolicy::Not(policy) => !policy.retries_error(err, req),
+            RetryPolicy::And(a, b) => a.retries_error(err, req) && b.retries_error(err, req),
+            RetryPolicy::Or(a, b) => a.retries_error(err, req) || b.retries_error(err, req),
+            policy if policy.is_request_policy() => req.is_none_or(|req| policy.matches_request(req)),
+            _ => false,
+        }
+    }
+
+    /// Whether a request policy would retry `req`, other policies don't decide anything here
+    fn matches_request(&self, req: &RequestHeadType) -> bool {
+        match self {
+            RetryPolicy::Methods(methods) => methods.contains(&req.as_ref().method),
+            RetryPolicy::RequestHeader(name) => crate::head::header(req, name).is_some(),
+            RetryPolicy::RequestHeaders(f) => f(&crate::head::headers(req)),
+            RetryPolicy::Hosts(hosts) => req.as_ref().uri.host().is_some_and(|host| host_matches(host, hosts)),
+            RetryPolicy::PathPrefix(prefix) => path_below(req.as_ref().uri.path(), prefix),
+            RetryPolicy::PathGlob(pattern) => glob_matches(pattern.as_bytes(), req.as_ref().uri.path().as_bytes()),
+            #[cfg(feature = "regex")]
+            RetryPolicy::UriRegex(regex) => regex.is_match(&req.as_ref().uri.to_string()),
+            RetryPolicy::Not(policy) => !policy.matches_request(req),
+            RetryPolicy::And(a, b) => a.matches_request(req) && b.matches_request(req),
+            RetryPolicy::Or(a, b) => a.matches_request(req) || b.matches_request(req),
+            _ => true,
+        }
+    }
+
+    /// Whether the policy has to be checked against the [`ResponseHead`]
+    fn needs_head(&self) -> bool {
+        matches!(self, RetryPolicy::Custom(_) | RetryPolicy::ContentType(_))
+    }
+
+    /// Whether `res` to `req` passes this policy at `ctx`, keeping what was read of the body in `body`
+    /// for the other policies. Body policies pass when `buffering` isn't allowed or the body
+    /// isn't theirs to look at.
+    async fn passes(
+        &self,
+        res: ClientResponse,
+        req: Option<&RequestHeadType>,
+        ctx: &AttemptContext,
+        body: &mut Option<Buffered>,
+        buffering: bool,
+    ) -> (ClientResponse, bool) {
+        match self {
+            RetryPolicy::XmlErrorCode(codes) => {
+                if !buffering || !is_xml(&res) {
+                    return (res, true);
+                }
+                let res = body::buffer_shared(res, body, XML_ERROR_LIMIT).await;
+                let valid = match body {
+                    Some(Buffered::Complete(bytes)) => {
+                        body::xml_error_code(bytes).is_none_or(|code| !codes.iter().any(|c| c == code))
+                    }
+                    _ => true,
+                };
+                (res, valid)
+            }
+            RetryPolicy::Body(limit, func) => {
+                if !buffering {
+                    return (res, true);
+                }
+                let res = body::buffer_shared(res, body, *limit).await;
+                let valid = match body {
+                    Some(Buffered::Complete(bytes)) if bytes.len() <= *limit => func(&res, bytes),
+                    _ => true,
+                };
+                (res, valid)
+            }
+            RetryPolicy::Version(version, policy) => {
+                if res.version() != *version {
+                    return (res, true);
+                }
+                Box::pin(policy.passes(res, req, ctx, body, buffering)).await
+            }
+            // Retrying when both retry is passing when either passes, and the other way around
+            RetryPolicy::And(a, b) => {
+                let (res, valid) = Box::pin(a.passes(res, req, ctx, body, buffering)).await;
+                if valid {
+                    return (res, true);
+                }
+                Box::pin(b.passes(res, req, ctx, body, buffering)).await
+            }
+            RetryPolicy::Or(a, b) => {
+                let (res, valid) = Box::pin(a.passes(res, req, ctx, body, buffering)).await;
+                if !valid {
+                    return (res, false);
+                }
+                Box::pin(b.passes(res, req, ctx, body, buffering)).await
+            }
+            RetryPolicy::Not(policy) => {
+                let (res, valid) = Box::pin(policy.passes(res, req, ctx, body, buffering)).await;
+                (res, !valid)
+            }
+            policy if policy.is_request_policy() => {
+                let valid = !req.is_none_or(|req| policy.matches_request(req));
+                (res, valid)
+            }
+            policy if policy.needs_head() => {
+                // `map_body` is the only way to get at the `ResponseHead` of a `ClientResponse`
+                let mut valid = true;
+                let res = res.map_body(|head, payload| {
+                    valid = policy.is_valid_head(head);
+                    payload
+                });
+                (res, valid)
+            }
+            RetryPolicy::ResponseWithContext(func) => {
+                let valid = func(ctx, &res);
+                (res, valid)
+            }
+            RetryPolicy::Exchange(func) => {
+                let req = match req {
+                    Some(req) => req.as_ref(),
+                    None => return (res, true),
+                };
+                let mut valid = true;
+                let res = res.map_body(|head, payload| {
+                    valid = func(req, head);
+                    payload
+                });
+                (res, valid)
+            }
+            RetryPolicy::Async(func) => {
+                let mut verdict = None;
+                let res = res.map_body(|head, payload| {
+                    verdict = Some(func(head));
+                    payload
+                });
+                let valid = match verdict {
+                    Some(verdict) => verdict.await,
+                    None => true,
+                };
+                (res, valid)
+            }
+            policy => {
+                let valid = policy.is_valid_client_response(&res);
+                (res, valid)
+            }
+        }
+    }
+
+    fn is_valid_head(&self, head: &ResponseHead) -> bool {
+        match self {
+            RetryPolicy::Status(statuses) => !statuses.contains(&head.status),
+            RetryPolicy::StatusRanges(ranges) => !in_ranges(head.status, ranges),
+            RetryPolicy::Custom(func) => func(head),
+            RetryPolicy::ContentType(expected) => match head.headers.get(header::CONTENT_TYPE) {
+                Some(value) => value.to_str().is_ok_and(|value| media_type_matches(value, expected)),
+                None => true,
+            },
+            _ => true,
+        }
+    }
+
+    fn is_valid_client_response(&self, res: &ClientResponse) -> bool {
+        match self {
+            RetryPolicy::Status(statuses) => !statuses.contains(&res.status()),
+            RetryPolicy::StatusRanges(ranges) => !in_ranges(res.status(), ranges),
+            RetryPolicy::Response(func) => func(res),
+            RetryPolicy::ResponseHeader(name, value) => !res
+                .headers()
+                .get_all(name)
+                .any(|v| v.as_bytes().eq_ignore_ascii_case(value.as_bytes())),
+            _ => true,
+        }
+    }
+}
+
+/// Whether `host` is one of `hosts`, which are lowercase and may be `*.` subdomain wildcards
+fn host_matches(host: &str, hosts: &[String]) -> bool {
+    let host = host.to_ascii_lowercase();
+    hosts.iter().any(|h| match h.strip_prefix("*.") {
+        Some(domain) => host.strip_suffix(domain).is_some_and(|sub| sub.len() > 1 && sub.ends_with('.')),
+        None => *h == host,
+    })
+}
+
+/// `NO_ERROR` and `REFUSED_STREAM` of RFC 7540 §7
+const H2_NO_ERROR: u32 = 0x0;
+const H2_REFUSED_STREAM: u32 = 0x7;
+
+/// Whether the server refused the HTTP/2 stream of the attempt without processing it, by
+/// resetting it with `REFUSED_STREAM` or shutting the connection down gracefully with `GOAWAY`
+/// before it got to the stream, which RFC 7540 §8.1.4 makes safe to retry for any method
+pub(crate) fn is_refused_stream(err: &SendRequestError) -> bool {
+    let e = match err {
+        SendRequestError::H2(e) | SendRequestError::Connect(ConnectError::H2(e)) => e,
+        _ => return false,
+    };
+    match e.reason().map(u32::from) {
+        Some(H2_REFUSED_STREAM) => e.is_reset() && e.is_remote(),
+        Some(H2_NO_ERROR) => e.is_go_away() && e.is_remote(),
+        _ => false,
+    }
+}
+
+/// The I/O error a connect or send error was caused by
+fn io_error(err: &SendRequestError) -> Option<&io::Error> {
+    match err {
+        SendRequestError::Connect(ConnectError::Io(e)) | SendRequestError::Send(e) => Some(e),
+        _ => None,
+    }
+}
+
+/// Whether connecting failed before anything of the request could have been sent
+fn is_safe_connect_error(err: &ConnectError) -> bool {
+    match err {
+        ConnectError::Timeout | ConnectError::Resolver(_) | ConnectError::NoRecords => true,
+        ConnectError::Io(e) => matches!(e.kind(), io::ErrorKind::ConnectionRefused | io::ErrorKind::ConnectionReset),
+        _ => false,
+    }
+}
+
+/// Whether `path` is `prefix` or one of the paths below it
+fn path_below(path: &str, prefix: &str) -> bool {
+    match path.strip_prefix(prefix) {
+        Some(rest) => rest.is_empty() || prefix.ends_with('/') || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+/// Matches `path` against a glob of `?`, `*` within a segment and `**` across segments
+fn glob_matches(pattern: &[u8], path: &[u8]) -> bool {
+    match pattern {
+        [] => path.is_empty(),
+        [b'*', b'*', rest @ ..] => {
+            // `/**/` also stands for a single `/`
+            (rest.first() == Some(&b'/') && glob_matches(&rest[1..], path))
+                || (0..=path.len()).any(|at| glob_matches(rest, &path[at..]))
+        }
+        [b'*', rest @ ..] => {
+            let segment = path.iter().position(|&c| c == b'/').unwrap_or(path.len());
+            (0..=segment).any(|at| glob_matches(rest, &path[at..]))
+        }
+        [b'?', rest @ ..] => matches!(path, [c, tail @ ..] if *c != b'/' && glob_matches(rest, tail)),
+        [p, rest @ ..] => matches!(path, [c, tail @ ..] if c == p && glob_matches(rest, tail)),
+    }
+}
+
+fn in_ranges(status: StatusCode, ranges: &[RangeInclusive<u16>]) -> bool {
+    ranges.iter().any(|range| range.contains(&status.as_u16()))
+}
+
+/// Whether the essence of the `Content-Type` value is one of `expected`, which are lowercase
+fn media_type_matches(value: &str, expected: &[String]) -> bool {
+    let essence = value.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+    let main_type = essence.split('/').next().unwrap_or_default();
+
+    expected.iter().any(|e| match e.strip_suffix("/*") {
+        Some(t) => t == main_type,
+        None => *e == essence,
+    })
+}
+
+/// How the verdicts of several policies are combined, see [`Retry::combine_policies`](crate::Retry::combine_policies)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Combine {
+    /// Retry when any policy rejects the response, the default
+    #[default]
+    Any,
+    /// Retry only when every policy rejects the response
+    All,
+}
+
+/// The policies of a [`Retry`](crate::Retry), ordered by priority
+#[derive(Default)]
+pub(crate) struct Policies {
+    policies: Vec<(i32, RetryPolicy)>,
+    /// Request policies given on their own, every one of them has to match a retried request
+    requests: Vec<RetryPolicy>,
+    pub(crate) combine: Combine,
+}
+
+impl Policies {
+    /// Adds `policy` after every policy with the same or a lower priority
+    pub(crate) fn push(&mut self, priority: i32, policy: RetryPolicy) {
+        if policy.is_request_policy() {
+            self.requests.push(policy);
+            return;
+        }
+        let at = self.policies.iter().position(|(p, _)| *p > priority).unwrap_or(self.policies.len());
+        self.policies.insert(at, (priority, policy));
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item=&RetryPolicy> {
+        self.policies.iter().map(|(_, policy)| policy).chain(&self.requests)
+    }
+
+    /// A method without idempotency keys the policies retry while none of them checks the
+    /// request headers for a key
+    pub(crate) fn unkeyed_method(&self) -> Option<Method> {
+        if self.iter().any(RetryPolicy::reads_request_headers) {
+            return None;
+        }
+        self.iter().find_map(RetryPolicy::keyed_method)
+    }
+
+    /// Whether the request policies allow retrying `req`, an unknown request is allowed
+    pub(crate) fn allow(&self, req: Option<&RequestHeadType>) -> bool {
+        req.is_none_or(|req| self.requests.iter().all(|policy| policy.matches_request(req)))
+    }
+
+    /// Whether `err` of an attempt of `req` is retried: always without error policies, otherwise
+    /// when any of the policies deciding errors retries it, or all of them do for [`Combine::All`].
+    /// The request policies given on their own aren't part of it, see [`Policies::allow`].
+    pub(crate) fn retries_error(&self, err: &SendRequestError, req: Option<&RequestHeadType>) -> bool {
+        let mut deciding = self.policies.iter().map(|(_, policy)| policy).filter(|p| p.has_error_policy()).peekable();
+        if deciding.peek().is_none() {
+            return true;
+        }
+
+        match self.combine {
+            Combine::Any => deciding.any(|policy| policy.retries_error(err, req)),
+            Combine::All => deciding.all(|policy| policy.retries_error(err, req)),
+        }
+    }
+
+    /// Checks the policies in order, stopping at the first one which decides the combined
+    /// verdict on `res` to `req`: a rejection when any policy retries, a pass when all have to.
+    /// The request policies given on their own and the error policies aren't part of it, see
+    /// [`Policies::allow`].
+    ///
+    /// Policies reading the body are skipped unless `buffering` is allowed, a skipped or
+    /// inapplicable policy passes.
+    pub(crate) async fn check(
+        &self,
+        mut res: ClientResponse,
+        req: Option<&RequestHeadType>,
+        ctx: &AttemptContext,
+        buffering: bool,
+    ) -> (ClientResponse, bool) {
+        let mut body = None;
+        let mut any = false;
+
+        for (_, policy) in self.policies.iter().filter(|(_, policy)| !policy.is_error_policy()) {
+            any = true;
+            let (checked, valid) = policy.passes(res, req, ctx, &mut body, buffering).await;
+            res = checked;
+
+            match (self.combine, valid) {
+                (Combine::Any, false) => return (res, false),
+                (Combine::All, true) => return (res, true),
+                _ => {}
+            }
+        }
+
+        // Every policy rejected the response when they all have to, or none did
+        (res, self.combine == Combine::Any || !any)
+    }
+}
+
+/// Whether `res` declares an XML body small enough to be an error document
+fn is_xml(res: &ClientResponse) -> bool {
+    let essence = res.content_type().split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+    let small = res.headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok()?.parse::<usize>().ok())
+        .is_none_or(|len| len <= XML_ERROR_LIMIT);
+
+    small && (essence == "application/xml" || essence == "text/xml" || essence.ends_with("+xml"))
+}
+
+pub trait IntoRetryPolicy {
+    fn into_policy(self) -> RetryPolicy;
+}
+
+/// What a policy closure given to [`Retry::policy`](crate::Retry::policy) returns: whether the
+/// response is fine, or a [`LocalBoxFuture`] resolving to it.
+///
+/// An async policy is handed the head before its future runs, so the future has to own whatever
+/// it needs of the head.
+///
+/// ```
+/// use actix_web::dev::ResponseHead;
+/// use awc_retry::Retry;
+/// use futures::future::FutureExt;
+///
+/// # async fn retries_enabled() -> bool { true }
+/// let retry = Retry::new(3).policy(|head: &ResponseHead| {
+///     let failed = head.status.is_server_error();
+///     // Only retried while the feature flag service says so
+///     async move { !failed || !retries_enabled().await }.boxed_local()
+/// });
+/// ```
+pub trait Verdict: 'static {
+    #[doc(hidden)]
+    fn policy<F>(f: F) -> RetryPolicy
+        where F: for<'a> Fn(&'a ResponseHead) -> Self + 'static;
+}
+
+impl Verdict for bool {
+    fn policy<F>(f: F) -> RetryPolicy
+        where F: for<'a> Fn(&'a ResponseHead) -> Self + 'static
+    {
+        RetryPolicy::Custom(Box::new(f))
+    }
+}
+
+impl Verdict for LocalBoxFuture<'static, bool> {
+    fn policy<F>(f: F) -> RetryPolicy
+        where F: for<'a> Fn(&'a ResponseHead) -> Self + 'static
+    {
+        RetryPolicy::Async(Box::new(f))
+    }
+}
+
+impl<T, V> IntoRetryPolicy for T
+    where
+        T: for<'a> Fn(&'a ResponseHead) -> V + 'static,
+        V: Verdict,
+{
+    fn into_policy(self) -> RetryPolicy {
+        V::policy(self)
+    }
+}
+
+/// Retries responses the policy wouldn't retry, `!RetryPolicy::server_errors()`.
+///
+/// Body policies which can't look at a response pass it, so their negation retries it.
+impl std::ops::Not for RetryPolicy {
+    type Output = RetryPolicy;
+
+    fn not(self) -> RetryPolicy {
+        match self {
+            RetryPolicy::Not(policy) => *policy,
+            policy => RetryPolicy::Not(Box::new(policy)),
+        }
+    }
+}
+
+impl IntoRetryPolicy for RetryPolicy {
+    fn into_policy(self) -> RetryPolicy {
+        self
+    }
+}
+
+impl IntoRetryPolicy for Vec<StatusCode> {
+    fn into_policy(self) -> RetryPolicy {
+        RetryPolicy::Status(self)
+    }
+}
+
+/// Retries one status, `.policy(StatusCode::BAD_GATEWAY)`
+impl IntoRetryPolicy for StatusCode {
+    fn into_policy(self) -> RetryPolicy {
+        RetryPolicy::Status(vec![self])
+    }
+}
+
+impl<const N: usize> IntoRetryPolicy for [StatusCode; N] {
+    fn into_policy(self) -> RetryPolicy {
+        RetryPolicy::Status(self.to_vec())
+    }
+}
+
+impl IntoRetryPolicy for &[StatusCode] {
+    fn into_policy(self) -> RetryPolicy {
+        RetryPolicy::Status(self.to_vec())
+    }
+}
+
+/// Retries a range of status codes, `.policy(500..=504)`
+impl IntoRetryPolicy for RangeInclusive<u16> {
+    fn into_policy(self) -> RetryPolicy {
+        RetryPolicy::status_range(self)
+    }
+}
+
+/// A shorter name for [`RetryPolicy`], for building policies fluently
+pub type Policy = RetryPolicy;
+
+/// What a single attempt ended with
+pub enum AttemptOutcome<'a> {
+    Response(&'a ClientResponse),
+    Error(&'a SendRequestError),
+}
+
+/// Where a request stands when one of its responses is judged, see
+/// [`RetryPolicy::response_with_context`]
+#[derive(Debug, Clone, Copy)]
+pub struct AttemptContext {
+    pub(crate) attempt: u32,
+    pub(crate) elapsed: Duration,
+    pub(crate) last_status: Option<StatusCode>,
+}
+
+impl AttemptContext {
+    /// The attempt the response belongs to, the first attempt is `1`
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    /// How long ago the first attempt of the request started
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    /// The status of the attempt before this one, `None` for the first attempt and when the
+    /// previous attempt failed without a response
+    pub fn last_status(&self) -> Option<StatusCode> {
+        self.last_status
+    }
+}
+
+/// Everything known about the attempt a policy is asked to judge
+pub struct RetryContext<'a> {
+    pub(crate) attempt: u32,
+    pub(crate) outcome: AttemptOutcome<'a>,
+}
+
+impl<'a> RetryContext<'a> {
+    /// The attempt which just finished, the first attempt is `1`
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    pub fn outcome(&self) -> &AttemptOutcome<'a> {
+        &self.outcome
+    }
+
+    /// The negotiated HTTP version, `None` when the attempt failed before it was known.
+    ///
+    /// HTTP/2 errors imply the version, so `HTTP_2` is returned for them.
+    pub fn version(&self) -> Option<Version> {
+        match self.outcome {
+            AttemptOutcome::Response(res) => Some(res.version()),
+            AttemptOutcome::Error(SendRequestError::H2(_)) => Some(Version::HTTP_2),
+            AttemptOutcome::Error(_) => None,
+        }
+    }
+
+    /// Status of the response, `None` when the attempt failed with an error
+    pub fn status(&self) -> Option<StatusCode> {
+        match self.outcome {
+            AttemptOutcome::Response(res) => Some(res.status()),
+            AttemptOutcome::Error(_) => None,
+        }
+    }
+}
+
+/// The verdict of a [`StatefulPolicy`] on one attempt
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetryDecision {
+    /// Send the request again
+    Retry,
+    /// Hand this outcome to the caller
+    Stop,
+    /// Leave it to the other policies
+    Defer,
+}
+
+/// A policy which remembers what earlier attempts of the same request observed.
+///
+/// A fresh copy of the policy is made for every request, so the state never leaks between
+/// requests. Every copy sees every attempt of its request, in order. If any stateful policy
+/// decides to [`Stop`](RetryDecision::Stop) the outcome is returned, otherwise any
+/// [`Retry`](RetryDecision::Retry) causes another attempt. Requests the request policies don't
+/// match and terminal errors, such as certificate failures, aren't retried either way.
+///
+/// ```
+/// use actix_http::http::{header, StatusCode, Version};
+/// use awc::error::SendRequestError;
+/// use awc_retry::{AttemptOutcome, Retry, RetryContext, RetryDecision, StatefulPolicy};
+///
+/// /// Retries a 503 only when it follows a connect error
+/// #[derive(Clone, Default)]
+/// struct UnavailableAfterConnectError {
+///     connect_failed: bool,
+/// }
+///
+/// impl StatefulPolicy for UnavailableAfterConnectError {
+///     fn decide(&mut self, ctx: &RetryContext<'_>) -> RetryDecision {
+///         let decision = match ctx.outcome() {
+///             AttemptOutcome::Response(res) if res.status() == StatusCode::SERVICE_UNAVAILABLE => {
+///                 if self.connect_failed { RetryDecision::Retry } else { RetryDecision::Stop }
+///             }
+///             _ => RetryDecision::Defer,
+///         };
+///         self.connect_failed = matches!(ctx.outcome(), AttemptOutcome::Error(SendRequestError::Connect(_)));
+///         decision
+///     }
+/// }
+///
+/// let retry = Retry::new(3).stateful_policy(UnavailableAfterConnectError::default());
+/// ```
+pub trait StatefulPolicy {
+    fn decide(&mut self, ctx: &RetryContext<'_>) -> RetryDecision;
+}
+
+/// Merges the decisions of every stateful policy, every policy sees the attempt
+pub(crate) fn decide_all(policies: &mut [Box<dyn StatefulPolicy>], ctx: &RetryContext<'_>) -> RetryDecision {
+    policies.iter_mut().fold(RetryDecision::Defer, |acc, policy| {
+        match (acc, policy.decide(ctx)) {
+            (RetryDecision::Stop, _) | (_, RetryDecision::Stop) => RetryDecision::Stop,
+            (RetryDecision::Retry, _) | (_, RetryDecision::Retry) => RetryDecision::Retry,
+            _ => RetryDecision::Defer,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Retry;
+
+    fn glob(pattern: &str, path: &str) -> bool {
+        glob_matches(pattern.as_bytes(), path.as_bytes())
+    }
+
+    #[test]
+    fn single_stars_and_question_marks_stay_within_a_segment() {
+        assert!(glob("/users/*/avatar", "/users/42/avatar"));
+        assert!(glob("/users/*/avatar", "/users//avatar"));
+        assert!(!glob("/users/*/avatar", "/users/42/7/avatar"));
+        assert!(glob("/v?/items", "/v2/items"));
+        assert!(!glob("/v?/items", "/v/items"));
+        assert!(!glob("/a?b", "/a/b"));
+    }
+
+    #[test]
+    fn double_stars_cross_segments() {
+        assert!(glob("/static/**", "/static/css/site.css"));
+        assert!(glob("/static/**", "/static/"));
+        assert!(glob("/api/**/health", "/api/health"));
+        assert!(glob("/api/**/health", "/api/v1/internal/health"));
+        assert!(!glob("/api/**/health", "/api/v1/healthz"));
+    }
+
+    #[test]
+    fn literals_match_whole_paths() {
+        assert!(glob("/users", "/users"));
+        assert!(!glob("/users", "/users/42"));
+        assert!(!glob("/users/42", "/users"));
+    }
+
+    #[test]
+    fn negated_and_wrapped_method_policies_are_checked_for_keys() {
+        let unkeyed = |policy: RetryPolicy| Retry::new(3).policy(policy).validate().err();
+        assert_eq!(unkeyed(!RetryPolicy::methods(vec![Method::GET])), Some(ConfigError::UnkeyedMethod(Method::POST)));
+        assert_eq!(unkeyed(!RetryPolicy::methods(vec![Method::POST])), Some(ConfigError::UnkeyedMethod(Method::PATCH)));
+        assert_eq!(unkeyed(!RetryPolicy::methods(vec![Method::POST, Method::PATCH])), None);
+        assert_eq!(unkeyed(!!RetryPolicy::methods(vec![Method::GET])), None);
+        let http2_posts = RetryPolicy::methods(vec![Method::POST]).for_version(Version::HTTP_2);
+        assert_eq!(unkeyed(http2_posts), Some(ConfigError::UnkeyedMethod(Method::POST)));
+        let keyed = RetryPolicy::idempotency_key(HeaderName::from_static("idempotency-key"));
+        assert_eq!(unkeyed(keyed), None);
+    }
+}
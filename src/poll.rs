@@ -0,0 +1,83 @@
+//! Long polling with a retried request per poll.
+
+use actix_http::error::PayloadError;
+use awc::error::SendRequestError;
+use awc::ClientResponse;
+use bytes::Bytes;
+use futures::{stream, Stream};
+use std::future::Future;
+
+use crate::SharedRetry;
+
+impl SharedRetry {
+    /// Polls with `request` until `extract` says to stop, yielding what it extracts.
+    ///
+    /// Every poll is sent like [`Retry::send`](crate::Retry::send), so failures are retried
+    /// with this configuration's policies and backoff before a poll is given up on. `extract`
+    /// turns a response into an item and the cursor of the next poll, `None` ends the stream
+    /// after the item. An error, from the retried request or from `extract`, is yielded and
+    /// ends the stream.
+    ///
+    /// ```no_run
+    /// use awc::ClientResponse;
+    /// use awc::error::SendRequestError;
+    /// use awc_retry::Retry;
+    ///
+    /// # fn watch() {
+    /// let client = awc::Client::default();
+    /// let events = Retry::new(3).shared().long_poll(
+    ///     0u64,
+    ///     move |since| client.get(format!("http://localhost:8080/events?since={}", since)).send(),
+    ///     |mut res: ClientResponse| async move {
+    ///         let body = res.body().await.map_err(|e| SendRequestError::Body(e.into()))?;
+    ///         let next = std::str::from_utf8(&body).ok().and_then(|b| b.trim().parse().ok());
+    ///         Ok((body, next))
+    ///     },
+    /// );
+    /// # }
+    /// ```
+    pub fn long_poll<T, C, F, Fut, S, X, XFut>(
+        &self,
+        cursor: C,
+        request: F,
+        extract: X,
+    ) -> impl Stream<Item=Result<T, SendRequestError>>
+        where
+            F: FnMut(&C) -> Fut,
+            Fut: Future<Output=Result<ClientResponse<S>, SendRequestError>>,
+            S: Stream<Item=Result<Bytes, PayloadError>> + Unpin + 'static,
+            X: FnMut(ClientResponse) -> XFut,
+            XFut: Future<Output=Result<(T, Option<C>), SendRequestError>>,
+    {
+        let state = Poll {
+            retry: self.clone(),
+            cursor: Some(cursor),
+            request,
+            extract,
+        };
+
+        stream::unfold(state, |mut state| async move {
+            let cursor = state.cursor.take()?;
+            let request = &mut state.request;
+
+            let item = match state.retry.send(|| request(&cursor)).await {
+                Ok(res) => (state.extract)(res).await,
+                Err(e) => Err(e),
+            };
+            let item = item.map(|(item, next)| {
+                state.cursor = next;
+                item
+            });
+
+            Some((item, state))
+        })
+    }
+}
+
+struct Poll<C, F, X> {
+    retry: SharedRetry,
+    /// Cursor of the next poll, `None` once the stream is done
+    cursor: Option<C>,
+    request: F,
+    extract: X,
+}
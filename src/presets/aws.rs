@@ -0,0 +1,114 @@
+use actix_http::http::header::HeaderName;
+use actix_http::http::StatusCode;
+use actix_web::dev::ResponseHead;
+use actix_web::HttpMessage;
+use awc::ClientResponse;
+use std::time::Duration;
+
+use super::Preset;
+use crate::{Jitter, Retry, RetryPolicy};
+
+/// Longest `200 OK` body searched for an S3 error document
+const ERROR_DOCUMENT_LIMIT: usize = 16 * 1024;
+
+/// Error codes AWS services use for throttling and transient failures
+const RETRYABLE_CODES: &[&str] = &[
+    "Throttling",
+    "ThrottlingException",
+    "ThrottledException",
+    "RequestThrottledException",
+    "TooManyRequestsException",
+    "ProvisionedThroughputExceededException",
+    "TransactionInProgressException",
+    "RequestLimitExceeded",
+    "BandwidthLimitExceeded",
+    "LimitExceededException",
+    "RequestThrottled",
+    "SlowDown",
+    "PriorRequestNotComplete",
+    "EC2ThrottledException",
+    "RequestTimeout",
+    "RequestTimeoutException",
+];
+
+/// The standard retry mode of the AWS SDKs.
+///
+/// Retries `429`, `500`, `502`, `503` and `504` as well as the throttling and timeout error codes
+/// of the JSON protocols' `x-amzn-ErrorType` header, waiting a fully jittered exponential
+/// backoff starting at one second and capped at 20 seconds. Other client errors are terminal.
+/// Codes only reported in XML bodies, as S3 does, aren't seen by this preset.
+/// See [`S3`] for the errors S3 reports in `200 OK` responses.
+pub struct Aws;
+
+impl Preset for Aws {
+    fn apply(self, retry: Retry) -> Retry {
+        retry
+            .jittered_backoff(Duration::from_secs(1), Duration::from_secs(20), Jitter::Full)
+            .policy(|head: &ResponseHead| !is_retryable(head))
+    }
+}
+
+/// [`Aws`] for S3 and S3 compatible object stores, which also retries `200 OK` responses
+/// carrying an `<Error>` document.
+///
+/// `CopyObject`, `UploadPartCopy` and `CompleteMultipartUpload` answer `200 OK` before they are
+/// done and report a failure in the body that follows. XML bodies of `200 OK` responses of up to
+/// 16 KiB are read to look for it and replayed to the caller when there is none, so
+/// [`Retry::streaming_safe`](crate::Retry::streaming_safe) rejects this preset.
+///
+/// ```
+/// use awc_retry::presets::S3;
+/// use awc_retry::Retry;
+///
+/// let retry = Retry::new(3).preset(S3);
+/// ```
+pub struct S3;
+
+impl Preset for S3 {
+    fn apply(self, retry: Retry) -> Retry {
+        retry
+            .preset(Aws)
+            .policy(RetryPolicy::body(ERROR_DOCUMENT_LIMIT, |res, body| !is_error_document(res, body)))
+    }
+}
+
+/// Whether `body` of a `200 OK` is an XML document whose root is `<Error>`, after any whitespace
+/// S3 sends to keep the connection alive and the XML declaration
+fn is_error_document(res: &ClientResponse, body: &[u8]) -> bool {
+    if res.status() != StatusCode::OK || !is_xml(res) {
+        return false;
+    }
+
+    let mut doc = body.trim_ascii_start();
+    if doc.starts_with(b"<?xml") {
+        doc = match doc.windows(2).position(|w| w == b"?>") {
+            Some(end) => doc[end + 2..].trim_ascii_start(),
+            None => return false,
+        };
+    }
+    doc.starts_with(b"<Error>")
+}
+
+fn is_xml(res: &ClientResponse) -> bool {
+    let essence = res.content_type().split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+    essence == "application/xml" || essence == "text/xml"
+}
+
+fn is_retryable(head: &ResponseHead) -> bool {
+    match head.status {
+        StatusCode::TOO_MANY_REQUESTS
+        | StatusCode::INTERNAL_SERVER_ERROR
+        | StatusCode::BAD_GATEWAY
+        | StatusCode::SERVICE_UNAVAILABLE
+        | StatusCode::GATEWAY_TIMEOUT => return true,
+        _ => {}
+    }
+
+    // e.g. `ThrottlingException:http://internal.amazon.com/coral/com.amazon.coral.availability/`
+    let error_type = HeaderName::from_static("x-amzn-errortype");
+    head.headers
+        .get(error_type)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(':').next())
+        .is_some_and(|code| RETRYABLE_CODES.contains(&code.trim()))
+}
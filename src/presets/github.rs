@@ -0,0 +1,66 @@
+use actix_http::http::header::HeaderName;
+use actix_http::http::StatusCode;
+use actix_web::dev::ResponseHead;
+use awc::ClientResponse;
+use std::time::Duration;
+
+use super::{header, response, retry_after, Preset};
+use crate::hints::rate_limit_reset;
+use crate::{Jitter, Retry};
+
+/// Rate limit waits longer than this are handed back to the caller instead of slept through
+const MAX_WAIT: Duration = Duration::from_secs(60);
+
+/// The rate limiting conventions of the GitHub REST API.
+///
+/// `403` and `429` responses are retried when they are rate limits, i.e. they carry a
+/// `Retry-After` or an exhausted `x-ratelimit-remaining`, waiting until the time those headers
+/// give. Other `403`s are permission errors and never retried, nor are rate limits which only
+/// reset more than a minute from now. `500`, `502`, `503` and `504` are retried with a jittered
+/// exponential backoff from one second up to 30 seconds.
+pub struct GitHub;
+
+impl Preset for GitHub {
+    fn apply(self, retry: Retry) -> Retry {
+        retry
+            .jittered_backoff(Duration::from_secs(1), Duration::from_secs(30), Jitter::Full)
+            .policy(|head: &ResponseHead| !is_retryable(head))
+            .wait_hint(|ctx| response(ctx).and_then(rate_limit_wait).filter(|wait| *wait <= MAX_WAIT))
+            .veto(|ctx| match response(ctx) {
+                Some(res) if is_limited_status(res.status()) => {
+                    rate_limit_wait(res).is_none_or(|wait| wait > MAX_WAIT)
+                }
+                _ => false,
+            })
+    }
+}
+
+fn is_limited_status(status: StatusCode) -> bool {
+    status == StatusCode::FORBIDDEN || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+fn is_retryable(head: &ResponseHead) -> bool {
+    match head.status {
+        StatusCode::INTERNAL_SERVER_ERROR
+        | StatusCode::BAD_GATEWAY
+        | StatusCode::SERVICE_UNAVAILABLE
+        | StatusCode::GATEWAY_TIMEOUT => true,
+        status if is_limited_status(status) => {
+            head.headers.contains_key("retry-after")
+                || head.headers.get("x-ratelimit-remaining").is_some_and(|v| v == "0")
+        }
+        _ => false,
+    }
+}
+
+/// How long until the rate limit `res` reports is lifted, `None` if it doesn't report one
+fn rate_limit_wait(res: &ClientResponse) -> Option<Duration> {
+    if let Some(wait) = retry_after(res) {
+        return Some(wait);
+    }
+
+    if header(res, &HeaderName::from_static("x-ratelimit-remaining"))? != "0" {
+        return None;
+    }
+    rate_limit_reset(res)
+}
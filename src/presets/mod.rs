@@ -0,0 +1,62 @@
+//! Retry conventions of popular APIs, enabled by the `presets` feature.
+//!
+//! A preset adds the policies, waits and vetoes a provider documents for its clients, on top of
+//! whatever the [`Retry`] was already configured with.
+//!
+//! ```
+//! use awc_retry::presets::GitHub;
+//! use awc_retry::Retry;
+//!
+//! let retry = Retry::new(3).preset(GitHub);
+//! ```
+
+use actix_http::http::header::HeaderName;
+use awc::ClientResponse;
+use std::time::Duration;
+
+use crate::hints::retry_after;
+use crate::{AttemptOutcome, ExponentialBackoff, Jitter, RetryContext, Retry};
+
+mod aws;
+mod github;
+mod stripe;
+
+pub use aws::{Aws, S3};
+pub use github::GitHub;
+pub use stripe::Stripe;
+
+/// A bundle of retry settings applied by [`Retry::preset`]
+pub trait Preset {
+    fn apply(self, retry: Retry) -> Retry;
+}
+
+impl Retry {
+    /// Adds the retry conventions of `preset`, see the [`presets`](crate::presets) module
+    pub fn preset<P: Preset>(self, preset: P) -> Self {
+        preset.apply(self)
+    }
+
+    /// Replaces the backoff with exponential delays from `base` up to `cap`, jittered
+    fn jittered_backoff(self, base: Duration, cap: Duration, jitter: Jitter) -> Self {
+        self.backoff(ExponentialBackoff::new(base).max(cap)).jitter(jitter)
+    }
+
+    fn wait_hint<F>(mut self, f: F) -> Self
+        where F: Fn(&RetryContext<'_>) -> Option<Duration> + 'static
+    {
+        self.0.wait_hints.push(Box::new(f));
+        self
+    }
+}
+
+/// The response of the attempt `ctx` describes, if it got one
+fn response<'a>(ctx: &RetryContext<'a>) -> Option<&'a ClientResponse> {
+    match ctx.outcome() {
+        AttemptOutcome::Response(res) => Some(*res),
+        AttemptOutcome::Error(_) => None,
+    }
+}
+
+fn header<'a>(res: &'a ClientResponse, name: &HeaderName) -> Option<&'a str> {
+    res.headers().get(name).and_then(|v| v.to_str().ok()).map(str::trim)
+}
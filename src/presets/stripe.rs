@@ -0,0 +1,48 @@
+use actix_http::http::header::HeaderName;
+use actix_http::http::StatusCode;
+use actix_web::dev::ResponseHead;
+use std::time::Duration;
+
+use super::{header, response, retry_after, Preset};
+use crate::{Jitter, Retry};
+
+/// `Retry-After` waits longer than this are ignored in favour of the backoff
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+/// The retry behaviour of Stripe's client libraries.
+///
+/// A `Stripe-Should-Retry` header decides on its own: `true` retries and `false` is terminal,
+/// even for statuses other policies retry. Without it `409` lock conflicts, `429` and `5xx`
+/// gateway and server errors are retried. Waits follow `Retry-After` up to a minute, otherwise
+/// a backoff doubling from half a second up to two seconds, with up to half of each delay
+/// taken off at random.
+pub struct Stripe;
+
+impl Preset for Stripe {
+    fn apply(self, retry: Retry) -> Retry {
+        retry
+            .jittered_backoff(Duration::from_millis(500), Duration::from_secs(2), Jitter::Equal)
+            .policy(|head: &ResponseHead| !is_retryable(head))
+            .wait_hint(|ctx| response(ctx).and_then(retry_after).filter(|wait| *wait <= MAX_RETRY_AFTER))
+            .veto(|ctx| {
+                response(ctx).and_then(|res| header(res, &should_retry())) == Some("false")
+            })
+    }
+}
+
+fn should_retry() -> HeaderName {
+    HeaderName::from_static("stripe-should-retry")
+}
+
+fn is_retryable(head: &ResponseHead) -> bool {
+    match head.headers.get(should_retry()).and_then(|v| v.to_str().ok()) {
+        Some("true") => return true,
+        Some("false") => return false,
+        _ => {}
+    }
+
+    match head.status {
+        StatusCode::CONFLICT | StatusCode::TOO_MANY_REQUESTS => true,
+        status => status.is_server_error(),
+    }
+}
@@ -0,0 +1,53 @@
+//! Lightweight requests checking an origin is back before a large body is sent to it again.
+
+use actix_http::http::uri::{Parts, PathAndQuery, Uri};
+use actix_http::http::{Method, StatusCode};
+use actix_http::RequestHeadType;
+use awc::error::SendRequestError;
+use awc::ConnectResponse;
+
+use crate::head::owned_head;
+use crate::ReplayBody;
+
+pub(crate) struct Probe {
+    /// Bodies this long or longer are probed for
+    min_body: usize,
+    /// Probed instead of the request's own path and query
+    path: Option<PathAndQuery>,
+}
+
+impl Probe {
+    pub(crate) fn new(min_body: usize, path: Option<PathAndQuery>) -> Self {
+        Probe { min_body, path }
+    }
+
+    pub(crate) fn applies_to(&self, body: &ReplayBody) -> bool {
+        matches!(body, ReplayBody::Bytes(b) if b.len() >= self.min_body)
+    }
+
+    /// A `HEAD` request to the origin of `head`
+    pub(crate) fn head(&self, head: &RequestHeadType) -> RequestHeadType {
+        let mut probe = owned_head(head);
+        probe.method = Method::HEAD;
+        if let Some(path) = &self.path {
+            let mut parts = Parts::from(probe.uri.clone());
+            parts.path_and_query = Some(path.clone());
+            if let Ok(uri) = Uri::from_parts(parts) {
+                probe.uri = uri;
+            }
+        }
+
+        RequestHeadType::Owned(probe)
+    }
+
+    /// The origin answered and didn't report being overloaded or broken
+    pub(crate) fn passed(res: &Result<ConnectResponse, SendRequestError>) -> bool {
+        match res {
+            Ok(ConnectResponse::Client(res)) => {
+                !res.status().is_server_error() && res.status() != StatusCode::TOO_MANY_REQUESTS
+            }
+            Ok(_) => true,
+            Err(_) => false,
+        }
+    }
+}
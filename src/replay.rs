@@ -0,0 +1,105 @@
+//! Retrying arbitrary services with the same engine as the middleware.
+
+use actix_service::Service;
+use futures::future::{poll_fn, LocalBoxFuture};
+use futures::task::{Context, Poll};
+use std::rc::Rc;
+
+use crate::attempt::Attempts;
+use crate::state::Counter;
+use crate::{Inner, Retry};
+
+/// A request which can be sent again, [`replay`](Replayable::replay) makes the copy for the next attempt
+pub trait Replayable {
+    fn replay(&self) -> Self;
+}
+
+/// Wraps any service so its calls are retried according to `retry`.
+///
+/// The HTTP specific parts of the configuration (response policies, stateful policies, vetoes,
+/// circuit breakers, connectors and fallbacks) have nothing to look at, while the attempt limit,
+/// backoff and dry-run mode apply as usual. By default every error is retried, use [`RetryFn::retry_if`] to decide
+/// based on the whole result instead.
+///
+/// ```
+/// use actix_service::{fn_service, Service};
+/// use awc_retry::{retry_fn, Replayable, Retry};
+///
+/// #[derive(Clone)]
+/// struct Lookup(String);
+///
+/// impl Replayable for Lookup {
+///     fn replay(&self) -> Self {
+///         self.clone()
+///     }
+/// }
+///
+/// let resolver = fn_service(|req: Lookup| async move { Ok::<_, ()>(req.0.len()) });
+/// let resolver = retry_fn(resolver, Retry::new(2)).retry_if(|res| !matches!(res, Ok(0)));
+/// ```
+pub fn retry_fn<S, Req>(service: S, retry: Retry) -> RetryFn<S, Req>
+    where S: Service<Req>
+{
+    RetryFn {
+        inner: Rc::new(retry.0),
+        service: Rc::new(service),
+        retry_if: Rc::new(|res: &Result<S::Response, S::Error>| res.is_err()),
+    }
+}
+
+/// A service retried by [`retry_fn`]
+pub struct RetryFn<S: Service<Req>, Req> {
+    inner: Rc<Inner>,
+    service: Rc<S>,
+    retry_if: Rc<RetryIfFn<S, Req>>,
+}
+
+type RetryIfFn<S, Req> = dyn Fn(&Result<<S as Service<Req>>::Response, <S as Service<Req>>::Error>) -> bool;
+
+impl<S: Service<Req>, Req> RetryFn<S, Req> {
+    /// Sets which results are retried, by default only errors are
+    pub fn retry_if<F>(mut self, f: F) -> Self
+        where F: Fn(&Result<S::Response, S::Error>) -> bool + 'static
+    {
+        self.retry_if = Rc::new(f);
+        self
+    }
+}
+
+impl<S, Req> Service<Req> for RetryFn<S, Req>
+    where
+        S: Service<Req> + 'static,
+        Req: Replayable + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&self, ctx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(ctx)
+    }
+
+    fn call(&self, req: Req) -> Self::Future {
+        let service = self.service.clone();
+        let inner = self.inner.clone();
+        let retry_if = self.retry_if.clone();
+
+        Box::pin(async move {
+            let mut attempts = Attempts::new(&inner);
+            loop {
+                if attempts.tries() > 0 {
+                    poll_fn(|cx| service.poll_ready(cx)).await?;
+                }
+
+                let res = service.call(req.replay()).await;
+                attempts.count(Counter::Attempt);
+
+                let outcome = if res.is_ok() { "response" } else { "error" };
+                match attempts.schedule(retry_if(&res), None, None, &"service call", &outcome) {
+                    Some(delay) if attempts.wait(delay, None, &"service call", &outcome).await => {}
+                    _ => return res,
+                }
+            }
+        })
+    }
+}
@@ -0,0 +1,174 @@
+//! Resuming downloads cut short with `Range` requests for the rest of the body.
+
+use actix_http::error::PayloadError;
+use actix_http::http::header::{self, HeaderValue};
+use actix_http::http::{Method, StatusCode};
+use actix_http::{Payload, PayloadStream, RequestHeadType};
+use actix_web::body::Body;
+use actix_web::HttpMessage;
+use awc::error::SendRequestError;
+use awc::{ClientResponse, ConnectRequest, ConnectResponse};
+use bytes::Bytes;
+use futures::future::LocalBoxFuture;
+use futures::{stream, StreamExt};
+use std::net::SocketAddr;
+use std::rc::Rc;
+
+use crate::{body, head, Retry};
+
+pub(crate) struct Resume {
+    /// Shortest body worth resuming rather than retrying from its start
+    min_length: u64,
+}
+
+/// Sends a request the way the attempt which got the response was sent
+pub(crate) type SendFn = dyn Fn(ConnectRequest) -> LocalBoxFuture<'static, Result<ConnectResponse, SendRequestError>>;
+
+/// A body being read, and what is needed to request the rest of it
+struct Download {
+    payload: Payload,
+    /// Bytes of the body read so far
+    offset: u64,
+    length: u64,
+    /// Strong `ETag` every part has to come with
+    etag: HeaderValue,
+    resumes: u8,
+    head: RequestHeadType,
+    addr: Option<SocketAddr>,
+    send: Rc<SendFn>,
+    done: bool,
+}
+
+impl Resume {
+    /// Makes the body of `res`, the response to `head` sent to `addr` with `send`, resume where
+    /// it broke off up to `resumes` times.
+    ///
+    /// Only complete `200 OK` responses to `GET` with a strong `ETag`, `Accept-Ranges: bytes` and
+    /// a `Content-Length` of at least `min_length` can be resumed, other responses are returned
+    /// as they are.
+    pub(crate) fn wrap(
+        &self,
+        mut res: ClientResponse,
+        head: RequestHeadType,
+        addr: Option<SocketAddr>,
+        send: Rc<SendFn>,
+        resumes: u8,
+    ) -> ClientResponse {
+        let etag = res.headers().get(header::ETAG).filter(|etag| !etag.as_bytes().starts_with(b"W/")).cloned();
+        let ranges = res.headers().get(header::ACCEPT_RANGES).is_some_and(|v| v.as_bytes().eq_ignore_ascii_case(b"bytes"));
+        let length = body::declared_length(&res).map(|len| len as u64);
+        let (etag, length) = match (etag, length) {
+            (Some(etag), Some(length))
+                if head.as_ref().method == Method::GET
+                    && res.status() == StatusCode::OK
+                    && ranges
+                    && resumes > 0
+                    && length >= self.min_length => (etag, length),
+            _ => return res,
+        };
+
+        let download = Download {
+            payload: res.take_payload(),
+            offset: 0,
+            length,
+            etag,
+            resumes,
+            head,
+            addr,
+            send,
+            done: false,
+        };
+        let stream: PayloadStream = Box::pin(stream::unfold(download, Download::next));
+        res.map_body(|_, _| Payload::Stream(stream))
+    }
+}
+
+impl Download {
+    async fn next(mut self) -> Option<(Result<Bytes, PayloadError>, Self)> {
+        if self.done {
+            return None;
+        }
+
+        loop {
+            let failure = match self.payload.next().await {
+                Some(Ok(chunk)) => {
+                    self.offset += chunk.len() as u64;
+                    return Some((Ok(chunk), self));
+                }
+                None if self.offset >= self.length => return None,
+                // Connections closing early can end bodies without an error
+                None => PayloadError::Incomplete(None),
+                Some(Err(e)) => e,
+            };
+
+            let payload = match self.resumes {
+                0 => None,
+                _ => {
+                    self.resumes -= 1;
+                    self.reopen().await
+                }
+            };
+            match payload {
+                Some(payload) => self.payload = payload,
+                None => {
+                    self.done = true;
+                    return Some((Err(failure), self));
+                }
+            }
+        }
+    }
+
+    /// Requests the body from where it broke off, the response has to be of the same version
+    /// of it
+    async fn reopen(&self) -> Option<Payload> {
+        log::debug!("resuming {} at byte {} of {}", self.head.as_ref().uri, self.offset, self.length);
+
+        let mut head = head::owned_head(&self.head);
+        let range = HeaderValue::from_str(&format!("bytes={}-", self.offset)).ok()?;
+        head.headers.insert(header::RANGE, range);
+        head.headers.insert(header::IF_RANGE, self.etag.clone());
+
+        let req = ConnectRequest::Client(RequestHeadType::Owned(head), Body::None, self.addr);
+        let mut res = match (self.send)(req).await {
+            Ok(ConnectResponse::Client(res)) => res,
+            _ => return None,
+        };
+
+        let start = res.headers()
+            .get(header::CONTENT_RANGE)
+            .and_then(|v| v.to_str().ok()?.strip_prefix("bytes ")?.split('-').next()?.trim().parse::<u64>().ok());
+        if res.status() != StatusCode::PARTIAL_CONTENT
+            || res.headers().get(header::ETAG) != Some(&self.etag)
+            || start != Some(self.offset)
+        {
+            log::debug!("not resuming {}, the server answered {} without the rest of the body", self.head.as_ref().uri, res.status());
+            return None;
+        }
+
+        Some(res.take_payload())
+    }
+}
+
+impl Retry {
+    /// Resumes `GET` bodies of at least `min_length` bytes where they broke off, instead of
+    /// leaving the caller with a failed download.
+    ///
+    /// The rest of the body is requested with `Range: bytes=N-` and `If-Range` and streamed on
+    /// as part of the same body, up to [`max_retries`](Retry::new) times per response. This
+    /// works for `200 OK` responses carrying a strong `ETag` and `Accept-Ranges: bytes`, and
+    /// only goes on with parts whose `ETag` is the same, so the pieces are of a single version
+    /// of the resource. Resumed parts are requested right away the way the attempt which got the
+    /// response was sent, its headers, address and connector, without the policies and backoff
+    /// of the retries and only as many times as retries were left, going by the adaptive limit
+    /// and the [`budget`](Retry::budget) too.
+    ///
+    /// ```
+    /// use awc_retry::Retry;
+    ///
+    /// let retry = Retry::new(3).resume_downloads(1024 * 1024);
+    /// ```
+    pub fn resume_downloads(mut self, min_length: u64) -> Self {
+        self.0.resume = Some(Resume { min_length });
+        self
+    }
+}
@@ -0,0 +1,276 @@
+//! Retries around `ClientRequest::send*()`, for clients which can't be wrapped with the middleware.
+
+use actix_http::error::PayloadError;
+use actix_http::{Payload, PayloadStream};
+use awc::error::SendRequestError;
+use awc::ClientResponse;
+use bytes::Bytes;
+use futures::channel::mpsc;
+use futures::Stream;
+use std::future::Future;
+
+use crate::attempt::{Attempts, Outcome, Step};
+use crate::body;
+use crate::events::{AttemptEvents, EventSender};
+use crate::{Inner, Retry, SendBodyError, SharedRetry};
+
+impl Inner {
+    async fn send<F, Fut, S>(&self, factory: F) -> Result<ClientResponse, SendRequestError>
+        where
+            F: FnMut() -> Fut,
+            Fut: Future<Output=Result<ClientResponse<S>, SendRequestError>>,
+            S: Stream<Item=Result<Bytes, PayloadError>> + Unpin + 'static,
+    {
+        self.send_with(factory, None).await
+    }
+
+    async fn send_with<F, Fut, S>(
+        &self,
+        mut factory: F,
+        events: Option<EventSender>,
+    ) -> Result<ClientResponse, SendRequestError>
+        where
+            F: FnMut() -> Fut,
+            Fut: Future<Output=Result<ClientResponse<S>, SendRequestError>>,
+            S: Stream<Item=Result<Bytes, PayloadError>> + Unpin + 'static,
+    {
+        let mut attempts = Attempts::new(self);
+        if let Some(events) = events {
+            attempts.observe(events);
+        }
+
+        let result = loop {
+            if let Err(e) = attempts.permit().await {
+                break Err(e);
+            }
+
+            attempts.start();
+            let res = attempts.bounded(factory()).await.map(|res| {
+                res.map_body(|_, payload| {
+                    let stream: PayloadStream = Box::pin(payload);
+                    Payload::Stream(stream)
+                })
+            });
+
+            match attempts.judge(res, &"request").await {
+                Step::Retry(outcome, delay) => {
+                    if !attempts.retry(&outcome, delay, &"request").await {
+                        break outcome.into_result();
+                    }
+                }
+                Step::Done(outcome) => break outcome.into_result(),
+            }
+        };
+
+        self.finish(result)
+    }
+
+    async fn send_and_body<F, Fut, S>(&self, mut factory: F, limit: usize) -> Result<(ClientResponse, Bytes), SendBodyError>
+        where
+            F: FnMut() -> Fut,
+            Fut: Future<Output=Result<ClientResponse<S>, SendRequestError>>,
+            S: Stream<Item=Result<Bytes, PayloadError>> + Unpin + 'static,
+    {
+        let mut attempts = Attempts::new(self);
+
+        loop {
+            attempts.permit().await?;
+
+            attempts.start();
+            let res = attempts.bounded(factory()).await.map(|res| {
+                res.map_body(|_, payload| {
+                    let stream: PayloadStream = Box::pin(payload);
+                    Payload::Stream(stream)
+                })
+            });
+
+            let mut res = match attempts.judge(res, &"request").await {
+                Step::Retry(outcome, delay) => {
+                    if attempts.retry(&outcome, delay, &"request").await {
+                        continue;
+                    }
+                    self.finish(outcome.into_result())?
+                }
+                Step::Done(outcome) => self.finish(outcome.into_result())?,
+            };
+
+            let err = match res.body().limit(limit).await {
+                // Connections closing early can end bodies without an error
+                Ok(body) if body::declared_length(&res).is_none_or(|len| body.len() >= len) => return Ok((res, body)),
+                Ok(_) => PayloadError::Incomplete(None),
+                Err(e) => e,
+            };
+            let outcome = Outcome::Response(res);
+            let delay = attempts.schedule(is_truncation(&err), None, None, &"request", &format_args!("{}, body: {}", outcome, err));
+            match delay {
+                Some(delay) if attempts.retry(&outcome, delay, &"request").await => {}
+                _ => return Err(err.into()),
+            }
+        }
+    }
+}
+
+/// Whether reading a body failed because the connection broke off, rather than for the body
+/// itself
+fn is_truncation(err: &PayloadError) -> bool {
+    matches!(err, PayloadError::Incomplete(_) | PayloadError::Io(_) | PayloadError::Http2Payload(_))
+}
+
+/// Runs `factory` under `retry`, calling it again for every retry.
+///
+/// The free function form of [`Retry::send`], for call sites which keep a configuration around
+/// rather than building a client with the middleware.
+///
+/// ```no_run
+/// use actix_http::http::StatusCode;
+/// use awc_retry::Retry;
+///
+/// # async fn fetch() -> Result<(), awc::error::SendRequestError> {
+/// let client = awc::Client::default();
+/// let retry = Retry::new(3).policy(vec![StatusCode::BAD_GATEWAY]);
+///
+/// let res = awc_retry::run(&retry, || async { client.get("http://localhost:8080/").send().await }).await?;
+/// # Ok(())
+/// # }
+/// ```
+pub async fn run<F, Fut, S>(retry: &Retry, factory: F) -> Result<ClientResponse, SendRequestError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output=Result<ClientResponse<S>, SendRequestError>>,
+        S: Stream<Item=Result<Bytes, PayloadError>> + Unpin + 'static,
+{
+    retry.0.send(factory).await
+}
+
+impl Retry {
+    /// Sends the request built by `factory`, building and sending a new one for every retry.
+    ///
+    /// This applies the same policies as the middleware to a client which wasn't built with it,
+    /// retrying the whole request including redirects followed by the client. Options which
+    /// need the middleware's view of the request, i.e. alternate connectors, fallbacks and
+    /// shadowing, are not used. The body of the returned response is boxed so responses from
+    /// clients with and without decompression can be checked by the same policies.
+    ///
+    /// ```no_run
+    /// use actix_http::http::StatusCode;
+    /// use awc_retry::Retry;
+    ///
+    /// # async fn run() -> Result<(), awc::error::SendRequestError> {
+    /// let client = awc::Client::default();
+    /// let retry = Retry::new(3).policy(vec![StatusCode::SERVICE_UNAVAILABLE]);
+    ///
+    /// let res = retry.send(|| client.get("http://localhost:8080/health").send()).await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn send<F, Fut, S>(&self, factory: F) -> Result<ClientResponse, SendRequestError>
+        where
+            F: FnMut() -> Fut,
+            Fut: Future<Output=Result<ClientResponse<S>, SendRequestError>>,
+            S: Stream<Item=Result<Bytes, PayloadError>> + Unpin + 'static,
+    {
+        self.0.send(factory).await
+    }
+
+    /// Same as [`send`](Retry::send), also returning a stream of the request's attempts as they
+    /// finish.
+    ///
+    /// The stream ends when the request is done, so it can be polled alongside the response
+    /// future or drained afterwards.
+    ///
+    /// ```no_run
+    /// use futures::StreamExt;
+    /// use awc_retry::Retry;
+    ///
+    /// # async fn fetch() {
+    /// let client = awc::Client::default();
+    /// let retry = Retry::new(3);
+    ///
+    /// let (res, mut events) = retry.send_observed(|| client.get("http://localhost:8080/").send());
+    /// let progress = async {
+    ///     while let Some(event) = events.next().await {
+    ///         println!("attempt {} finished, retrying in {:?}", event.attempt(), event.retry_in());
+    ///     }
+    /// };
+    /// let (res, _) = futures::join!(res, progress);
+    /// # }
+    /// ```
+    pub fn send_observed<'a, F, Fut, S>(
+        &'a self,
+        factory: F,
+    ) -> (impl Future<Output=Result<ClientResponse, SendRequestError>> + 'a, AttemptEvents)
+        where
+            F: FnMut() -> Fut + 'a,
+            Fut: Future<Output=Result<ClientResponse<S>, SendRequestError>> + 'a,
+            S: Stream<Item=Result<Bytes, PayloadError>> + Unpin + 'static,
+    {
+        let (tx, rx) = mpsc::unbounded();
+        (self.0.send_with(factory, Some(tx)), AttemptEvents(rx))
+    }
+
+    /// Same as [`send`](Retry::send), also reading the body of the response, of at most `limit`
+    /// bytes, and retrying when the connection breaks off while it is read.
+    ///
+    /// Body failures happen after the middleware returned the response, so it can't retry them.
+    /// They count as retries like any other, but aren't checked by the policies: all failures
+    /// reading a body short of its end are retried, including bodies ending before their
+    /// `Content-Length`, bodies longer than `limit` or failing to decode are returned as errors
+    /// right away. `HEAD` requests, whose responses declare a length without a body, should be
+    /// sent with [`send`](Retry::send).
+    ///
+    /// ```no_run
+    /// use awc_retry::{Retry, SendBodyError};
+    ///
+    /// # async fn fetch() -> Result<(), SendBodyError> {
+    /// let client = awc::Client::default();
+    /// let retry = Retry::new(3).policy(500..=599);
+    ///
+    /// let (res, body) = retry.send_and_body(|| client.get("http://localhost:8080/report").send(), 1 << 20).await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn send_and_body<F, Fut, S>(&self, factory: F, limit: usize) -> Result<(ClientResponse, Bytes), SendBodyError>
+        where
+            F: FnMut() -> Fut,
+            Fut: Future<Output=Result<ClientResponse<S>, SendRequestError>>,
+            S: Stream<Item=Result<Bytes, PayloadError>> + Unpin + 'static,
+    {
+        self.0.send_and_body(factory, limit).await
+    }
+}
+
+impl SharedRetry {
+    /// Same as [`Retry::send`]
+    pub async fn send<F, Fut, S>(&self, factory: F) -> Result<ClientResponse, SendRequestError>
+        where
+            F: FnMut() -> Fut,
+            Fut: Future<Output=Result<ClientResponse<S>, SendRequestError>>,
+            S: Stream<Item=Result<Bytes, PayloadError>> + Unpin + 'static,
+    {
+        self.0.send(factory).await
+    }
+
+    /// Same as [`Retry::send_observed`]
+    pub fn send_observed<'a, F, Fut, S>(
+        &'a self,
+        factory: F,
+    ) -> (impl Future<Output=Result<ClientResponse, SendRequestError>> + 'a, AttemptEvents)
+        where
+            F: FnMut() -> Fut + 'a,
+            Fut: Future<Output=Result<ClientResponse<S>, SendRequestError>> + 'a,
+            S: Stream<Item=Result<Bytes, PayloadError>> + Unpin + 'static,
+    {
+        let (tx, rx) = mpsc::unbounded();
+        (self.0.send_with(factory, Some(tx)), AttemptEvents(rx))
+    }
+
+    /// Same as [`Retry::send_and_body`]
+    pub async fn send_and_body<F, Fut, S>(&self, factory: F, limit: usize) -> Result<(ClientResponse, Bytes), SendBodyError>
+        where
+            F: FnMut() -> Fut,
+            Fut: Future<Output=Result<ClientResponse<S>, SendRequestError>>,
+            S: Stream<Item=Result<Bytes, PayloadError>> + Unpin + 'static,
+    {
+        self.0.send_and_body(factory, limit).await
+    }
+}
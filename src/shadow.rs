@@ -0,0 +1,56 @@
+//! Mirroring of requests which needed retries to a debug endpoint.
+
+use actix_http::http::header::{self, HeaderValue};
+use actix_http::http::uri::{Parts, Uri};
+use actix_http::RequestHeadType;
+
+use crate::head::owned_head;
+
+pub(crate) struct Shadow {
+    /// Scheme and authority the mirrored requests are sent to, path and query are kept
+    endpoint: Uri,
+    /// Fraction of retried requests to mirror, between 0 and 1
+    ratio: f64,
+}
+
+impl Shadow {
+    pub(crate) fn new(endpoint: Uri, percent: u8) -> Self {
+        Shadow {
+            endpoint,
+            ratio: f64::from(percent.min(100)) / 100.0,
+        }
+    }
+
+    pub(crate) fn sample(&self) -> bool {
+        rand::random::<f64>() < self.ratio
+    }
+
+    /// Copies `head` with its URI, and `Host` header if it has one, pointed at the shadow
+    /// endpoint
+    pub(crate) fn head(&self, head: &RequestHeadType) -> RequestHeadType {
+        let mut shadow = owned_head(head);
+        shadow.uri = self.uri(&shadow.uri);
+        if shadow.headers.contains_key(header::HOST) {
+            match self.endpoint.authority().and_then(|a| HeaderValue::from_str(a.as_str()).ok()) {
+                Some(host) => {
+                    shadow.headers.insert(header::HOST, host);
+                }
+                None => {
+                    shadow.headers.remove(header::HOST);
+                }
+            }
+        }
+
+        RequestHeadType::Owned(shadow)
+    }
+
+    fn uri(&self, original: &Uri) -> Uri {
+        let endpoint = self.endpoint.clone().into_parts();
+        let mut parts = Parts::default();
+        parts.scheme = endpoint.scheme;
+        parts.authority = endpoint.authority;
+        parts.path_and_query = original.path_and_query().cloned();
+
+        Uri::from_parts(parts).unwrap_or_else(|_| self.endpoint.clone())
+    }
+}
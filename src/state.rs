@@ -0,0 +1,168 @@
+//! State shared by several [`Retry`](crate::Retry) configurations, across threads.
+
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+/// A handle to process wide retry state, see [`Retry::shared_state`](crate::Retry::shared_state).
+///
+/// Clones share the same state and can be sent to other threads, so every worker's client can
+/// report into one handle. Failsafe circuit breakers are shared the same way, by handing clones
+/// of one state machine to each [`Retry`](crate::Retry).
+///
+/// ```
+/// use awc_retry::{Retry, SharedState};
+///
+/// let state = SharedState::new();
+///
+/// let worker_state = state.clone();
+/// std::thread::spawn(move || {
+///     let retry = Retry::new(3).shared_state(worker_state);
+///     // ... build the worker's client with `retry`
+/// });
+///
+/// println!("{} retries so far", state.stats().retries);
+/// ```
+#[derive(Clone, Default)]
+pub struct SharedState(Arc<Counters>);
+
+#[derive(Default)]
+struct Counters {
+    requests: AtomicU64,
+    attempts: AtomicU64,
+    retries: AtomicU64,
+    give_ups: AtomicU64,
+    head_micros: AtomicU64,
+    history: Mutex<History>,
+}
+
+/// The latest retry decisions, oldest first
+#[derive(Default)]
+struct History {
+    capacity: usize,
+    entries: VecDeque<HistoryEntry>,
+}
+
+/// A retry decision kept by [`SharedState::with_history`], the same one the
+/// [audit log](crate::Retry::audit_log) gets a line for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[non_exhaustive]
+pub struct HistoryEntry {
+    pub time: SystemTime,
+    /// Authority the request was sent to, when known
+    pub host: Option<String>,
+    /// The attempt the decision was made after, the first attempt is `1`
+    pub attempt: u32,
+    /// `retry` or `return`
+    pub decision: &'static str,
+    /// Why, such as `policy`, `max_retries` or `vetoed`
+    pub reason: &'static str,
+    /// Delay before the retry
+    pub delay: Option<Duration>,
+    /// Status or error the attempt ended with
+    pub outcome: String,
+}
+
+/// What a [`SharedState`] counted, at the time of [`SharedState::stats`].
+///
+/// With the `serde` feature it implements `Serialize`, for health endpoints and reports.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[non_exhaustive]
+pub struct Stats {
+    /// Requests which were sent at least once
+    pub requests: u64,
+    /// Attempts which finished, including the first of every request
+    pub attempts: u64,
+    /// Attempts which were retries
+    pub retries: u64,
+    /// Requests which ran out of retries or backoff while the policies still asked for one
+    pub give_ups: u64,
+    /// The [`Timings::to_head`](crate::Timings::to_head) of all attempts added up, divide by
+    /// `attempts` for the average
+    pub head_time: Duration,
+}
+
+#[derive(Clone, Copy)]
+pub(crate) enum Counter {
+    Request,
+    Attempt,
+    Retry,
+    GiveUp,
+}
+
+impl SharedState {
+    pub fn new() -> Self {
+        SharedState::default()
+    }
+
+    /// Also keeps the last `capacity` retry decisions, see [`recent`](SharedState::recent).
+    pub fn with_history(capacity: usize) -> Self {
+        let state = SharedState::default();
+        state.history().capacity = capacity;
+        state
+    }
+
+    /// The retry decisions kept by [`with_history`](SharedState::with_history), oldest first,
+    /// such as for a debug endpoint.
+    ///
+    /// ```
+    /// use awc_retry::{Retry, SharedState};
+    ///
+    /// let state = SharedState::with_history(100);
+    /// let retry = Retry::new(3).shared_state(state.clone());
+    /// // ... send requests with `retry`
+    /// for entry in state.recent() {
+    ///     println!("{:?} {:?}: {} ({})", entry.time, entry.host, entry.decision, entry.reason);
+    /// }
+    /// ```
+    pub fn recent(&self) -> Vec<HistoryEntry> {
+        self.history().entries.iter().cloned().collect()
+    }
+
+    pub fn stats(&self) -> Stats {
+        let counters = &self.0;
+        Stats {
+            requests: counters.requests.load(Ordering::Relaxed),
+            attempts: counters.attempts.load(Ordering::Relaxed),
+            retries: counters.retries.load(Ordering::Relaxed),
+            give_ups: counters.give_ups.load(Ordering::Relaxed),
+            head_time: Duration::from_micros(counters.head_micros.load(Ordering::Relaxed)),
+        }
+    }
+
+    pub(crate) fn count(&self, counter: Counter) {
+        let counters = &self.0;
+        let counter = match counter {
+            Counter::Request => &counters.requests,
+            Counter::Attempt => &counters.attempts,
+            Counter::Retry => &counters.retries,
+            Counter::GiveUp => &counters.give_ups,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record(&self, entry: impl FnOnce() -> HistoryEntry) {
+        let mut history = self.history();
+        if history.capacity == 0 {
+            return;
+        }
+        if history.entries.len() >= history.capacity {
+            history.entries.pop_front();
+        }
+        history.entries.push_back(entry());
+    }
+
+    fn history(&self) -> std::sync::MutexGuard<'_, History> {
+        // The history is only ever appended to, a panic elsewhere can't leave it inconsistent
+        self.0.history.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub(crate) fn add_head_time(&self, time: Duration) {
+        let micros = u64::try_from(time.as_micros()).unwrap_or(u64::MAX);
+        self.0.head_micros.fetch_add(micros, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,108 @@
+//! Holding requests back while a host's rate limit quota is nearly used up.
+
+use awc::ClientResponse;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::breaker::MAX_HOSTS;
+use crate::{hints, Retry};
+
+pub(crate) struct Throttle {
+    /// Quota left at which requests start being held back
+    min_remaining: u64,
+    max_wait: Duration,
+    /// When each throttled host's quota resets, for up to [`MAX_HOSTS`] hosts beyond which the
+    /// one resetting first is forgotten
+    hosts: RefCell<HashMap<String, Instant>>,
+}
+
+impl Throttle {
+    /// Reads the quota left from a successful response of `host`, received at `now`
+    pub(crate) fn record(&self, host: &str, res: &ClientResponse, now: Instant) {
+        if !res.status().is_success() {
+            return;
+        }
+        let remaining = match hints::number(res, "ratelimit-remaining").or_else(|| hints::number(res, "x-ratelimit-remaining")) {
+            Some(remaining) => remaining,
+            None => return,
+        };
+
+        let mut hosts = self.hosts.borrow_mut();
+        if remaining > self.min_remaining {
+            hosts.remove(host);
+            return;
+        }
+
+        if let Some(reset) = hints::rate_limit_reset(res) {
+            log::debug!("{} has {} requests left, holding requests back for {:?}", host, remaining, reset);
+            if hosts.len() >= MAX_HOSTS && !hosts.contains_key(host) {
+                let first = hosts.iter().min_by_key(|(_, reset)| **reset).map(|(host, _)| host.clone());
+                if let Some(first) = first {
+                    hosts.remove(&first);
+                }
+            }
+            hosts.insert(host.to_owned(), now + reset.min(self.max_wait));
+        }
+    }
+
+    /// How long from `now` to hold back the next attempt towards `host`
+    pub(crate) fn delay(&self, host: &str, now: Instant) -> Option<Duration> {
+        let mut hosts = self.hosts.borrow_mut();
+        let reset = *hosts.get(host)?;
+        if reset <= now {
+            hosts.remove(host);
+            return None;
+        }
+
+        Some(reset - now)
+    }
+}
+
+impl Retry {
+    /// Holds attempts back once a host reports its rate limit is nearly used up, instead of
+    /// waiting for it to answer `429 Too Many Requests`.
+    ///
+    /// Successful responses carrying `RateLimit-Remaining` (or `X-RateLimit-Remaining`) of
+    /// `min_remaining` or less make later attempts towards the host wait until the reset time
+    /// of `RateLimit-Reset` (or `X-RateLimit-Reset`, as seconds or a Unix timestamp), for at
+    /// most `max_wait`. Responses without a reset time don't hold anything back. Retries are held
+    /// back after their backoff, and return the outcome of the last attempt instead when the
+    /// [`total_deadline`](Retry::total_deadline) passes before the reset or draining cuts the
+    /// wait short. First attempts fail with a timeout then.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use awc_retry::Retry;
+    ///
+    /// let retry = Retry::new(3).rate_limit_throttle(1, Duration::from_secs(30));
+    /// ```
+    pub fn rate_limit_throttle(mut self, min_remaining: u64, max_wait: Duration) -> Self {
+        self.0.throttle = Some(Throttle {
+            min_remaining,
+            max_wait,
+            hosts: RefCell::new(HashMap::new()),
+        });
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use awc::test::TestResponse;
+
+    #[test]
+    fn quota_waits_are_kept_for_the_hosts_resetting_last() {
+        let throttle = Throttle { min_remaining: 1, max_wait: Duration::from_secs(60), hosts: RefCell::new(HashMap::new()) };
+        let now = Instant::now();
+        let exhausted = |reset: &str| TestResponse::default().header("ratelimit-remaining", "0").header("ratelimit-reset", reset).finish();
+        throttle.record("soon.example.com", &exhausted("1"), now);
+        for i in 0..MAX_HOSTS {
+            throttle.record(&format!("{}.example.com", i), &exhausted("30"), now);
+        }
+        assert_eq!(throttle.hosts.borrow().len(), MAX_HOSTS);
+        assert_eq!(throttle.delay("soon.example.com", now), None);
+        assert_eq!(throttle.delay("0.example.com", now), Some(Duration::from_secs(30)));
+    }
+}
@@ -0,0 +1,123 @@
+//! The clock retries wait and check their deadlines with, replaceable for tests.
+
+use futures::future::{FutureExt, LocalBoxFuture};
+use std::time::{Duration, Instant};
+
+use crate::Retry;
+
+/// Tells the time and sleeps for the retry engine, see [`Retry::timer`].
+///
+/// Backoff delays, timeouts, deadlines, the queueing of first attempts and the windows of the
+/// adaptive limit, bandwidth cap and rate limit throttle all go through it. DNS record TTLs of
+/// SRV failover don't.
+pub trait Timer {
+    fn now(&self) -> Instant;
+
+    /// Resolves once `duration` passed
+    fn sleep(&self, duration: Duration) -> LocalBoxFuture<'static, ()>;
+}
+
+/// The default [`Timer`], the clock and timers of the actix runtime
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RuntimeTimer;
+
+impl Timer for RuntimeTimer {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) -> LocalBoxFuture<'static, ()> {
+        actix_rt::time::sleep(duration).boxed_local()
+    }
+}
+
+impl Retry {
+    /// Replaces the clock the retry engine uses, so backoff behaviour can be tested without
+    /// waiting for real.
+    ///
+    /// ```
+    /// use std::cell::RefCell;
+    /// use std::rc::Rc;
+    /// use std::time::{Duration, Instant};
+    /// use actix_service::{fn_service, Service};
+    /// use futures::future::{ready, FutureExt, LocalBoxFuture};
+    /// use awc_retry::{retry_fn, Replayable, Retry, Timer};
+    ///
+    /// /// Sleeps return at once, moving the clock forward instead
+    /// #[derive(Clone)]
+    /// struct Virtual {
+    ///     start: Instant,
+    ///     slept: Rc<RefCell<Vec<Duration>>>,
+    /// }
+    ///
+    /// impl Timer for Virtual {
+    ///     fn now(&self) -> Instant {
+    ///         self.start + self.slept.borrow().iter().sum::<Duration>()
+    ///     }
+    ///
+    ///     fn sleep(&self, duration: Duration) -> LocalBoxFuture<'static, ()> {
+    ///         self.slept.borrow_mut().push(duration);
+    ///         ready(()).boxed_local()
+    ///     }
+    /// }
+    ///
+    /// #[derive(Clone)]
+    /// struct Ping;
+    ///
+    /// impl Replayable for Ping {
+    ///     fn replay(&self) -> Self {
+    ///         Ping
+    ///     }
+    /// }
+    ///
+    /// let timer = Virtual { start: Instant::now(), slept: Rc::default() };
+    /// let retry = Retry::new(3)
+    ///     .backoff(vec![Duration::from_secs(1), Duration::from_secs(2), Duration::from_secs(4)])
+    ///     .timer(timer.clone());
+    /// let service = retry_fn(fn_service(|_: Ping| async { Err::<(), _>("down") }), retry);
+    ///
+    /// let res = actix_rt::System::new().block_on(service.call(Ping));
+    /// assert_eq!(res, Err("down"));
+    /// assert_eq!(*timer.slept.borrow(), [Duration::from_secs(1), Duration::from_secs(2), Duration::from_secs(4)]);
+    /// ```
+    pub fn timer<T: Timer + 'static>(mut self, timer: T) -> Self {
+        self.0.timer = Box::new(timer);
+        self
+    }
+}
+
+/// A [`Timer`] for the unit tests which only moves when slept on or advanced
+#[cfg(test)]
+#[derive(Clone)]
+pub(crate) struct MockTimer {
+    start: Instant,
+    elapsed: std::rc::Rc<std::cell::Cell<Duration>>,
+}
+
+#[cfg(test)]
+impl MockTimer {
+    pub(crate) fn new() -> Self {
+        MockTimer { start: Instant::now(), elapsed: Default::default() }
+    }
+
+    pub(crate) fn advance(&self, duration: Duration) {
+        self.elapsed.set(self.elapsed.get() + duration);
+    }
+
+    /// All the time slept or advanced through so far
+    pub(crate) fn elapsed(&self) -> Duration {
+        self.elapsed.get()
+    }
+}
+
+#[cfg(test)]
+impl Timer for MockTimer {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed.get()
+    }
+
+    fn sleep(&self, duration: Duration) -> LocalBoxFuture<'static, ()> {
+        self.advance(duration);
+        futures::future::ready(()).boxed_local()
+    }
+}
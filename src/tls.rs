@@ -0,0 +1,108 @@
+//! Classification of TLS connect failures for the rustls and openssl backends.
+//!
+//! Both backends surface handshake problems through [`ConnectError`], but in different shapes:
+//! rustls wraps its [`TLSError`](tls_rustls::TLSError) in an [`io::Error`], while openssl either
+//! reports an `SslError` or a stringified handshake error. [`classify`] hides those differences
+//! so the same rules apply whichever backend the client was built with.
+
+use awc::error::{ConnectError, SendRequestError};
+use std::io;
+
+/// The kind of TLS failure an attempt ended with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum TlsFailure {
+    /// The TLS handshake timed out, as far as the backend tells. The connect timeout of the
+    /// client covers resolving and the TCP connect as well, so it's no TLS failure on its own.
+    Timeout,
+    /// The peer's certificate was missing, invalid or not trusted
+    Certificate,
+    /// The handshake failed for any other reason, e.g. no common protocol version or cipher
+    Protocol,
+}
+
+impl TlsFailure {
+    /// Timeouts are worth another attempt, certificate and protocol failures will fail again
+    pub fn is_retryable(self) -> bool {
+        matches!(self, TlsFailure::Timeout)
+    }
+}
+
+/// Classifies `err` as a TLS failure, returns `None` for errors unrelated to TLS
+pub fn classify(err: &SendRequestError) -> Option<TlsFailure> {
+    match err {
+        SendRequestError::Connect(e) => classify_connect(e),
+        _ => None,
+    }
+}
+
+fn classify_connect(err: &ConnectError) -> Option<TlsFailure> {
+    match err {
+        #[cfg(feature = "openssl")]
+        ConnectError::SslError(_) => Some(TlsFailure::Protocol),
+        ConnectError::Io(e) => classify_io(e),
+        _ => None,
+    }
+}
+
+#[allow(unused_variables)]
+fn classify_io(err: &io::Error) -> Option<TlsFailure> {
+    #[cfg(feature = "rustls")]
+    {
+        if let Some(e) = err.get_ref().and_then(|e| e.downcast_ref::<tls_rustls::TLSError>()) {
+            return Some(classify_rustls(e));
+        }
+    }
+
+    #[cfg(feature = "openssl")]
+    {
+        if let Some(f) = classify_openssl(err) {
+            return Some(f);
+        }
+    }
+
+    None
+}
+
+#[cfg(feature = "rustls")]
+fn classify_rustls(err: &tls_rustls::TLSError) -> TlsFailure {
+    use tls_rustls::internal::msgs::enums::AlertDescription;
+    use tls_rustls::TLSError;
+
+    match err {
+        TLSError::WebPKIError(_) | TLSError::NoCertificatesPresented | TLSError::InvalidSCT(_) => {
+            TlsFailure::Certificate
+        }
+        TLSError::AlertReceived(
+            AlertDescription::BadCertificate
+            | AlertDescription::UnsupportedCertificate
+            | AlertDescription::CertificateRevoked
+            | AlertDescription::CertificateExpired
+            | AlertDescription::CertificateUnknown
+            | AlertDescription::UnknownCA
+            | AlertDescription::CertificateRequired,
+        ) => TlsFailure::Certificate,
+        _ => TlsFailure::Protocol,
+    }
+}
+
+/// The openssl connector only hands back the display form of its `HandshakeError`
+#[cfg(feature = "openssl")]
+fn classify_openssl(err: &io::Error) -> Option<TlsFailure> {
+    if err.kind() != io::ErrorKind::Other {
+        return None;
+    }
+
+    let msg = err.to_string();
+    if msg.starts_with("the handshake failed") || msg.starts_with("stream setup failed") {
+        if msg.contains("certificate") {
+            Some(TlsFailure::Certificate)
+        } else if msg.contains("timed out") {
+            Some(TlsFailure::Timeout)
+        } else {
+            Some(TlsFailure::Protocol)
+        }
+    } else {
+        None
+    }
+}